@@ -0,0 +1,1248 @@
+// What the host functions do, independent from the engine that executes the kontract:
+// every backend gives a `HostEnv` and only moves the arguments and the errors between
+// the engine and these functions, so the semantics (and the fuel) are the same for all
+
+use super::{
+	memory, AbiVersion, CallContext, Dependency, DependencyOutcome, EntryPoint, ExecutionErrors,
+	ExecutionOutcome, ExecutionParams, ExtensionRequest, Key, KeyHashing, KontractState,
+	KontractStorage, KontractStore, Library, Metering, RawKontractStorage, RequestKind,
+	StorageLimits, StorageUsage, Transfer, Value, KEY_NOT_FOUND, NO_KEY, OUT_OF_RANGE, RANDOM_SIZE,
+};
+use codec::{Decode, Encode};
+use sp_std::{collections::btree_map::BTreeMap, ops::Bound, vec::Vec};
+
+/// What a backend gives to the host functions, implemented by the caller of its engine
+pub trait HostEnv {
+	/// Store of the kontract that called the host function
+	fn store(&mut self) -> &mut KontractStore;
+
+	/// The memory exported by the kontract
+	fn memory(&mut self) -> Result<&mut [u8], ExecutionErrors>;
+
+	/// Consume the fuel of the engine and return the fuel left, only with `Metering::Engine`
+	fn consume_engine_fuel(&mut self, fuel: u64) -> Result<u64, ExecutionErrors>;
+
+	/// Only with `Metering::Engine`
+	fn add_engine_fuel(&mut self, fuel: u64) -> Result<(), ExecutionErrors>;
+
+	/// Execute the main of the code in a new store with `data` and `fuel`, the data is
+	/// always given back together with the fuel consumed
+	fn run_nested(
+		&mut self,
+		data: KontractStore,
+		code: &[u8],
+		fuel: u64,
+	) -> (KontractStore, u64, Result<(), ExecutionErrors>);
+}
+
+/// The host functions of the version `$abi`, the only ones a code of that version can import.
+/// `$define` registers one of them (module, name, function and its signature) in `$linker`
+macro_rules! host_functions {
+	($define:ident, $linker:expr, $abi:expr) => {
+		// Called by the instrumented codes, the cost of the following basic block
+		$define!(
+			$linker,
+			$crate::instrument::GAS_MODULE,
+			$crate::instrument::GAS_FUNCTION,
+			gas(amount: u64) -> ()
+		);
+		for &module in $abi.modules() {
+			$crate::host::shared_functions!($define, $linker, module);
+			match $abi {
+				$crate::AbiVersion::V0 => {
+					$define!(
+						$linker,
+						module,
+						"get",
+						get(key_ptr: u32, key_size: u32, value_ptr: u32, value_max_size: u32) -> ()
+					);
+					$define!(
+						$linker,
+						module,
+						"get_foreign",
+						get_foreign(
+							kontract_id_ptr: u32,
+							kontract_id_size: u32,
+							key_ptr: u32,
+							key_size: u32,
+							value_ptr: u32,
+							value_max_size: u32
+						) -> ()
+					);
+				},
+				$crate::AbiVersion::V1 => {
+					$define!(
+						$linker,
+						module,
+						"get",
+						get_v1(key_ptr: u32, key_size: u32, value_ptr: u32, value_max_size: u32) -> u32
+					);
+					$define!(
+						$linker,
+						module,
+						"get_foreign",
+						get_foreign_v1(
+							kontract_id_ptr: u32,
+							kontract_id_size: u32,
+							key_ptr: u32,
+							key_size: u32,
+							value_ptr: u32,
+							value_max_size: u32
+						) -> u32
+					);
+				},
+			}
+		}
+	};
+}
+pub(crate) use host_functions;
+
+// The host functions that are the same in every version, registered in `$module`
+macro_rules! shared_functions {
+	($define:ident, $linker:expr, $module:expr) => {
+		$define!(
+			$linker,
+			$module,
+			"set",
+			set(key_ptr: u32, key_size: u32, value_ptr: u32, value_size: u32) -> ()
+		);
+		$define!(
+			$linker,
+			$module,
+			"get_value",
+			get_value(key_ptr: u32, key_size: u32, value_ptr: u32, value_max_size: u32) -> u32
+		);
+		$define!(
+			$linker,
+			$module,
+			"get_foreign_value",
+			get_foreign_value(
+				kontract_id_ptr: u32,
+				kontract_id_size: u32,
+				key_ptr: u32,
+				key_size: u32,
+				value_ptr: u32,
+				value_max_size: u32
+			) -> u32
+		);
+		$define!(
+			$linker,
+			$module,
+			"next_key",
+			next_key(
+				prefix_ptr: u32,
+				prefix_size: u32,
+				after_ptr: u32,
+				after_size: u32,
+				key_ptr: u32,
+				key_max_size: u32
+			) -> u32
+		);
+		$define!($linker, $module, "contains_key", contains_key(key_ptr: u32, key_size: u32) -> u32);
+		$define!($linker, $module, "value_len", value_len(key_ptr: u32, key_size: u32) -> u32);
+		$define!(
+			$linker,
+			$module,
+			"take",
+			take(key_ptr: u32, key_size: u32, value_ptr: u32, value_max_size: u32) -> u32
+		);
+		$define!(
+			$linker,
+			$module,
+			"append",
+			append(key_ptr: u32, key_size: u32, value_ptr: u32, value_size: u32) -> ()
+		);
+		$define!(
+			$linker,
+			$module,
+			"get_range",
+			get_range(key_ptr: u32, key_size: u32, offset: u32, len: u32, value_ptr: u32) -> u32
+		);
+		$define!($linker, $module, "remove", remove(key_ptr: u32, key_size: u32) -> ());
+		$define!($linker, $module, "input_len", input_len() -> u32);
+		$define!($linker, $module, "input", input(input_ptr: u32, input_max_size: u32) -> ());
+		$define!($linker, $module, "balance", balance(balance_ptr: u32) -> ());
+		$define!(
+			$linker,
+			$module,
+			"transfer",
+			transfer(dest_ptr: u32, dest_size: u32, amount_ptr: u32) -> ()
+		);
+		$define!(
+			$linker,
+			$module,
+			"call_extension",
+			call_extension(
+				id: u32,
+				input_ptr: u32,
+				input_len: u32,
+				output_ptr: u32,
+				output_max_size: u32
+			) -> u32
+		);
+		$define!(
+			$linker,
+			$module,
+			"random",
+			random(subject_ptr: u32, subject_len: u32, out_ptr: u32) -> ()
+		);
+		$define!($linker, $module, "dispatch_call", dispatch_call(call_ptr: u32, call_len: u32) -> ());
+		$define!(
+			$linker,
+			$module,
+			"call",
+			call(
+				kontract_id_ptr: u32,
+				kontract_id_size: u32,
+				input_ptr: u32,
+				input_size: u32,
+				fuel: u64
+			) -> ()
+		);
+		$define!(
+			$linker,
+			$module,
+			"delegate_call",
+			delegate_call(code_id_ptr: u32, code_id_size: u32, input_ptr: u32, input_size: u32) -> ()
+		);
+		$define!($linker, $module, "call_output_len", call_output_len() -> u32);
+		$define!(
+			$linker,
+			$module,
+			"call_output",
+			call_output(output_ptr: u32, output_max_size: u32) -> ()
+		);
+		$define!($linker, $module, "set_output", set_output(output_ptr: u32, output_size: u32) -> ());
+	};
+}
+pub(crate) use shared_functions;
+
+// The engine only knows that the host function failed, the error is kept in the context
+// (that follows the nested calls) and returned at the end of the execution
+pub fn record_error(store: &mut KontractStore, error: ExecutionErrors) {
+	store.context.error.get_or_insert(error);
+}
+
+// false = read
+fn update_with_check(val: &mut u32, max: u32, r_or_w: bool) -> Result<(), ExecutionErrors> {
+	*val = match val.saturating_add(1) {
+		x if x <= max => x,
+		_ =>
+			return Err(match r_or_w {
+				true => ExecutionErrors::OutOfWrites,
+				false => ExecutionErrors::OutOfReads,
+			}),
+	};
+	Ok(())
+}
+
+// false = read, like `update_with_check` but for the bytes of the values
+fn update_bytes_with_check(
+	val: &mut u32,
+	max: u32,
+	bytes: usize,
+	r_or_w: bool,
+) -> Result<(), ExecutionErrors> {
+	*val = match u32::try_from(bytes).ok().and_then(|bytes| val.checked_add(bytes)) {
+		Some(x) if x <= max => x,
+		_ =>
+			return Err(match r_or_w {
+				true => ExecutionErrors::OutOfWriteBytes,
+				false => ExecutionErrors::OutOfReadBytes,
+			}),
+	};
+	Ok(())
+}
+
+// The same counter is used by the wasm instructions, so with `Metering::Engine`
+// the fuel of the engine and with `Metering::Instrumented` the `gas_left` of the store
+fn consume_fuel<E: HostEnv>(env: &mut E, fuel: u64) -> Result<(), ExecutionErrors> {
+	match env.store().context.metering {
+		Metering::Engine => env.consume_engine_fuel(fuel).map(|_| ()),
+		Metering::Instrumented => {
+			let store = env.store();
+			store.gas_left = store.gas_left.checked_sub(fuel).ok_or(ExecutionErrors::OutOfFuel)?;
+			Ok(())
+		},
+	}
+}
+
+fn fuel_left<E: HostEnv>(env: &mut E) -> Result<u64, ExecutionErrors> {
+	match env.store().context.metering {
+		Metering::Engine => env.consume_engine_fuel(0),
+		Metering::Instrumented => Ok(env.store().gas_left),
+	}
+}
+
+fn refund_fuel<E: HostEnv>(env: &mut E, fuel: u64) -> Result<(), ExecutionErrors> {
+	match env.store().context.metering {
+		Metering::Engine => env.add_engine_fuel(fuel),
+		Metering::Instrumented => {
+			let store = env.store();
+			store.gas_left = store.gas_left.saturating_add(fuel);
+			Ok(())
+		},
+	}
+}
+
+// Charged at the beginning of every host function
+fn charge_call<E: HostEnv>(env: &mut E) -> Result<(), ExecutionErrors> {
+	let base = env.store().context.host_fn_costs.base;
+	consume_fuel(env, base)
+}
+
+// The bytes are paid before the copy, so a huge length doesn't cost anything to the node
+fn charge_bytes<E: HostEnv>(env: &mut E, bytes: usize) -> Result<(), ExecutionErrors> {
+	let per_byte = env.store().context.host_fn_costs.per_byte;
+	consume_fuel(env, per_byte.saturating_mul(bytes as u64))
+}
+
+// `memory::read` paying the copied bytes
+fn read<E: HostEnv>(env: &mut E, ptr: u32, len: u32) -> Result<Vec<u8>, ExecutionErrors> {
+	charge_bytes(env, len as usize)?;
+	Ok(memory::read(env.memory()?, ptr, len)?)
+}
+
+// `memory::write` paying the copied bytes
+fn write<E: HostEnv>(
+	env: &mut E,
+	data: &[u8],
+	ptr: u32,
+	max_size: u32,
+) -> Result<(), ExecutionErrors> {
+	charge_bytes(env, data.len())?;
+	Ok(memory::write(env.memory()?, data, ptr, max_size)?)
+}
+
+// `memory::write_encoded` paying the copied bytes
+fn write_encoded<E: HostEnv, V: Encode>(
+	env: &mut E,
+	value: &V,
+	ptr: u32,
+	max_size: u32,
+) -> Result<(), ExecutionErrors> {
+	charge_bytes(env, value.encoded_size())?;
+	Ok(memory::write_encoded(env.memory()?, value, ptr, max_size)?)
+}
+
+// State of the kontract executed in the store, always present in the context
+fn state(store: &mut KontractStore) -> Result<&mut KontractState, ExecutionErrors> {
+	store
+		.context
+		.kontracts
+		.get_mut(&store.kontract_id)
+		.ok_or(ExecutionErrors::UnexpectedBehavoiur)
+}
+
+// What I get here is raw bytes, probably already encoded by the wasm, but is not managed by the
+// storage
+fn kontracts_set(store: &mut KontractStore, key: Key, value: Value) -> Result<(), ExecutionErrors> {
+	// Throw Trap if the number of write goes over the maximum supported number
+	update_with_check(&mut store.curr_n_write, store.max_n_write, true)?;
+	update_bytes_with_check(&mut store.curr_write_bytes, store.max_write_bytes, value.len(), true)?;
+
+	let key = store.context.key_hashing.hash(&key[..]);
+	let limits = store.context.storage_limits;
+	insert_checked(state(store)?, limits, key, value)
+}
+
+// The limits are checked on what is really stored, so the key after the hashing
+fn insert_checked(
+	state: &mut KontractState,
+	limits: StorageLimits,
+	key: Key,
+	value: Value,
+) -> Result<(), ExecutionErrors> {
+	if key.len() > limits.max_key_size as usize {
+		return Err(ExecutionErrors::KeyTooLarge)
+	}
+	if value.len() > limits.max_value_size as usize {
+		return Err(ExecutionErrors::ValueTooLarge)
+	}
+
+	// Saturating, the usage comes from the pallet and a kontract must never panic the node
+	let old = state.storage.get(&key).map(|value| (key.len() + value.len()) as u32);
+	let usage = StorageUsage {
+		bytes: state
+			.usage
+			.bytes
+			.saturating_sub(old.unwrap_or(0))
+			.saturating_add((key.len() + value.len()) as u32),
+		entries: state.usage.entries.saturating_add(old.is_none() as u32),
+	};
+	if usage.entries > limits.max_entries || usage.bytes > limits.max_bytes {
+		return Err(ExecutionErrors::StorageFull)
+	}
+
+	state.storage.insert(key, value);
+	state.usage = usage;
+	Ok(())
+}
+
+fn remove_tracked(state: &mut KontractState, key: &Key) -> Option<Value> {
+	let value = state.storage.remove(key)?;
+	state.usage.bytes = state.usage.bytes.saturating_sub((key.len() + value.len()) as u32);
+	state.usage.entries = state.usage.entries.saturating_sub(1);
+	Some(value)
+}
+
+fn kontracts_get(store: &mut KontractStore, key: Key) -> Result<Option<Value>, ExecutionErrors> {
+	update_with_check(&mut store.curr_n_read, store.max_n_read, false)?;
+
+	let key = store.context.key_hashing.hash(&key[..]);
+	let value = state(store)?.storage.get(&key).map(|v| v.to_owned());
+
+	let len = value.as_ref().map_or(0, |v| v.len());
+	update_bytes_with_check(&mut store.curr_read_bytes, store.max_read_bytes, len, false)?;
+
+	// TODO: here I have do to owned (that call clone underneath) to avoid managing lifetime...
+	Ok(value)
+}
+
+// Read only access to the storage of another declared kontract
+fn kontracts_get_foreign(
+	store: &mut KontractStore,
+	kontract_id: Vec<u8>,
+	key: Key,
+) -> Result<Option<Value>, ExecutionErrors> {
+	update_with_check(&mut store.curr_n_read, store.max_n_read, false)?;
+
+	let kontract = match store.context.kontracts.get(&kontract_id) {
+		Some(kontract) => kontract,
+		None => return Err(ExecutionErrors::KontractNotDeclared),
+	};
+
+	let value = kontract.storage.get(&store.context.key_hashing.hash(&key[..])).cloned();
+
+	let len = value.as_ref().map_or(0, |v| v.len());
+	update_bytes_with_check(&mut store.curr_read_bytes, store.max_read_bytes, len, false)?;
+	Ok(value)
+}
+
+// The first key, in the order of the keys used by the kontract, with the prefix and
+// after `after` (if present). Counted as a read, the bytes of every key visited to find
+// it are paid before the key is checked
+fn kontracts_next_key<E: HostEnv>(
+	env: &mut E,
+	prefix: Key,
+	after: Option<Key>,
+) -> Result<Option<Key>, ExecutionErrors> {
+	let store = env.store();
+	update_with_check(&mut store.curr_n_read, store.max_n_read, false)?;
+
+	let key_hashing = store.context.key_hashing;
+	match key_hashing {
+		KeyHashing::Blake2 => Err(ExecutionErrors::KeysNotRecoverable),
+		// The stored order is the order of the keys, the search starts from the
+		// first possible key
+		KeyHashing::Identity => {
+			let start = match after {
+				Some(after) if after >= prefix => Bound::Excluded(after),
+				_ => Bound::Included(prefix.clone()),
+			};
+			let key = state(env.store())?
+				.storage
+				.range((start, Bound::Unbounded))
+				.next()
+				.map(|(key, _)| key)
+				.filter(|key| key.starts_with(&prefix[..]))
+				.cloned();
+			charge_bytes(env, key.as_ref().map_or(0, |key| key.len()))?;
+			Ok(key)
+		},
+		// The stored order is not the order of the keys, so every key is visited
+		// and paid
+		KeyHashing::Blake2Concat => {
+			let mut found: Option<Key> = None;
+			let mut visited = Bound::Unbounded;
+			loop {
+				let storage = &state(env.store())?.storage;
+				let stored = match storage.range((visited, Bound::Unbounded)).next() {
+					Some((stored, _)) => stored.clone(),
+					None => return Ok(found),
+				};
+				charge_bytes(env, stored.len())?;
+
+				if let Some(key) = key_hashing.raw_key(&stored) {
+					let is_after = after.as_ref().map_or(true, |after| key > &after[..]);
+					let is_first = found.as_ref().map_or(true, |found| key < &found[..]);
+					if key.starts_with(&prefix[..]) && is_after && is_first {
+						found = Some(key.to_vec());
+					}
+				}
+				visited = Bound::Excluded(stored);
+			}
+		},
+	}
+}
+
+fn kontracts_remove(store: &mut KontractStore, key: Key) -> Result<(), ExecutionErrors> {
+	// Throw Trap if the number of write goes over the maximum supported number
+	// To make thik easier I count the remove as a write
+	update_with_check(&mut store.curr_n_write, store.max_n_write, true)?;
+
+	let key = store.context.key_hashing.hash(&key[..]);
+	remove_tracked(state(store)?, &key);
+	Ok(())
+}
+
+// Get and remove, counted as a read and a write. A value bigger than `max_size` is only
+// read and stays in the storage
+fn kontracts_take(
+	store: &mut KontractStore,
+	key: Key,
+	max_size: u32,
+) -> Result<Option<Value>, ExecutionErrors> {
+	update_with_check(&mut store.curr_n_read, store.max_n_read, false)?;
+
+	let key = store.context.key_hashing.hash(&key[..]);
+	let len = state(store)?.storage.get(&key).map_or(0, |v| v.len());
+	update_bytes_with_check(&mut store.curr_read_bytes, store.max_read_bytes, len, false)?;
+	if len > max_size as usize {
+		return Ok(state(store)?.storage.get(&key).cloned())
+	}
+
+	update_with_check(&mut store.curr_n_write, store.max_n_write, true)?;
+	Ok(remove_tracked(state(store)?, &key))
+}
+
+// The value is concatenated to the stored one (or stored if missing), counted as a write
+fn kontracts_append(
+	store: &mut KontractStore,
+	key: Key,
+	value: Value,
+) -> Result<(), ExecutionErrors> {
+	update_with_check(&mut store.curr_n_write, store.max_n_write, true)?;
+	// Only the appended bytes are written
+	update_bytes_with_check(&mut store.curr_write_bytes, store.max_write_bytes, value.len(), true)?;
+
+	let key = store.context.key_hashing.hash(&key[..]);
+	let limits = store.context.storage_limits;
+	let state = state(store)?;
+
+	let mut new_value = state.storage.get(&key).cloned().unwrap_or_default();
+	new_value.extend_from_slice(&value[..]);
+	insert_checked(state, limits, key, new_value)
+}
+
+// Inspect the stored value without copying it in the wasm memory, counted as a read
+fn kontracts_with_value<R>(
+	store: &mut KontractStore,
+	key: Key,
+	f: impl FnOnce(Option<&Value>) -> R,
+) -> Result<R, ExecutionErrors> {
+	update_with_check(&mut store.curr_n_read, store.max_n_read, false)?;
+
+	let key = store.context.key_hashing.hash(&key[..]);
+	Ok(f(state(store)?.storage.get(&key)))
+}
+
+fn kontracts_transfer(
+	store: &mut KontractStore,
+	dest: Vec<u8>,
+	amount: u128,
+) -> Result<(), ExecutionErrors> {
+	let state = state(store)?;
+
+	// The balance is reduced immediately, so the kontract can't queue
+	// more transfers than what it owns
+	state.balance = match state.balance.checked_sub(amount) {
+		Some(balance) => balance,
+		None => return Err(ExecutionErrors::InsufficientBalance),
+	};
+	state.transfers.push(Transfer { dest, amount });
+
+	Ok(())
+}
+
+// The outputs are resolved by the pallet before the execution, if the next one is not
+// known yet the execution is stopped and the pallet executes it again with the output
+fn resolve_request(
+	store: &mut KontractStore,
+	kind: RequestKind,
+	input: Vec<u8>,
+) -> Result<Vec<u8>, ExecutionErrors> {
+	let context = &mut store.context;
+
+	match context.extension_outputs.get(context.next_extension) {
+		Some(output) => {
+			let output = output.clone();
+			context.next_extension += 1;
+			Ok(output)
+		},
+		None => {
+			context.pending_extension =
+				Some(ExtensionRequest { kontract_id: store.kontract_id.clone(), kind, input });
+			// Never returned, the pending request is checked before the errors
+			Err(ExecutionErrors::UnexpectedBehavoiur)
+		},
+	}
+}
+
+// Execute the main of another kontract in a new store, a trap of the called kontract
+// is also a trap of the caller so everything touched is committed only if the whole
+// execution succeed
+fn kontracts_call<E: HostEnv>(
+	env: &mut E,
+	kontract_id: Vec<u8>,
+	input: Vec<u8>,
+	fuel: u64,
+) -> Result<(), ExecutionErrors> {
+	let context = &env.store().context;
+
+	check_call_depth(context)?;
+	if !context.allow_reentrancy && context.stack.contains(&kontract_id) {
+		return Err(ExecutionErrors::ReentrancyDenied)
+	}
+	let (code, abi) = match context.kontracts.get(&kontract_id) {
+		Some(kontract) => (kontract.code.clone(), kontract.abi),
+		None => return Err(ExecutionErrors::KontractNotDeclared),
+	};
+
+	// The fuel is taken from the caller and what is not consumed is given back
+	consume_fuel(env, fuel)?;
+
+	let consumed = run_nested(env, kontract_id, (code, abi), input, fuel)?;
+	refund_fuel(env, fuel.saturating_sub(consumed))
+}
+
+// Execute the main of a declared code over the storage, the balance and the counters
+// of the caller, as if the code were part of the caller
+fn kontracts_delegate_call<E: HostEnv>(
+	env: &mut E,
+	code_id: Vec<u8>,
+	input: Vec<u8>,
+) -> Result<(), ExecutionErrors> {
+	check_call_depth(&env.store().context)?;
+	let library = match env.store().context.libraries.get(&code_id) {
+		Some(library) => library.clone(),
+		None => return Err(ExecutionErrors::CodeNotDeclared),
+	};
+
+	// The library can use all the remaining fuel of the caller, it is paid after the
+	// execution because wasmtime can't take all the fuel of a store
+	let fuel = fuel_left(env)?;
+
+	let kontract_id = env.store().kontract_id.clone();
+	let consumed = run_nested(env, kontract_id, library, input, fuel)?;
+	consume_fuel(env, consumed)
+}
+
+// The first kontract in the stack is not a call
+fn check_call_depth(context: &CallContext) -> Result<(), ExecutionErrors> {
+	match context.stack.len() as u32 > context.max_call_depth {
+		true => Err(ExecutionErrors::MaxCallDepthReached),
+		false => Ok(()),
+	}
+}
+
+// The context is moved in a new store and than back, returns the fuel consumed
+// by the nested execution
+fn run_nested<E: HostEnv>(
+	env: &mut E,
+	kontract_id: Vec<u8>,
+	(code, abi): (Vec<u8>, AbiVersion),
+	input: Vec<u8>,
+	fuel: u64,
+) -> Result<u64, ExecutionErrors> {
+	let store = env.store();
+	let mut context = core::mem::take(&mut store.context);
+	context.stack.push(kontract_id.clone());
+	if let Some(kontract) = context.kontracts.get_mut(&kontract_id) {
+		kontract.touched = true;
+	}
+
+	let data = KontractStore {
+		kontract_id,
+		max_n_read: store.max_n_read,
+		max_n_write: store.max_n_write,
+		curr_n_read: store.curr_n_read,
+		curr_n_write: store.curr_n_write,
+		max_read_bytes: store.max_read_bytes,
+		max_write_bytes: store.max_write_bytes,
+		curr_read_bytes: store.curr_read_bytes,
+		curr_write_bytes: store.curr_write_bytes,
+		gas_left: fuel,
+		input,
+		output: Vec::new(),
+		call_output: Vec::new(),
+		abi,
+		context,
+	};
+	let (mut data, consumed, result) = env.run_nested(data, &code[..], fuel);
+	data.context.stack.pop();
+
+	let store = env.store();
+	store.context = data.context;
+	store.curr_n_read = data.curr_n_read;
+	store.curr_n_write = data.curr_n_write;
+	store.curr_read_bytes = data.curr_read_bytes;
+	store.curr_write_bytes = data.curr_write_bytes;
+	store.call_output = data.output;
+
+	result.map(|_| consumed)
+}
+
+pub fn gas<E: HostEnv>(env: &mut E, amount: u64) -> Result<(), ExecutionErrors> {
+	consume_fuel(env, amount)
+}
+
+pub fn set<E: HostEnv>(
+	env: &mut E,
+	key_ptr: u32,
+	key_size: u32,
+	value_ptr: u32,
+	value_size: u32,
+) -> Result<(), ExecutionErrors> {
+	charge_call(env)?;
+	// The inputs are the pointers the begining of the vec
+	// Those are u32 because the wasm executor work in 32bit
+	// to create a real sandbox execution
+	let key_vec = read(env, key_ptr, key_size)?;
+	let value_vec = read(env, value_ptr, value_size)?;
+
+	kontracts_set(env.store(), key_vec, value_vec)
+}
+
+pub fn get<E: HostEnv>(
+	env: &mut E,
+	key_ptr: u32,
+	key_size: u32,
+	value_ptr: u32,
+	value_max_size: u32,
+) -> Result<(), ExecutionErrors> {
+	charge_call(env)?;
+	let key_vec = read(env, key_ptr, key_size)?;
+
+	// I think this is no a really good approch, no enough time to do a better one:
+	// If the key is not present in the storage than I use an empty vec, write it
+	// in the wasm buffer so that in the other side the initial of the buffer is a
+	// compact encoding 0 => empty vec = no value
+	let value_vec = kontracts_get(env.store(), key_vec)?.unwrap_or_default();
+
+	write_encoded(env, &value_vec, value_ptr, value_max_size)
+}
+
+// Unlike `get` the value is written as it is and the real length is returned,
+// if it is bigger than `value_max_size` nothing is written and the kontract can
+// try again with a buffer of the returned size
+pub fn get_value<E: HostEnv>(
+	env: &mut E,
+	key_ptr: u32,
+	key_size: u32,
+	value_ptr: u32,
+	value_max_size: u32,
+) -> Result<u32, ExecutionErrors> {
+	charge_call(env)?;
+	let key_vec = read(env, key_ptr, key_size)?;
+
+	match kontracts_get(env.store(), key_vec)? {
+		Some(value) if value.len() <= value_max_size as usize => {
+			write(env, &value[..], value_ptr, value_max_size)?;
+			Ok(value.len() as u32)
+		},
+		Some(value) => Ok(value.len() as u32),
+		None => Ok(KEY_NOT_FOUND),
+	}
+}
+
+// `get` of `AbiVersion::V1`, the same of `get_value`: the value of the old `get` is
+// SCALE encoded and can't be told apart from an empty one
+pub fn get_v1<E: HostEnv>(
+	env: &mut E,
+	key_ptr: u32,
+	key_size: u32,
+	value_ptr: u32,
+	value_max_size: u32,
+) -> Result<u32, ExecutionErrors> {
+	get_value(env, key_ptr, key_size, value_ptr, value_max_size)
+}
+
+pub fn get_foreign<E: HostEnv>(
+	env: &mut E,
+	kontract_id_ptr: u32,
+	kontract_id_size: u32,
+	key_ptr: u32,
+	key_size: u32,
+	value_ptr: u32,
+	value_max_size: u32,
+) -> Result<(), ExecutionErrors> {
+	charge_call(env)?;
+	let kontract_id = read(env, kontract_id_ptr, kontract_id_size)?;
+	let key_vec = read(env, key_ptr, key_size)?;
+
+	// Same encoding of `get`, an empty vec means no value
+	let value_vec = kontracts_get_foreign(env.store(), kontract_id, key_vec)?.unwrap_or_default();
+
+	write_encoded(env, &value_vec, value_ptr, value_max_size)
+}
+
+// `get_value` on the storage of another declared kontract
+pub fn get_foreign_value<E: HostEnv>(
+	env: &mut E,
+	kontract_id_ptr: u32,
+	kontract_id_size: u32,
+	key_ptr: u32,
+	key_size: u32,
+	value_ptr: u32,
+	value_max_size: u32,
+) -> Result<u32, ExecutionErrors> {
+	charge_call(env)?;
+	let kontract_id = read(env, kontract_id_ptr, kontract_id_size)?;
+	let key_vec = read(env, key_ptr, key_size)?;
+
+	match kontracts_get_foreign(env.store(), kontract_id, key_vec)? {
+		Some(value) if value.len() <= value_max_size as usize => {
+			write(env, &value[..], value_ptr, value_max_size)?;
+			Ok(value.len() as u32)
+		},
+		Some(value) => Ok(value.len() as u32),
+		None => Ok(KEY_NOT_FOUND),
+	}
+}
+
+// `get_foreign` of `AbiVersion::V1`, the same of `get_foreign_value`
+pub fn get_foreign_v1<E: HostEnv>(
+	env: &mut E,
+	kontract_id_ptr: u32,
+	kontract_id_size: u32,
+	key_ptr: u32,
+	key_size: u32,
+	value_ptr: u32,
+	value_max_size: u32,
+) -> Result<u32, ExecutionErrors> {
+	get_foreign_value(
+		env,
+		kontract_id_ptr,
+		kontract_id_size,
+		key_ptr,
+		key_size,
+		value_ptr,
+		value_max_size,
+	)
+}
+
+pub fn next_key<E: HostEnv>(
+	env: &mut E,
+	prefix_ptr: u32,
+	prefix_size: u32,
+	after_ptr: u32,
+	after_size: u32,
+	key_ptr: u32,
+	key_max_size: u32,
+) -> Result<u32, ExecutionErrors> {
+	charge_call(env)?;
+	let prefix = read(env, prefix_ptr, prefix_size)?;
+	// `NO_KEY` as size of `after` means from the first key with the prefix,
+	// an empty `after` is the empty key
+	let after = match after_size {
+		NO_KEY => None,
+		_ => Some(read(env, after_ptr, after_size)?),
+	};
+
+	// Like `get_value` a key bigger than `key_max_size` is not written, the kontract can
+	// try again with a buffer of the returned size
+	match kontracts_next_key(env, prefix, after)? {
+		Some(key) if key.len() <= key_max_size as usize => {
+			write(env, &key[..], key_ptr, key_max_size)?;
+			Ok(key.len() as u32)
+		},
+		Some(key) => Ok(key.len() as u32),
+		None => Ok(NO_KEY),
+	}
+}
+
+pub fn contains_key<E: HostEnv>(
+	env: &mut E,
+	key_ptr: u32,
+	key_size: u32,
+) -> Result<u32, ExecutionErrors> {
+	charge_call(env)?;
+	let key_vec = read(env, key_ptr, key_size)?;
+	kontracts_with_value(env.store(), key_vec, |value| value.is_some() as u32)
+}
+
+pub fn value_len<E: HostEnv>(
+	env: &mut E,
+	key_ptr: u32,
+	key_size: u32,
+) -> Result<u32, ExecutionErrors> {
+	charge_call(env)?;
+	let key_vec = read(env, key_ptr, key_size)?;
+	kontracts_with_value(env.store(), key_vec, |value| {
+		value.map_or(KEY_NOT_FOUND, |value| value.len() as u32)
+	})
+}
+
+pub fn take<E: HostEnv>(
+	env: &mut E,
+	key_ptr: u32,
+	key_size: u32,
+	value_ptr: u32,
+	value_max_size: u32,
+) -> Result<u32, ExecutionErrors> {
+	charge_call(env)?;
+	let key_vec = read(env, key_ptr, key_size)?;
+
+	// Unlike `get` the value is written as it is, without any encoding. Like `get_value`
+	// if the buffer is too small only the length is returned, and the value is kept
+	match kontracts_take(env.store(), key_vec, value_max_size)? {
+		Some(value) if value.len() <= value_max_size as usize => {
+			write(env, &value[..], value_ptr, value_max_size)?;
+			Ok(value.len() as u32)
+		},
+		Some(value) => Ok(value.len() as u32),
+		None => Ok(KEY_NOT_FOUND),
+	}
+}
+
+pub fn append<E: HostEnv>(
+	env: &mut E,
+	key_ptr: u32,
+	key_size: u32,
+	value_ptr: u32,
+	value_size: u32,
+) -> Result<(), ExecutionErrors> {
+	charge_call(env)?;
+	let key_vec = read(env, key_ptr, key_size)?;
+	let value_vec = read(env, value_ptr, value_size)?;
+
+	kontracts_append(env.store(), key_vec, value_vec)
+}
+
+pub fn get_range<E: HostEnv>(
+	env: &mut E,
+	key_ptr: u32,
+	key_size: u32,
+	offset: u32,
+	len: u32,
+	value_ptr: u32,
+) -> Result<u32, ExecutionErrors> {
+	charge_call(env)?;
+	let key_vec = read(env, key_ptr, key_size)?;
+
+	// At most `len` bytes, less if the value ends before
+	let range = kontracts_with_value(env.store(), key_vec, |value| {
+		value.map(|value| {
+			value
+				.get(offset as usize..)
+				.map(|value| value[..value.len().min(len as usize)].to_vec())
+		})
+	})?;
+
+	match range {
+		Some(Some(range)) => {
+			let store = env.store();
+			update_bytes_with_check(
+				&mut store.curr_read_bytes,
+				store.max_read_bytes,
+				range.len(),
+				false,
+			)?;
+			write(env, &range[..], value_ptr, len)?;
+			Ok(range.len() as u32)
+		},
+		Some(None) => Ok(OUT_OF_RANGE),
+		None => Ok(KEY_NOT_FOUND),
+	}
+}
+
+pub fn remove<E: HostEnv>(env: &mut E, key_ptr: u32, key_size: u32) -> Result<(), ExecutionErrors> {
+	charge_call(env)?;
+	let key_vec = read(env, key_ptr, key_size)?;
+
+	kontracts_remove(env.store(), key_vec)
+}
+
+pub fn input_len<E: HostEnv>(env: &mut E) -> Result<u32, ExecutionErrors> {
+	charge_call(env)?;
+	Ok(env.store().input.len() as u32)
+}
+
+pub fn input<E: HostEnv>(
+	env: &mut E,
+	input_ptr: u32,
+	input_max_size: u32,
+) -> Result<(), ExecutionErrors> {
+	charge_call(env)?;
+	// The input is not stored anywhere else, so it can't be modified
+	// by the kontract and than I can simply clone it
+	let input = env.store().input.clone();
+	write(env, &input[..], input_ptr, input_max_size)
+}
+
+pub fn balance<E: HostEnv>(env: &mut E, balance_ptr: u32) -> Result<(), ExecutionErrors> {
+	charge_call(env)?;
+	// u128 little endian, the same as the scale encoding
+	let balance = state(env.store())?.balance.to_le_bytes();
+	write(env, &balance[..], balance_ptr, balance.len() as u32)
+}
+
+pub fn transfer<E: HostEnv>(
+	env: &mut E,
+	dest_ptr: u32,
+	dest_size: u32,
+	amount_ptr: u32,
+) -> Result<(), ExecutionErrors> {
+	charge_call(env)?;
+	let dest = read(env, dest_ptr, dest_size)?;
+	let amount = read(env, amount_ptr, 16)?;
+	let amount =
+		u128::from_le_bytes(amount.try_into().map_err(|_| ExecutionErrors::MemoryOutOfBounds)?);
+
+	kontracts_transfer(env.store(), dest, amount)
+}
+
+pub fn call_extension<E: HostEnv>(
+	env: &mut E,
+	id: u32,
+	input_ptr: u32,
+	input_len: u32,
+	output_ptr: u32,
+	output_max_size: u32,
+) -> Result<u32, ExecutionErrors> {
+	charge_call(env)?;
+	let input = read(env, input_ptr, input_len)?;
+	let output = resolve_request(env.store(), RequestKind::Extension(id), input)?;
+	write(env, &output[..], output_ptr, output_max_size)?;
+	Ok(output.len() as u32)
+}
+
+// The random value is known only by the runtime, so it is resolved as an extension
+pub fn random<E: HostEnv>(
+	env: &mut E,
+	subject_ptr: u32,
+	subject_len: u32,
+	out_ptr: u32,
+) -> Result<(), ExecutionErrors> {
+	charge_call(env)?;
+	let subject = read(env, subject_ptr, subject_len)?;
+	let random = resolve_request(env.store(), RequestKind::Random, subject)?;
+	write(env, &random[..], out_ptr, RANDOM_SIZE)
+}
+
+// The call is only queued, it is decoded and dispatched by the pallet
+// if the execution succeed
+pub fn dispatch_call<E: HostEnv>(
+	env: &mut E,
+	call_ptr: u32,
+	call_len: u32,
+) -> Result<(), ExecutionErrors> {
+	charge_call(env)?;
+	let call = read(env, call_ptr, call_len)?;
+	state(env.store())?.calls.push(call);
+	Ok(())
+}
+
+pub fn call<E: HostEnv>(
+	env: &mut E,
+	kontract_id_ptr: u32,
+	kontract_id_size: u32,
+	input_ptr: u32,
+	input_size: u32,
+	fuel: u64,
+) -> Result<(), ExecutionErrors> {
+	charge_call(env)?;
+	let kontract_id = read(env, kontract_id_ptr, kontract_id_size)?;
+	let input = read(env, input_ptr, input_size)?;
+
+	kontracts_call(env, kontract_id, input, fuel)
+}
+
+pub fn delegate_call<E: HostEnv>(
+	env: &mut E,
+	code_id_ptr: u32,
+	code_id_size: u32,
+	input_ptr: u32,
+	input_size: u32,
+) -> Result<(), ExecutionErrors> {
+	charge_call(env)?;
+	let code_id = read(env, code_id_ptr, code_id_size)?;
+	let input = read(env, input_ptr, input_size)?;
+
+	kontracts_delegate_call(env, code_id, input)
+}
+
+pub fn call_output_len<E: HostEnv>(env: &mut E) -> Result<u32, ExecutionErrors> {
+	charge_call(env)?;
+	Ok(env.store().call_output.len() as u32)
+}
+
+pub fn call_output<E: HostEnv>(
+	env: &mut E,
+	output_ptr: u32,
+	output_max_size: u32,
+) -> Result<(), ExecutionErrors> {
+	charge_call(env)?;
+	let output = env.store().call_output.clone();
+	write(env, &output[..], output_ptr, output_max_size)
+}
+
+pub fn set_output<E: HostEnv>(
+	env: &mut E,
+	output_ptr: u32,
+	output_size: u32,
+) -> Result<(), ExecutionErrors> {
+	charge_call(env)?;
+	let output = read(env, output_ptr, output_size)?;
+	env.store().output = output;
+	Ok(())
+}
+
+/// Store of the kontract called by the user, with every declared kontract in its context.
+/// Returns also the code, the entry point and the fuel of the execution
+pub fn prepare(
+	params: ExecutionParams,
+) -> Result<(KontractStore, Vec<u8>, EntryPoint, u64), ExecutionErrors> {
+	let ExecutionParams {
+		code,
+		abi_version,
+		storage,
+		storage_usage,
+		entry_point,
+		input,
+		fuel,
+		max_read,
+		max_write,
+		max_read_bytes,
+		max_write_bytes,
+		balance,
+		kontract_id,
+		dependencies,
+		libraries,
+		max_call_depth,
+		allow_reentrancy,
+		key_hashing,
+		storage_limits,
+		host_fn_costs,
+		metering,
+		extension_outputs,
+	} = params;
+
+	let decode_storage = |storage: RawKontractStorage| -> Result<KontractStorage, ExecutionErrors> {
+		Decode::decode(&mut &storage[..])
+			.map_err(|_| ExecutionErrors::ImpossibleDecodingKontractStorage)
+	};
+
+	let mut kontracts = BTreeMap::new();
+	for Dependency { kontract_id, code, abi_version, storage, storage_usage, balance } in
+		dependencies
+	{
+		kontracts.insert(
+			kontract_id,
+			KontractState {
+				code,
+				abi: abi_version,
+				storage: decode_storage(storage)?,
+				usage: storage_usage,
+				balance,
+				transfers: Vec::new(),
+				calls: Vec::new(),
+				touched: false,
+			},
+		);
+	}
+	// The code is also stored in the context, the kontract could be called again if
+	// reentrancy is allowed
+	kontracts.insert(
+		kontract_id.clone(),
+		KontractState {
+			code: code.clone(),
+			abi: abi_version,
+			storage: decode_storage(storage)?,
+			usage: storage_usage,
+			balance,
+			transfers: Vec::new(),
+			calls: Vec::new(),
+			touched: true,
+		},
+	);
+
+	let store = KontractStore {
+		kontract_id: kontract_id.clone(),
+		max_n_read: max_read,
+		max_n_write: max_write,
+		curr_n_read: 0,
+		curr_n_write: 0,
+		max_read_bytes,
+		max_write_bytes,
+		curr_read_bytes: 0,
+		curr_write_bytes: 0,
+		gas_left: fuel as u64,
+		input,
+		output: Vec::new(),
+		call_output: Vec::new(),
+		abi: abi_version,
+		context: CallContext {
+			kontracts,
+			libraries: libraries
+				.into_iter()
+				.map(|Library { code_id, code, abi_version }| (code_id, (code, abi_version)))
+				.collect(),
+			stack: sp_std::vec![kontract_id],
+			max_call_depth,
+			allow_reentrancy,
+			key_hashing,
+			storage_limits,
+			host_fn_costs,
+			metering,
+			extension_outputs,
+			next_extension: 0,
+			pending_extension: None,
+			error: None,
+		},
+	};
+
+	Ok((store, code, entry_point, fuel as u64))
+}
+
+/// Everything done by the execution, `result` is the error of the engine (if the kontract
+/// trapped) that is used only if no host function failed
+pub fn finish(
+	store: KontractStore,
+	result: Result<(), ExecutionErrors>,
+	fuel_consumed: u64,
+) -> Result<ExecutionOutcome, ExecutionErrors> {
+	let CallContext { mut kontracts, pending_extension, error, .. } = store.context;
+
+	// Nothing done by the execution matters, it will be repeated
+	if let Some(request) = pending_extension {
+		return Ok(ExecutionOutcome {
+			storage: Vec::new(),
+			storage_usage: StorageUsage::default(),
+			transfers: Vec::new(),
+			calls: Vec::new(),
+			dependencies: Vec::new(),
+			pending_extension: Some(request),
+			fuel_consumed,
+		});
+	}
+
+	result.map_err(|engine_error| error.unwrap_or(engine_error))?;
+
+	let KontractState { storage, usage, transfers, calls, .. } = kontracts
+		.remove(&store.kontract_id)
+		.ok_or(ExecutionErrors::UnexpectedBehavoiur)?;
+
+	let dependencies = kontracts
+		.into_iter()
+		.filter(|(_, kontract)| kontract.touched)
+		.map(|(kontract_id, kontract)| DependencyOutcome {
+			kontract_id,
+			storage: kontract.storage.encode(),
+			storage_usage: kontract.usage,
+			transfers: kontract.transfers,
+			calls: kontract.calls,
+		})
+		.collect();
+
+	Ok(ExecutionOutcome {
+		storage: storage.encode(),
+		storage_usage: usage,
+		transfers,
+		calls,
+		dependencies,
+		pending_extension: None,
+		fuel_consumed,
+	})
+}
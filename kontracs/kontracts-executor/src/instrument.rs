@@ -0,0 +1,85 @@
+// Gas metering injected in the code when it is uploaded: every basic block starts with
+// a call to `env.gas` with the cost of its instructions, so the consumption is defined
+// by the `InstructionCosts` of the pallet and not by the version of the engine.
+// Here is also checked which version of the host functions the uploaded code imports
+
+use super::{AbiVersion, InstructionCosts};
+use core::num::NonZeroU32;
+use sp_std::vec::Vec;
+use wasm_instrument::{
+	gas_metering::{self, MemoryGrowCost, Rules},
+	parity_wasm::elements::{self, Instruction, Module},
+};
+
+/// Module and name of the host function called by the injected code
+pub const GAS_MODULE: &str = "env";
+pub const GAS_FUNCTION: &str = "gas";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstrumentError {
+	/// The code is not a wasm binary (the text format can't be inspected)
+	InvalidWasm,
+	/// The injection failed, for example because the code already imports `env.gas`
+	InjectionFailed,
+	/// The code imports from a module that is not of the host functions
+	UnknownImportModule,
+	/// The code imports host functions of different versions
+	MixedAbiVersions,
+}
+
+impl Rules for InstructionCosts {
+	fn instruction_cost(&self, instruction: &Instruction) -> Option<u32> {
+		use Instruction::*;
+
+		Some(match instruction {
+			I32Load(..) | I64Load(..) | F32Load(..) | F64Load(..) | I32Load8S(..) |
+			I32Load8U(..) | I32Load16S(..) | I32Load16U(..) | I64Load8S(..) | I64Load8U(..) |
+			I64Load16S(..) | I64Load16U(..) | I64Load32S(..) | I64Load32U(..) => self.load,
+			I32Store(..) | I64Store(..) | F32Store(..) | F64Store(..) | I32Store8(..) |
+			I32Store16(..) | I64Store8(..) | I64Store16(..) | I64Store32(..) => self.store,
+			Br(..) | BrIf(..) | BrTable(..) | If(..) => self.branch,
+			Call(..) => self.call,
+			CallIndirect(..) => self.call_indirect,
+			I32DivS | I32DivU | I32RemS | I32RemU | I64DivS | I64DivU | I64RemS | I64RemU =>
+				self.div,
+			_ => self.regular,
+		})
+	}
+
+	fn memory_grow_cost(&self) -> MemoryGrowCost {
+		match NonZeroU32::new(self.memory_grow) {
+			Some(cost) => MemoryGrowCost::Linear(cost),
+			None => MemoryGrowCost::Free,
+		}
+	}
+}
+
+/// Return the code with the gas metering injected, ready to be stored
+pub fn inject_gas(code: &[u8], costs: &InstructionCosts) -> Result<Vec<u8>, InstrumentError> {
+	let module: Module =
+		elements::deserialize_buffer(code).map_err(|_| InstrumentError::InvalidWasm)?;
+	let module = gas_metering::inject(module, costs, GAS_MODULE)
+		.map_err(|_| InstrumentError::InjectionFailed)?;
+	elements::serialize(module).map_err(|_| InstrumentError::InjectionFailed)
+}
+
+/// Version of the host functions imported by the code, `env.gas` is the same for every
+/// version. Only the wasm binaries are accepted, the text format can't be inspected
+pub fn abi_version(code: &[u8]) -> Result<AbiVersion, InstrumentError> {
+	let module: Module =
+		elements::deserialize_buffer(code).map_err(|_| InstrumentError::InvalidWasm)?;
+
+	let mut version = None;
+	for import in module.import_section().map(|section| section.entries()).unwrap_or_default() {
+		if (import.module(), import.field()) == (GAS_MODULE, GAS_FUNCTION) {
+			continue
+		}
+		let import_version =
+			AbiVersion::of_module(import.module()).ok_or(InstrumentError::UnknownImportModule)?;
+		if *version.get_or_insert(import_version) != import_version {
+			return Err(InstrumentError::MixedAbiVersions)
+		}
+	}
+
+	Ok(version.unwrap_or_default())
+}
@@ -6,131 +6,607 @@ use sp_std::{collections::btree_map::BTreeMap, vec::Vec};
 
 pub type Key = Vec<u8>;
 pub type Value = Vec<u8>;
-type RawValue = (*const u8, usize);
 type RawKontractStorage = Vec<u8>;
 type KontractStorage = BTreeMap<Key, Value>;
 
 pub struct KontractStore {
+	// Encoded id of the kontract executed in this store
+	kontract_id: Vec<u8>,
+	// Reads and writes are shared by all the nested calls
 	max_n_read: u32,
 	max_n_write: u32,
 	curr_n_read: u32,
 	curr_n_write: u32,
+	// Bytes of the values read and written, shared like the counters
+	max_read_bytes: u32,
+	max_write_bytes: u32,
+	curr_read_bytes: u32,
+	curr_write_bytes: u32,
+	// Fuel left with `Metering::Instrumented`, otherwise it is counted by wasmtime
+	gas_left: u64,
+	input: Vec<u8>,
+	// Data returned by this kontract to its caller
+	output: Vec<u8>,
+	// Data returned by the last kontract called by this one
+	call_output: Vec<u8>,
+	// Version of the host functions imported by the code executed in this store,
+	// the only ones it can link
+	abi: AbiVersion,
+	context: CallContext,
+}
+
+/// State of every kontract that can be touched by the execution,
+/// moved from a store to the other when a kontract calls another one
+#[derive(Default)]
+pub struct CallContext {
+	kontracts: BTreeMap<Vec<u8>, KontractState>,
+	// Codes that can be executed over the storage of the caller, by code id
+	libraries: BTreeMap<Vec<u8>, (Vec<u8>, AbiVersion)>,
+	// Kontracts currently in execution, the first one is the one called by the user
+	stack: Vec<Vec<u8>>,
+	max_call_depth: u32,
+	allow_reentrancy: bool,
+	// The same for every kontract, so the foreign reads find the keys
+	key_hashing: KeyHashing,
+	storage_limits: StorageLimits,
+	host_fn_costs: HostFnCosts,
+	metering: Metering,
+	// Outputs of the requests already resolved by the pallet, in order
+	extension_outputs: Vec<Vec<u8>>,
+	next_extension: usize,
+	// Request that stopped the execution because its output is not known yet
+	pending_extension: Option<ExtensionRequest>,
+	// First error of a host function, the engine only knows that the kontract trapped
+	error: Option<ExecutionErrors>,
+}
+
+pub struct KontractState {
+	code: Vec<u8>,
+	abi: AbiVersion,
 	storage: KontractStorage,
+	// Updated at every insert and remove
+	usage: StorageUsage,
+	// Balance of the kontract minus the already queued transfers
+	balance: u128,
+	transfers: Vec<Transfer>,
+	// Encoded runtime calls dispatched by the pallet after the execution
+	calls: Vec<Vec<u8>>,
+	// Called at least once during the execution
+	touched: bool,
 }
 
-#[cfg(feature = "std")]
-mod kontracts_host_function {
-	use super::{Key, KontractStorage, KontractStore, RawValue, Value};
-	use codec::{Decode, Encode};
-	use sp_core::{Blake2Hasher, Hasher};
-	use wasmtime::{Caller, Trap};
-
-	// false = read
-	fn update_with_check(val: &mut u32, max: u32, r_or_w: bool) -> Result<(), Trap> {
-		*val = match *val + 1 {
-			x if x <= max => x,
-			_ =>
-				return Err(match r_or_w {
-					true => Trap::new("ExceededWrites"),
-					false => Trap::new("ExceededReads"),
-				}),
-		};
-		Ok(())
+/// Transfer requested by the kontract, applied by the pallet only if the execution succeed
+#[derive(codec::Encode, codec::Decode)]
+pub struct Transfer {
+	/// Encoded AccountId of the receiver
+	pub dest: Vec<u8>,
+	pub amount: u128,
+}
+
+/// Everything needed to execute a kontract
+#[derive(Clone, codec::Encode, codec::Decode, sp_runtime_interface::pass_by::PassByCodec)]
+pub struct ExecutionParams {
+	pub code: Vec<u8>,
+	/// Version of the host functions imported by `code`, recorded when it was uploaded
+	pub abi_version: AbiVersion,
+	pub storage: RawKontractStorage,
+	/// Usage of `storage` at the beginning of the execution
+	pub storage_usage: StorageUsage,
+	pub entry_point: EntryPoint,
+	pub input: Vec<u8>,
+	pub fuel: u32,
+	pub max_read: u32,
+	pub max_write: u32,
+	/// Max bytes of the values read and written during the whole execution
+	pub max_read_bytes: u32,
+	pub max_write_bytes: u32,
+	/// Balance owned by the kontract at the beginning of the execution
+	pub balance: u128,
+	/// Encoded id of the executed kontract
+	pub kontract_id: Vec<u8>,
+	/// Kontracts that can be called during the execution
+	pub dependencies: Vec<Dependency>,
+	/// Codes that can be executed with a delegate call
+	pub libraries: Vec<Library>,
+	pub max_call_depth: u32,
+	pub allow_reentrancy: bool,
+	pub key_hashing: KeyHashing,
+	/// Checked at every write of every kontract
+	pub storage_limits: StorageLimits,
+	/// Fuel charged by the host functions
+	pub host_fn_costs: HostFnCosts,
+	/// It has to be the one used when the codes were uploaded
+	pub metering: Metering,
+	/// Outputs of the extensions and of the random values requested by the kontract, in order
+	pub extension_outputs: Vec<Vec<u8>>,
+}
+
+impl ExecutionParams {
+	/// Parameters of the first version of `execute_code`: `main` with the first host functions,
+	/// engine fuel and the keys hashed with blake2, without other limits
+	pub fn original(
+		code: Vec<u8>,
+		storage: RawKontractStorage,
+		fuel: u32,
+		max_read: u32,
+		max_write: u32,
+	) -> Self {
+		ExecutionParams {
+			code,
+			abi_version: AbiVersion::V0,
+			storage,
+			storage_usage: StorageUsage::default(),
+			entry_point: EntryPoint::Main,
+			input: vec![],
+			fuel,
+			max_read,
+			max_write,
+			max_read_bytes: u32::MAX,
+			max_write_bytes: u32::MAX,
+			balance: 0,
+			kontract_id: vec![],
+			dependencies: vec![],
+			libraries: vec![],
+			max_call_depth: 0,
+			allow_reentrancy: false,
+			key_hashing: KeyHashing::Blake2,
+			storage_limits: StorageLimits {
+				max_key_size: u32::MAX,
+				max_value_size: u32::MAX,
+				max_entries: u32::MAX,
+				max_bytes: u32::MAX,
+			},
+			host_fn_costs: HostFnCosts::default(),
+			metering: Metering::Engine,
+			extension_outputs: vec![],
+		}
 	}
+}
 
-	// What I get here is raw bytes, probably already encoded by the wasm, but is not managed by the
-	// storage
-	pub fn kontracts_set(
-		caller: &mut Caller<'_, KontractStore>,
-		key: Key,
-		value: Value,
-	) -> Result<(), Trap> {
-		//println!("KONTRACS: Instert new elem (key: {:?}, value: {:?})", key, value);
+/// How the keys used by the kontracts are transformed before being stored
+#[derive(
+	Clone,
+	Copy,
+	PartialEq,
+	Eq,
+	Default,
+	codec::Encode,
+	codec::Decode,
+	codec::MaxEncodedLen,
+	frame_support::RuntimeDebug,
+	frame_support::pallet_prelude::TypeInfo,
+)]
+pub enum KeyHashing {
+	/// Blake2 256 of the key, the original key can't be recovered
+	#[default]
+	Blake2,
+	/// Blake2 128 of the key followed by the key itself, the keys are still spread
+	/// in the storage but they can be listed
+	Blake2Concat,
+	/// The key as it is, the storage is ordered by the keys of the kontract
+	Identity,
+}
 
-		let store = caller.data_mut();
+impl KeyHashing {
+	pub fn hash(&self, key: &[u8]) -> Vec<u8> {
+		match self {
+			KeyHashing::Blake2 => sp_core::hashing::blake2_256(key).to_vec(),
+			KeyHashing::Blake2Concat => [&sp_core::hashing::blake2_128(key)[..], key].concat(),
+			KeyHashing::Identity => key.to_vec(),
+		}
+	}
 
-		// Throw Trap if the number of write goes over the maximum supported number
-		update_with_check(&mut store.curr_n_write, store.max_n_write, true)?;
+	/// The key used by the kontract, if it can be recovered from the stored one
+	pub fn raw_key<'a>(&self, hashed_key: &'a [u8]) -> Option<&'a [u8]> {
+		match self {
+			KeyHashing::Blake2 => None,
+			KeyHashing::Blake2Concat => hashed_key.get(16..),
+			KeyHashing::Identity => Some(hashed_key),
+		}
+	}
+}
 
-		caller
-			.data_mut()
-			.storage
-			.insert(Blake2Hasher::hash(&key[..])[..].to_vec(), value);
+/// Who counts the fuel consumed by the wasm instructions
+#[derive(
+	Clone,
+	Copy,
+	PartialEq,
+	Eq,
+	Default,
+	codec::Encode,
+	codec::Decode,
+	codec::MaxEncodedLen,
+	frame_support::RuntimeDebug,
+	frame_support::pallet_prelude::TypeInfo,
+)]
+pub enum Metering {
+	/// The fuel of wasmtime, the consumption could change with the version of wasmtime
+	#[default]
+	Engine,
+	/// The codes are instrumented when uploaded, they call `env.gas` at the beginning
+	/// of every basic block with the cost defined by the `InstructionCosts`
+	Instrumented,
+}
 
-		Ok(())
+/// Engine that executes the kontracts, the semantics of the host functions are the same
+#[derive(
+	Clone,
+	Copy,
+	PartialEq,
+	Eq,
+	Default,
+	codec::Encode,
+	codec::Decode,
+	frame_support::RuntimeDebug,
+	frame_support::pallet_prelude::TypeInfo,
+)]
+pub enum Backend {
+	/// wasmtime in the node, through the `execute_code` host function
+	#[default]
+	Native,
+	/// wasmi compiled in the runtime, for the nodes and the tools without `execute_code`.
+	/// Only the wasm binaries can be executed and the fuel is the same of `Native` only
+	/// with `Metering::Instrumented`
+	Wasmi,
+}
+
+/// Version of the host functions imported by a kontract, every version has modules of its
+/// own so changing a host function never changes the codes already uploaded
+#[derive(
+	Clone,
+	Copy,
+	PartialEq,
+	Eq,
+	PartialOrd,
+	Ord,
+	Default,
+	codec::Encode,
+	codec::Decode,
+	codec::MaxEncodedLen,
+	frame_support::RuntimeDebug,
+	frame_support::pallet_prelude::TypeInfo,
+)]
+pub enum AbiVersion {
+	/// The first host functions, imported from `env` (or `kontracts_v0`)
+	#[default]
+	V0,
+	/// `get` and `get_foreign` write the value as it is and return its length like
+	/// `get_value` and `get_foreign_value`, `KEY_NOT_FOUND` if the key is not in the storage
+	V1,
+}
+
+impl AbiVersion {
+	/// Version of the new codes, the last one
+	pub const LATEST: AbiVersion = AbiVersion::V1;
+
+	/// Modules of the host functions of this version
+	pub fn modules(&self) -> &'static [&'static str] {
+		match self {
+			AbiVersion::V0 => &["env", "kontracts_v0"],
+			AbiVersion::V1 => &["kontracts_v1"],
+		}
+	}
+
+	/// Version of the host functions of `module`, if it is one of them
+	pub fn of_module(module: &str) -> Option<AbiVersion> {
+		[AbiVersion::V0, AbiVersion::V1]
+			.into_iter()
+			.find(|version| version.modules().contains(&module))
 	}
+}
+
+/// Fuel of every wasm instruction with `Metering::Instrumented`
+#[derive(
+	Clone,
+	Copy,
+	Default,
+	PartialEq,
+	Eq,
+	codec::Encode,
+	codec::Decode,
+	codec::MaxEncodedLen,
+	frame_support::RuntimeDebug,
+	frame_support::pallet_prelude::TypeInfo,
+)]
+pub struct InstructionCosts {
+	/// Every instruction without its own cost (arithmetic, locals, constants, ...)
+	pub regular: u32,
+	pub load: u32,
+	pub store: u32,
+	/// `br`, `br_if`, `br_table` and `if`
+	pub branch: u32,
+	pub call: u32,
+	pub call_indirect: u32,
+	/// Divisions and remainders
+	pub div: u32,
+	/// For every page added with `memory.grow`
+	pub memory_grow: u32,
+}
+
+/// Bounds of the storage of the pallet, a write that doesn't respect them
+/// stops the execution immediately
+#[derive(
+	Clone,
+	Copy,
+	Default,
+	PartialEq,
+	Eq,
+	codec::Encode,
+	codec::Decode,
+	codec::MaxEncodedLen,
+	frame_support::RuntimeDebug,
+	frame_support::pallet_prelude::TypeInfo,
+)]
+pub struct StorageLimits {
+	/// Max size of the stored key, so after the `KeyHashing`
+	pub max_key_size: u32,
+	pub max_value_size: u32,
+	/// Max number of entries in the storage of a kontract
+	pub max_entries: u32,
+	/// Max number of bytes (keys plus values) in the storage of a kontract
+	pub max_bytes: u32,
+}
+
+/// Fuel consumed by the host functions, on top of the fuel of the wasm instructions,
+/// so the work done by the host (hashing, copying memory, ...) is paid by the kontract
+#[derive(
+	Clone,
+	Copy,
+	Default,
+	PartialEq,
+	Eq,
+	codec::Encode,
+	codec::Decode,
+	codec::MaxEncodedLen,
+	frame_support::RuntimeDebug,
+	frame_support::pallet_prelude::TypeInfo,
+)]
+pub struct HostFnCosts {
+	/// Charged at every call of a host function
+	pub base: u64,
+	/// Charged for every byte copied between the memory of the kontract and the host
+	pub per_byte: u64,
+}
 
-	pub fn kontracts_get(
-		caller: &mut Caller<'_, KontractStore>,
-		key: Key,
-	) -> Result<Option<Value>, Trap> {
-		let store = caller.data_mut();
+/// Space used by the storage of a kontract, kept by the pallet and updated
+/// by the executor at every write, so the storage is never measured again
+#[derive(
+	Clone,
+	Copy,
+	Default,
+	PartialEq,
+	Eq,
+	codec::Encode,
+	codec::Decode,
+	codec::MaxEncodedLen,
+	frame_support::RuntimeDebug,
+	frame_support::pallet_prelude::TypeInfo,
+)]
+pub struct StorageUsage {
+	/// Sum of the stored keys and values
+	pub bytes: u32,
+	pub entries: u32,
+}
+
+/// Returned by `next_key` when there are no more keys, as size of `after` it lists from
+/// the first key
+pub const NO_KEY: u32 = u32::MAX;
+/// Returned by the storage host functions when the key is not in the storage
+pub const KEY_NOT_FOUND: u32 = u32::MAX;
+/// Returned by `get_range` when the offset is after the end of the value
+pub const OUT_OF_RANGE: u32 = u32::MAX - 1;
+
+/// Kontract declared before the execution, the only ones that can be called
+#[derive(Clone, codec::Encode, codec::Decode)]
+pub struct Dependency {
+	pub kontract_id: Vec<u8>,
+	pub code: Vec<u8>,
+	pub abi_version: AbiVersion,
+	pub storage: RawKontractStorage,
+	pub storage_usage: StorageUsage,
+	pub balance: u128,
+}
+
+/// Uploaded code declared before the execution, the only ones that can be delegate called
+#[derive(Clone, codec::Encode, codec::Decode)]
+pub struct Library {
+	pub code_id: Vec<u8>,
+	pub code: Vec<u8>,
+	pub abi_version: AbiVersion,
+}
+
+/// Everything produced by a successful execution
+#[derive(codec::Encode, codec::Decode)]
+pub struct ExecutionOutcome {
+	pub storage: RawKontractStorage,
+	pub storage_usage: StorageUsage,
+	pub transfers: Vec<Transfer>,
+	/// Encoded runtime calls requested by the kontract, in order
+	pub calls: Vec<Vec<u8>>,
+	/// Only the dependencies called at least once
+	pub dependencies: Vec<DependencyOutcome>,
+	/// If present the execution was stopped by an extension not yet resolved,
+	/// everything else is empty and the execution has to be repeated with its output
+	pub pending_extension: Option<ExtensionRequest>,
+	/// Fuel of the wasm instructions and of the host functions, nested calls included
+	pub fuel_consumed: u64,
+}
+
+/// Something requested by a kontract that only the runtime knows, serviced by the pallet
+#[derive(codec::Encode, codec::Decode)]
+pub struct ExtensionRequest {
+	/// Encoded id of the kontract that made the request
+	pub kontract_id: Vec<u8>,
+	pub kind: RequestKind,
+	pub input: Vec<u8>,
+}
+
+#[derive(codec::Encode, codec::Decode)]
+pub enum RequestKind {
+	/// Function registered by the runtime, the input is passed as it is
+	Extension(u32),
+	/// Random value of the runtime, the input is the subject
+	Random,
+}
+
+#[derive(codec::Encode, codec::Decode)]
+pub struct DependencyOutcome {
+	pub kontract_id: Vec<u8>,
+	pub storage: RawKontractStorage,
+	pub storage_usage: StorageUsage,
+	pub transfers: Vec<Transfer>,
+	pub calls: Vec<Vec<u8>>,
+}
+
+/// Size of the random values given to the kontracts, in Byte
+pub const RANDOM_SIZE: u32 = 32;
 
-		update_with_check(&mut store.curr_n_read, store.max_n_read, false)?;
+mod host;
+pub mod instrument;
+mod memory;
+mod wasmi_backend;
 
-		let value = store.storage.get(&Blake2Hasher::hash(&key.clone()[..])[..].to_vec());
+#[cfg(feature = "std")]
+mod kontracts_host_function {
+	use super::{
+		host::{self, HostEnv},
+		AbiVersion, EntryPoint, ExecutionErrors, ExecutionOutcome, ExecutionParams, KontractStore,
+		Metering,
+	};
+	use wasmtime::{Caller, Config, Engine, Extern, Linker, Module, Store, Trap, TypedFunc};
+
+	impl HostEnv for Caller<'_, KontractStore> {
+		fn store(&mut self) -> &mut KontractStore {
+			self.data_mut()
+		}
+
+		fn memory(&mut self) -> Result<&mut [u8], ExecutionErrors> {
+			match self.get_export("memory") {
+				Some(Extern::Memory(mem)) => Ok(mem.data_mut(self)),
+				_ => Err(ExecutionErrors::MemoryNotExported),
+			}
+		}
 
-		//println!("KONTRACS: Get elem (key: {:?}, value: {:?})", key, value);
+		fn consume_engine_fuel(&mut self, fuel: u64) -> Result<u64, ExecutionErrors> {
+			self.consume_fuel(fuel).map_err(|_| ExecutionErrors::OutOfFuel)
+		}
 
-		// TODO: here I have do to owned (that call clone underneath) to avoid managing lifetime...
-		Ok(value.map(|v| v.to_owned()))
+		fn add_engine_fuel(&mut self, fuel: u64) -> Result<(), ExecutionErrors> {
+			self.add_fuel(fuel).map_err(|_| ExecutionErrors::CallFailed)
+		}
+
+		fn run_nested(
+			&mut self,
+			data: KontractStore,
+			code: &[u8],
+			fuel: u64,
+		) -> (KontractStore, u64, Result<(), ExecutionErrors>) {
+			let metering = data.context.metering;
+			let mut nested = Store::new(self.engine(), data);
+
+			let result = match metering {
+				Metering::Engine => nested.add_fuel(fuel).map_err(|_| ExecutionErrors::CallFailed),
+				Metering::Instrumented => Ok(()),
+			}
+			.and_then(|_| {
+				instantiate(&mut nested, code, EntryPoint::Main)
+					.map_err(|_| ExecutionErrors::CallFailed)
+			})
+			.and_then(|main| main.call(&mut nested, ()).map_err(engine_error));
+			let consumed = fuel.saturating_sub(fuel_left(&mut nested));
+
+			(nested.into_data(), consumed, result)
+		}
 	}
 
-	pub fn kontracts_remove(caller: &mut Caller<'_, KontractStore>, key: Key) -> Result<(), Trap> {
-		let store = caller.data_mut();
+	// The host functions of the version `abi`
+	fn new_linker(
+		engine: &Engine,
+		abi: AbiVersion,
+	) -> Result<Linker<KontractStore>, ExecutionErrors> {
+		let mut linker = Linker::new(engine);
+
+		macro_rules! define {
+			($linker:expr, $module:expr, $name:expr, $f:ident($($arg:ident: $ty:ty),*) -> $ret:ty) => {
+				$linker
+					.func_wrap(
+						$module,
+						$name,
+						|mut caller: Caller<'_, KontractStore>, $($arg: $ty),*| -> Result<$ret, Trap> {
+							host::$f(&mut caller, $($arg),*).map_err(|error| {
+								host::record_error(caller.data_mut(), error);
+								Trap::new("HostFunctionFailed")
+							})
+						},
+					)
+					.map_err(|_| ExecutionErrors::ImpossibleCreateHostFunction)?;
+			};
+		}
+		host::host_functions!(define, linker, abi);
 
-		// Throw Trap if the number of write goes over the maximum supported number
-		// To make thik easier I count the remove as a write
-		update_with_check(&mut store.curr_n_write, store.max_n_write, true)?;
+		Ok(linker)
+	}
 
-		//println!("KONTRACS: Get key: {:?} to remove", key);
+	// Instantiate the code in the store and return the requested entry point
+	fn instantiate(
+		store: &mut Store<KontractStore>,
+		code: &[u8],
+		entry_point: EntryPoint,
+	) -> Result<TypedFunc<(), ()>, ExecutionErrors> {
+		let module =
+			Module::new(store.engine(), code).map_err(|_| ExecutionErrors::IncorrecBinary)?;
+
+		let abi = store.data().abi;
+		let instance = new_linker(store.engine(), abi)?
+			.instantiate(&mut *store, &module)
+			.map_err(|_| ExecutionErrors::ImpossibleCreateInstance)?;
 
-		store.storage.remove(&Blake2Hasher::hash(&key[..])[..].to_vec());
-		Ok(())
+		instance
+			.get_typed_func::<(), (), _>(&mut *store, entry_point.name())
+			.map_err(|_| match entry_point {
+				EntryPoint::Main => ExecutionErrors::MainEntryPointNotDefined,
+				_ => ExecutionErrors::EntryPointNotDefined,
+			})
 	}
 
-	pub fn read_vec(
-		caller: &mut Caller<'_, KontractStore>,
-		ptr: u32,
-		size: u32,
-	) -> Result<Value, Trap> {
-		let mem = match caller.get_export("memory") {
-			Some(wasmtime::Extern::Memory(mem)) => mem,
-			_ => return Err(Trap::new("Impossible reading wasm memory")),
-		};
-
-		// Use the `ptr` and `len` values to get a subslice of the wasm-memory
-		let wasm_slice: Option<&[u8]> = mem
-			.data(&caller)
-			.get(ptr as u32 as usize..)
-			.and_then(|arr| arr.get(..size as u32 as usize));
-
-		match wasm_slice {
-			Some(w) => Ok(w.to_vec()),
-			None => Err(Trap::new("Impossible reading wasm memory")),
+	// The errors of the host functions are in the context, here there are only
+	// the traps of wasmtime
+	fn engine_error(trap: Trap) -> ExecutionErrors {
+		match format!("{}", trap.display_reason()).as_str() {
+			"all fuel consumed by WebAssembly" => ExecutionErrors::OutOfFuel,
+			_ => ExecutionErrors::WasmPanic,
 		}
 	}
 
-	pub fn write_vec(
-		caller: &mut Caller<'_, KontractStore>,
-		vec: Vec<u8>,
-		ptr: u32,
-		size: u32,
-	) -> Result<(), Trap> {
-		let mem = match caller.get_export("memory") {
-			Some(wasmtime::Extern::Memory(mem)) => mem,
-			_ => return Err(Trap::new("Impossible write data in wasm memory")),
-		};
-
-		if (size as usize) < vec.len() {
-			return Err(Trap::new("Impossible write data in wasm memory"))
+	fn fuel_left(store: &mut Store<KontractStore>) -> u64 {
+		match store.data().context.metering {
+			Metering::Engine => store.consume_fuel(0).unwrap_or(0),
+			Metering::Instrumented => store.data().gas_left,
 		}
+	}
+
+	pub fn execute(params: ExecutionParams) -> Result<ExecutionOutcome, ExecutionErrors> {
+		let (data, code, entry_point, fuel) = host::prepare(params)?;
+		let metering = data.context.metering;
 
-		let wasm_buffer = &mut mem.data_mut(caller)[ptr as usize..=ptr as usize + vec.len()];
+		let engine = Engine::new(Config::new().consume_fuel(metering == Metering::Engine))
+			.map_err(|_| ExecutionErrors::ImpossibleCreateEngine)?;
+		let mut store = Store::new(&engine, data);
 
-		wasm_buffer.copy_from_slice(&vec.encode()[..]);
+		if metering == Metering::Engine {
+			store.add_fuel(fuel).map_err(|_| ExecutionErrors::ImpossibleAddFuel)?;
+		}
 
-		Ok(())
+		let main = instantiate(&mut store, &code[..], entry_point)?;
+
+		// And finally we can call the wasm!
+		// Here there is a GIGANTIC generalization...
+		// not all TRAPs are equal to panic but with only 4 days I don't have time to
+		// manage all of them.... Trap = Panic (removed my own traps)
+		let result = main.call(&mut store, ()).map_err(engine_error);
+		let consumed = fuel.saturating_sub(fuel_left(&mut store));
+
+		host::finish(store.into_data(), result, consumed)
 	}
 }
 
@@ -152,6 +628,7 @@ pub enum ExecutionErrors {
 	IncorrecBinary,
 	ImpossibleCreateInstance, // Not sure why this happen
 	MainEntryPointNotDefined,
+	EntryPointNotDefined,
 	WasmPanic,
 	ImpossibleAddFuel,
 	ImpossibleCreateEngine,
@@ -161,8 +638,83 @@ pub enum ExecutionErrors {
 	OutOfFuel,
 	OutOfReads,
 	OutOfWrites,
+	/// Read more bytes than `max_read_bytes`
+	OutOfReadBytes,
+	/// Written more bytes than `max_write_bytes`
+	OutOfWriteBytes,
+	InsufficientBalance,
+	/// Too many nested calls
+	MaxCallDepthReached,
+	/// The kontract is already in execution
+	ReentrancyDenied,
+	/// The called kontract is not in the declared dependencies
+	KontractNotDeclared,
+	/// The called kontract can't be instantiated
+	CallFailed,
+	/// The delegate called code is not in the declared libraries
+	CodeNotDeclared,
+	/// The keys can't be listed with the `KeyHashing` of the runtime
+	KeysNotRecoverable,
+	/// The kontract doesn't export its memory
+	MemoryNotExported,
+	/// A host function was called with a pointer outside the memory of the kontract
+	MemoryOutOfBounds,
+	/// The buffer given to a host function can't contain the data
+	BufferTooSmall,
+	/// The stored key would be bigger than `MaxKontracStorageKeySize`
+	KeyTooLarge,
+	/// The stored value would be bigger than `MaxKontracStorageValueSize`
+	ValueTooLarge,
+	/// The storage of the kontract would exceed `MaxStorageEntries` or `MaxStorageBytes`
+	StorageFull,
+}
+
+/// Exported function of the kontract that will be called by the executor
+#[derive(
+	Clone,
+	Copy,
+	PartialEq,
+	Eq,
+	codec::Encode,
+	codec::Decode,
+	sp_runtime_interface::pass_by::PassByCodec,
+)]
+pub enum EntryPoint {
+	/// Normal execution of the kontract
+	Main,
+	/// Executed only once, when the code of the kontract is upgraded,
+	/// to adapt the old storage to the new code
+	Migrate,
+	/// Constructor of the kontract, executed only once when the kontract is instantiated
+	Deploy,
+}
+
+impl EntryPoint {
+	pub fn name(&self) -> &'static str {
+		match self {
+			EntryPoint::Main => "main",
+			EntryPoint::Migrate => "migrate",
+			EntryPoint::Deploy => "deploy",
+		}
+	}
+}
+
+/// Execute the kontract with the chosen backend
+pub fn execute(
+	backend: Backend,
+	params: ExecutionParams,
+) -> Result<ExecutionOutcome, ExecutionErrors> {
+	match backend {
+		Backend::Native => kontracts_executor::execute_code(params),
+		Backend::Wasmi => wasmi_backend::execute(params),
+	}
 }
 
+// Every version has to stay for the old runtimes. Version 1 is the original interface,
+// executed as before (`ExecutionParams::original`). From version 2 the runtime gives the
+// version of every code so a new version of the host functions doesn't change it. Any change
+// of `ExecutionParams`, `ExecutionOutcome` or of the types they contain changes their
+// encoding and needs a new version of `execute_code` with new types
 #[sp_runtime_interface::runtime_interface]
 pub trait KontractsExecutor {
 	fn execute_code(
@@ -173,135 +725,15 @@ pub trait KontractsExecutor {
 		max_read: u32,
 		max_write: u32,
 	) -> Result<RawKontractStorage, ExecutionErrors> {
-		//println!("Entered in the KontractExecutor");
-
-		use codec::{Decode, Encode};
-		use wasmtime::*;
-
-		let engine = Engine::new(Config::new().consume_fuel(true))
-			.map_err(|_| ExecutionErrors::ImpossibleCreateEngine)?;
-
-		let module = Module::new(&engine, code).map_err(|_| ExecutionErrors::IncorrecBinary)?;
-
-		let mut store = Store::new(
-			&engine,
-			KontractStore {
-				max_n_read: max_read,
-				max_n_write: max_write,
-				curr_n_read: 0,
-				curr_n_write: 0,
-				storage: Decode::decode(&mut &storage[..])
-					.map_err(|_| ExecutionErrors::ImpossibleDecodingKontractStorage)?,
-			},
-		);
-
-		store.add_fuel(fuel as u64).map_err(|_| ExecutionErrors::ImpossibleAddFuel)?;
-
-		let mut linker = Linker::new(&engine);
-
-		linker
-			.func_wrap(
-				"env",
-				"set",
-				|mut caller: Caller<'_, KontractStore>,
-				 key_ptr: u32,
-				 key_size: u32,
-				 value_ptr: u32,
-				 value_size: u32|
-				 -> Result<(), Trap> {
-					// The inputs are the pointers the begining of the vec
-					// Those are u32 because the wasm executor work in 32bit
-					// to create a real sandbox execution
-					//
-					// I think that could be added some sort of mememory error handling,
-					// 100% I'm forgetting something
-
-					let key_vec =
-						kontracts_host_function::read_vec(&mut caller, key_ptr, key_size)?;
-					let value_vec =
-						kontracts_host_function::read_vec(&mut caller, value_ptr, value_size)?;
-
-					//println!("Key Vec: {:?}", key_vec);
-					//println!("Value Vec: {:?}", value_vec);
-
-					kontracts_host_function::kontracts_set(&mut caller, key_vec, value_vec)
-				},
-			)
-			.map_err(|_| ExecutionErrors::ImpossibleDecodingKontractStorage)?;
-
-		linker
-			.func_wrap(
-				"env",
-				"get",
-				|mut caller: Caller<'_, KontractStore>,
-				 key_ptr: u32,
-				 key_size: u32,
-				 value_ptr: u32,
-				 value_max_size: u32|
-				 -> Result<(), Trap> {
-					let key_vec =
-						kontracts_host_function::read_vec(&mut caller, key_ptr, key_size)?;
-
-					//println!("Key Vec: {:?}", key_vec);
-
-					// I think this is no a really good approch, no enough time to do a better one:
-					// If the key is not present in the storage than I use an empty vec, write it
-					// in the wasm buffer so that in the other side the initial of the buffer is a
-					// compact encoding 0 => empty vec = no value
-					let value_vec = kontracts_host_function::kontracts_get(&mut caller, key_vec)?
-						.unwrap_or(vec![]);
-
-					//println!("Key Vec From the storage: {:?}", value_vec);
-
-					kontracts_host_function::write_vec(
-						&mut caller,
-						value_vec,
-						value_ptr,
-						value_max_size,
-					)
-				},
-			)
-			.map_err(|_| ExecutionErrors::ImpossibleDecodingKontractStorage)?;
-
-		linker
-			.func_wrap(
-				"env",
-				"remove",
-				|mut caller: Caller<'_, KontractStore>,
-				 key_ptr: u32,
-				 key_size: u32|
-				 -> Result<(), Trap> {
-					let key_vec =
-						kontracts_host_function::read_vec(&mut caller, key_ptr, key_size)?;
-
-					kontracts_host_function::kontracts_remove(&mut caller, key_vec)
-				},
-			)
-			.map_err(|_| ExecutionErrors::ImpossibleDecodingKontractStorage)?;
-
-		let instance = linker
-			.instantiate(&mut store, &module)
-			.map_err(|_| ExecutionErrors::ImpossibleCreateInstance)?;
-
-		let main = instance
-			.get_typed_func::<(), (), _>(&mut store, "main")
-			.map_err(|_| ExecutionErrors::MainEntryPointNotDefined)?;
-
-		// And finally we can call the wasm!
-		// Here there is a GIGANTIC generalization...
-		// not all TRAPs are equal to panic but with only 4 days I don't have time to
-		// manage all of them.... Trap = Panic (removed my own traps)
-		main.call(&mut store, ()).map_err(|trap| {
-			match format!("{}", trap.display_reason()).as_str() {
-				"all fuel consumed by WebAssembly" => ExecutionErrors::OutOfFuel,
-				"ExceededReads" => ExecutionErrors::OutOfReads,
-				"ExceededWrites" => ExecutionErrors::OutOfWrites,
-				_ => ExecutionErrors::WasmPanic,
-			}
-		})?;
-
-		// println!("{:?}", store.data());
+		let params = ExecutionParams::original(code, storage, fuel, max_read, max_write);
+		kontracts_host_function::execute(params).map(|outcome| outcome.storage)
+	}
 
-		Ok(store.data().storage.encode())
+	#[version(2)]
+	fn execute_code(
+		&mut self,
+		params: ExecutionParams,
+	) -> Result<ExecutionOutcome, ExecutionErrors> {
+		kontracts_host_function::execute(params)
 	}
 }
@@ -0,0 +1,69 @@
+// Every access of the host functions to the memory of the kontract goes through here,
+// the pointers and the sizes come from the kontract so nothing can be trusted:
+// every range is checked and a wrong access is an error, never a panic of the node.
+// The memory is the slice exported by the kontract, whatever is the backend
+
+use super::ExecutionErrors;
+use codec::Encode;
+use core::ops::Range;
+use sp_std::vec::Vec;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryError {
+	/// The kontract doesn't export its memory as `memory`
+	MemoryNotExported,
+	/// The range is outside the memory of the kontract (or `ptr + len` overflows)
+	OutOfBounds,
+	/// The data doesn't fit in the buffer given by the kontract
+	BufferTooSmall,
+}
+
+impl From<MemoryError> for ExecutionErrors {
+	fn from(error: MemoryError) -> ExecutionErrors {
+		match error {
+			MemoryError::MemoryNotExported => ExecutionErrors::MemoryNotExported,
+			MemoryError::OutOfBounds => ExecutionErrors::MemoryOutOfBounds,
+			MemoryError::BufferTooSmall => ExecutionErrors::BufferTooSmall,
+		}
+	}
+}
+
+fn range(ptr: u32, len: usize) -> Result<Range<usize>, MemoryError> {
+	let start = ptr as usize;
+	let end = start.checked_add(len).ok_or(MemoryError::OutOfBounds)?;
+	Ok(start..end)
+}
+
+/// Copy `len` bytes from the memory of the kontract
+pub fn read(memory: &[u8], ptr: u32, len: u32) -> Result<Vec<u8>, MemoryError> {
+	memory
+		.get(range(ptr, len as usize)?)
+		.map(|data| data.to_vec())
+		.ok_or(MemoryError::OutOfBounds)
+}
+
+/// Copy the raw bytes in the buffer of `max_size` bytes given by the kontract,
+/// only the first `data.len()` bytes of the buffer are touched
+pub fn write(memory: &mut [u8], data: &[u8], ptr: u32, max_size: u32) -> Result<(), MemoryError> {
+	if data.len() > max_size as usize {
+		return Err(MemoryError::BufferTooSmall)
+	}
+
+	memory
+		.get_mut(range(ptr, data.len())?)
+		.ok_or(MemoryError::OutOfBounds)?
+		.copy_from_slice(data);
+	Ok(())
+}
+
+/// SCALE encode the value and copy it in the buffer, for a `Vec<u8>` this means
+/// the compact encoded length (1 to 5 bytes) followed by the bytes, so the buffer
+/// has to be bigger than the vec itself
+pub fn write_encoded<E: Encode>(
+	memory: &mut [u8],
+	value: &E,
+	ptr: u32,
+	max_size: u32,
+) -> Result<(), MemoryError> {
+	write(memory, &value.encode()[..], ptr, max_size)
+}
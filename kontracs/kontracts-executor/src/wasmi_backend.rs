@@ -0,0 +1,147 @@
+// The same execution of `execute_code` with wasmi compiled in the runtime, slower than
+// wasmtime but available also where the host function is not (try-runtime, light clients,
+// other clients). wasmi doesn't parse the text format, so only the binaries are executed
+
+use super::{
+	host::{self, HostEnv},
+	EntryPoint, ExecutionErrors, ExecutionOutcome, ExecutionParams, KontractStore, Metering,
+};
+use wasmi::{
+	core::{Trap, TrapCode},
+	Caller, Config, Engine, Extern, Func, Linker, Module, Store, TypedFunc,
+};
+
+impl HostEnv for Caller<'_, KontractStore> {
+	fn store(&mut self) -> &mut KontractStore {
+		self.data_mut()
+	}
+
+	fn memory(&mut self) -> Result<&mut [u8], ExecutionErrors> {
+		match self.get_export("memory").and_then(Extern::into_memory) {
+			Some(mem) => Ok(mem.data_mut(self)),
+			None => Err(ExecutionErrors::MemoryNotExported),
+		}
+	}
+
+	fn consume_engine_fuel(&mut self, fuel: u64) -> Result<u64, ExecutionErrors> {
+		self.consume_fuel(fuel).map_err(|_| ExecutionErrors::OutOfFuel)
+	}
+
+	fn add_engine_fuel(&mut self, fuel: u64) -> Result<(), ExecutionErrors> {
+		self.add_fuel(fuel).map_err(|_| ExecutionErrors::CallFailed)
+	}
+
+	fn run_nested(
+		&mut self,
+		data: KontractStore,
+		code: &[u8],
+		fuel: u64,
+	) -> (KontractStore, u64, Result<(), ExecutionErrors>) {
+		// A new engine, the one of the caller is still executing the caller
+		let metering = data.context.metering;
+		let mut nested = new_store(data);
+
+		let result = match metering {
+			Metering::Engine => nested.add_fuel(fuel).map_err(|_| ExecutionErrors::CallFailed),
+			Metering::Instrumented => Ok(()),
+		}
+		.and_then(|_| {
+			instantiate(&mut nested, code, EntryPoint::Main)
+				.map_err(|_| ExecutionErrors::CallFailed)
+		})
+		.and_then(|main| main.call(&mut nested, ()).map_err(engine_error));
+		let consumed = fuel.saturating_sub(fuel_left(&mut nested));
+
+		(nested.into_data(), consumed, result)
+	}
+}
+
+fn new_store(data: KontractStore) -> Store<KontractStore> {
+	let mut config = Config::default();
+	config.consume_fuel(data.context.metering == Metering::Engine);
+	Store::new(&Engine::new(&config), data)
+}
+
+// The host functions of the version of the executed code, the functions of wasmi belong
+// to the store so the linker can't be shared between the stores
+fn new_linker(store: &mut Store<KontractStore>) -> Result<Linker<KontractStore>, ExecutionErrors> {
+	let abi = store.data().abi;
+	let mut linker = Linker::new(store.engine());
+
+	macro_rules! define {
+		($linker:expr, $module:expr, $name:expr, $f:ident($($arg:ident: $ty:ty),*) -> $ret:ty) => {
+			let func = Func::wrap(
+				&mut *store,
+				|mut caller: Caller<'_, KontractStore>, $($arg: $ty),*| -> Result<$ret, Trap> {
+					host::$f(&mut caller, $($arg),*).map_err(|error| {
+						host::record_error(caller.data_mut(), error);
+						Trap::new("HostFunctionFailed")
+					})
+				},
+			);
+			$linker
+				.define($module, $name, func)
+				.map_err(|_| ExecutionErrors::ImpossibleCreateHostFunction)?;
+		};
+	}
+	host::host_functions!(define, linker, abi);
+
+	Ok(linker)
+}
+
+// Instantiate the code in the store and return the requested entry point
+fn instantiate(
+	store: &mut Store<KontractStore>,
+	code: &[u8],
+	entry_point: EntryPoint,
+) -> Result<TypedFunc<(), ()>, ExecutionErrors> {
+	let module = Module::new(store.engine(), code).map_err(|_| ExecutionErrors::IncorrecBinary)?;
+
+	let instance = new_linker(store)?
+		.instantiate(&mut *store, &module)
+		.map_err(|_| ExecutionErrors::ImpossibleCreateInstance)?
+		.start(&mut *store)
+		.map_err(|_| ExecutionErrors::ImpossibleCreateInstance)?;
+
+	instance
+		.get_export(&*store, entry_point.name())
+		.and_then(Extern::into_func)
+		.and_then(|func| func.typed::<(), ()>(&*store).ok())
+		.ok_or(match entry_point {
+			EntryPoint::Main => ExecutionErrors::MainEntryPointNotDefined,
+			_ => ExecutionErrors::EntryPointNotDefined,
+		})
+}
+
+// The errors of the host functions are in the context, here there are only
+// the traps of wasmi
+fn engine_error(trap: Trap) -> ExecutionErrors {
+	match trap.trap_code() {
+		Some(TrapCode::OutOfFuel) => ExecutionErrors::OutOfFuel,
+		_ => ExecutionErrors::WasmPanic,
+	}
+}
+
+fn fuel_left(store: &mut Store<KontractStore>) -> u64 {
+	match store.data().context.metering {
+		Metering::Engine => store.consume_fuel(0).unwrap_or(0),
+		Metering::Instrumented => store.data().gas_left,
+	}
+}
+
+pub fn execute(params: ExecutionParams) -> Result<ExecutionOutcome, ExecutionErrors> {
+	let (data, code, entry_point, fuel) = host::prepare(params)?;
+	let metering = data.context.metering;
+	let mut store = new_store(data);
+
+	if metering == Metering::Engine {
+		store.add_fuel(fuel).map_err(|_| ExecutionErrors::ImpossibleAddFuel)?;
+	}
+
+	let main = instantiate(&mut store, &code[..], entry_point)?;
+
+	let result = main.call(&mut store, ()).map_err(engine_error);
+	let consumed = fuel.saturating_sub(fuel_left(&mut store));
+
+	host::finish(store.into_data(), result, consumed)
+}
@@ -0,0 +1,44 @@
+use crate::Config;
+use core::marker::PhantomData;
+use frame_support::{inherent::Vec, traits::Get};
+use kontracts_executor::{
+	instrument::{self, InstrumentError},
+	AbiVersion, ExecutionErrors, ExecutionOutcome, ExecutionParams, InstructionCosts, Metering,
+};
+
+/// What the pallet needs to run the kontracts, everything else (deposits, events, errors)
+/// is done by the pallet with the outcome, so the executor can be replaced by a mock
+pub trait KontractExecutor {
+	/// Execute the entry point of the code, stopped at the first extension not yet resolved
+	fn execute(params: ExecutionParams) -> Result<ExecutionOutcome, ExecutionErrors>;
+
+	/// The uploaded code as it will be stored with the version of the host functions
+	/// it imports, an error if it can't be executed with `metering`
+	fn validate(
+		code: Vec<u8>,
+		metering: Metering,
+		costs: &InstructionCosts,
+	) -> Result<(Vec<u8>, AbiVersion), InstrumentError>;
+}
+
+/// The executor of `kontracts_executor` with the `Backend` of the `Config`
+pub struct HostExecutor<T>(PhantomData<T>);
+
+impl<T: Config> KontractExecutor for HostExecutor<T> {
+	fn execute(params: ExecutionParams) -> Result<ExecutionOutcome, ExecutionErrors> {
+		kontracts_executor::execute(T::Backend::get(), params)
+	}
+
+	fn validate(
+		code: Vec<u8>,
+		metering: Metering,
+		costs: &InstructionCosts,
+	) -> Result<(Vec<u8>, AbiVersion), InstrumentError> {
+		let code = match metering {
+			Metering::Engine => code,
+			Metering::Instrumented => instrument::inject_gas(&code[..], costs)?,
+		};
+		let abi_version = instrument::abi_version(&code[..])?;
+		Ok((code, abi_version))
+	}
+}
@@ -0,0 +1,20 @@
+use frame_support::{inherent::Vec, pallet_prelude::DispatchError};
+
+/// Functionalities of the runtime exposed to the kontracts, every function is identified
+/// by a number and the kontracts call it with `call_extension(id, input, output)`
+pub trait KontractExtension<AccountId> {
+	/// Service the request `id` of the kontract that owns `kontract_account`,
+	/// the output is given back to the kontract
+	fn call(
+		kontract_account: &AccountId,
+		id: u32,
+		input: Vec<u8>,
+	) -> Result<Vec<u8>, DispatchError>;
+}
+
+/// No extension, every request fails
+impl<AccountId> KontractExtension<AccountId> for () {
+	fn call(_: &AccountId, _: u32, _: Vec<u8>) -> Result<Vec<u8>, DispatchError> {
+		Err(DispatchError::Other("UnknownExtension"))
+	}
+}
@@ -3,8 +3,17 @@
 /// Edit this file to define custom logic or remove it if it is not needed.
 /// Learn more about FRAME and the core library of Substrate FRAME pallets:
 /// <https://docs.substrate.io/reference/frame-pallets/>
+pub use executor::{HostExecutor, KontractExecutor};
+pub use extension::KontractExtension;
+pub use kontracts_executor::{
+	AbiVersion, Backend, HostFnCosts, InstructionCosts, KeyHashing, Metering, StorageUsage,
+};
 pub use pallet::*;
 
+pub mod executor;
+pub mod extension;
+pub mod migration;
+
 #[cfg(test)]
 mod mock;
 
@@ -16,20 +25,37 @@ mod benchmarking;
 
 #[frame_support::pallet]
 pub mod pallet {
-	use codec::{Decode, Encode, EncodeLike, FullCodec};
+	use crate::{KontractExecutor, KontractExtension};
+	use codec::{Decode, DecodeLimit, Encode, EncodeLike, FullCodec};
 	use frame_support::{
-		dispatch::MaxEncodedLen,
+		dispatch::{extract_actual_weight, GetDispatchInfo, MaxEncodedLen, PostDispatchInfo},
 		inherent::Vec,
 		pallet_prelude::*,
-		traits::{Currency, ReservableCurrency},
-		BoundedBTreeMap, BoundedVec,
+		sp_runtime::{
+			traits::{AccountIdConversion, Dispatchable, Saturating, Zero},
+			SaturatedConversion, TransactionOutcome,
+		},
+		storage::with_transaction,
+		traits::{Contains, Currency, ExistenceRequirement, Randomness, ReservableCurrency},
+		BoundedBTreeMap, BoundedVec, PalletId,
 	};
 	use frame_system::pallet_prelude::*;
-	use kontracts_executor::{kontracts_executor::*, ExecutionErrors};
-	use sp_core::Hasher;
+	use kontracts_executor::{
+		AbiVersion, Backend, Dependency, DependencyOutcome, EntryPoint, ExecutionErrors,
+		ExecutionOutcome, ExecutionParams, ExtensionRequest, HostFnCosts, InstructionCosts,
+		KeyHashing, Library, Metering, RequestKind, StorageLimits, StorageUsage, Transfer,
+	};
+	use sp_core::{hashing::blake2_256, Hasher};
+
+	/// Max depth of the calls encoded by the kontracts, the same used for the extrinsics
+	const MAX_CALL_DECODE_DEPTH: u32 = 256;
+
+	/// Version of the storage, `migration::migrate` brings the older ones to it
+	const STORAGE_VERSION: StorageVersion = StorageVersion::new(4);
 
 	#[pallet::pallet]
 	#[pallet::generate_store(pub(super) trait Store)]
+	#[pallet::storage_version(STORAGE_VERSION)]
 	pub struct Pallet<T>(_);
 
 	/// Configure the pallet by specifying the parameters and types on which it depends.
@@ -50,12 +76,101 @@ pub mod pallet {
 		/// Max size of the storage key, in Byte
 		type MaxKontracStorageKeySize: Get<u32>;
 
-		/// Max size of the storage, in Byte
-		type MaxKontracStorageSize: Get<u32>;
+		/// Max number of entries in the storage of a kontract, the `Schedule` can only lower it
+		type MaxStorageEntries: Get<u32>;
+
+		/// Max size of the storage of a kontract (keys plus values), in Byte,
+		/// the `Schedule` can only lower it
+		type MaxStorageBytes: Get<u32>;
 
 		/// Max code size, in Byte
 		type MaxCodeSize: Get<u32>;
 
+		/// Deposit reserved from the uploader for every Byte of code stored on chain,
+		/// used until the root sets a `Schedule`
+		type CodeDepositPerByte: Get<BalanceOf<Self>>;
+
+		/// Max number of deleted kontracts waiting for their deposits to be released
+		type MaxDeletionQueue: Get<u32>;
+
+		/// Max size of the salt used to derive the address of a kontract, in Byte
+		type MaxSaltLen: Get<u32>;
+
+		/// Max size of the input given to the constructor of a kontract, in Byte
+		type MaxInputLen: Get<u32>;
+
+		/// Used to derive the account that holds the balance of every kontract
+		#[pallet::constant]
+		type PalletId: Get<PalletId>;
+
+		/// Max number of kontracts that can be called during a single execution
+		#[pallet::constant]
+		type MaxDependencies: Get<u32>;
+
+		/// Max number of nested calls between kontracts
+		#[pallet::constant]
+		type MaxCallDepth: Get<u32>;
+
+		/// If a kontract can be called while it is already in execution
+		#[pallet::constant]
+		type AllowReentrancy: Get<bool>;
+
+		/// Calls that can be dispatched by the kontracts
+		type RuntimeCall: Parameter
+			+ Dispatchable<
+				RuntimeOrigin = <Self as frame_system::Config>::RuntimeOrigin,
+				PostInfo = PostDispatchInfo,
+			> + GetDispatchInfo;
+
+		/// Only the calls accepted by the filter can be dispatched by the kontracts
+		type CallFilter: Contains<<Self as Config>::RuntimeCall>;
+
+		/// Max weight of all the calls dispatched during a single execution,
+		/// charged in advance to the caller and refunded if not used
+		#[pallet::constant]
+		type MaxDispatchWeight: Get<Weight>;
+
+		/// Functions of the runtime that the kontracts can call with `call_extension`
+		type Extension: KontractExtension<AccountId<Self>>;
+
+		/// How the keys of the kontracts are stored, with `Blake2` the kontracts can't list
+		/// their keys. Fixed by the first successful execution, then the executions fail with
+		/// `KeyHashingChanged` if it is changed
+		#[pallet::constant]
+		type KeyHashing: Get<KeyHashing>;
+
+		/// Source of the random values requested by the kontracts with `random`
+		type Randomness: Randomness<Self::Hash, BlockNumberFor<Self>>;
+
+		/// Max number of extension calls (random values included) during a single execution,
+		/// every call not yet resolved requires to repeat the execution
+		#[pallet::constant]
+		type MaxExtensionCalls: Get<u32>;
+
+		/// Fuel charged by the host functions, it has to follow the weight of the host
+		/// functions because the fuel is converted in weight with `fuel_weight`.
+		/// Used until the root sets a `Schedule`
+		#[pallet::constant]
+		type HostFnCosts: Get<HostFnCosts>;
+
+		/// Who counts the fuel of the wasm instructions, with `Instrumented` the gas metering
+		/// is injected in the codes when uploaded. It is recorded in the `CodeInfo` and every
+		/// code is executed with its own, so changing it affects only the new codes
+		#[pallet::constant]
+		type Metering: Get<Metering>;
+
+		/// Engine used by `HostExecutor`, `Wasmi` doesn't need the `execute_code` host
+		/// function of the node. Both give the same results, but the fuel consumed is the same
+		/// only with `Metering::Instrumented`, so `Wasmi` is accepted only with it.
+		/// The backend is part of the consensus: all the nodes must execute the kontracts
+		/// with the same one, it can change only with a runtime upgrade
+		#[pallet::constant]
+		type Backend: Get<Backend>;
+
+		/// Executes and validates the codes, `HostExecutor` unless the execution has to be
+		/// replaced (for example by a mock in the tests)
+		type Executor: KontractExecutor;
+
         /// Identifier of the code
 		type CodeId: Decode
 			+ FullCodec
@@ -65,24 +180,172 @@ pub mod pallet {
 			+ TypeInfo
             + frame_support::dispatch::fmt::Debug;
 
-        /// Hashing Algorith used to evaluate the code id based on the wasm binary
+        /// Hashing Algorith used to evaluate the code id based on the wasm binary,
+		/// also used to derive the address of the kontracts
 		type HashingAlgorith: Hasher<Out = Self::CodeId>;
 	}
 
 	pub type AccountId<T> = <T as frame_system::Config>::AccountId;
+	pub type BalanceOf<T> = <<T as Config>::Currency as Currency<AccountId<T>>>::Balance;
+	/// Address of a kontract, it is derived from the code, the deployer and a salt,
+	/// so it has the same shape of a CodeId
+	pub type KontractId<T> = <T as Config>::CodeId;
 	pub type KontractStorage<T> = BoundedBTreeMap<
 		BoundedVec<u8, <T as Config>::MaxKontracStorageKeySize>,
 		BoundedVec<u8, <T as Config>::MaxKontracStorageValueSize>,
-		<T as Config>::MaxKontracStorageSize,
+		<T as Config>::MaxStorageEntries,
 	>;
 
 	#[pallet::storage]
 	pub type Codes<T> =
 		StorageMap<_, Identity, <T as Config>::CodeId, BoundedVec<u8, <T as Config>::MaxCodeSize>>;
 
+	/// Informations about who uploaded a code, how much he deposited for it
+	/// and how many kontracts are using it
+	#[derive(Encode, Decode, Clone, PartialEq, Eq, RuntimeDebug, MaxEncodedLen, TypeInfo)]
+	#[scale_info(skip_type_params(T))]
+	pub struct CodeInfo<T: Config> {
+		pub owner: AccountId<T>,
+		pub deposit: BalanceOf<T>,
+		pub refcount: u32,
+		/// Version of the host functions imported by the code
+		pub abi_version: AbiVersion,
+		/// `Metering` of the `Config` when the code was uploaded
+		pub metering: Metering,
+	}
+
+	#[pallet::storage]
+	pub type CodeInfoOf<T> = StorageMap<_, Identity, <T as Config>::CodeId, CodeInfo<T>>;
+
+	/// An instance of a code, with its own storage
+	#[derive(Encode, Decode, Clone, PartialEq, Eq, RuntimeDebug, MaxEncodedLen, TypeInfo)]
+	#[scale_info(skip_type_params(T))]
+	pub struct KontractInfo<T: Config> {
+		pub code_id: T::CodeId,
+		pub deployer: AccountId<T>,
+		/// The constructor of the kontract has been successfully executed,
+		/// until then the kontract can't be executed
+		pub initialized: bool,
+	}
+
+	#[pallet::storage]
+	pub type Kontracts<T> = StorageMap<_, Identity, KontractId<T>, KontractInfo<T>>;
+
+	/// Resources given to an entry point that is not executed by `execute_code`,
+	/// like the `migrate` during an upgrade or the `deploy` during the instantiation
+	#[derive(Encode, Decode, Clone, Copy, PartialEq, Eq, RuntimeDebug, TypeInfo)]
+	pub struct ExecutionLimits {
+		pub fuel: u32,
+		pub max_read: u32,
+		pub max_write: u32,
+	}
+
+	/// Something that a kontract can use during the execution, it has to be declared
+	/// by the caller so the pallet knows what to load
+	#[derive(Encode, Decode, Clone, PartialEq, Eq, RuntimeDebugNoBound, MaxEncodedLen, TypeInfo)]
+	#[scale_info(skip_type_params(T))]
+	pub enum DeclaredDependency<T: Config> {
+		/// Another kontract, that can be called or read
+		Kontract(KontractId<T>),
+		/// An uploaded code, that can be executed over the storage of the caller
+		Library(T::CodeId),
+	}
+
 	#[pallet::storage]
 	pub type Storages<T> =
-		StorageMap<_, Identity, <T as Config>::CodeId, KontractStorage<T>, ValueQuery>;
+		StorageMap<_, Identity, KontractId<T>, KontractStorage<T>, ValueQuery>;
+
+	/// Bytes and entries of the storage of every kontract, updated after every execution
+	#[pallet::storage]
+	pub type StorageUsageOf<T> = StorageMap<_, Identity, KontractId<T>, StorageUsage, ValueQuery>;
+
+	/// Amount reserved by every account for the storage used by a kontract
+	#[pallet::storage]
+	pub type StorageDeposits<T> = StorageDoubleMap<
+		_,
+		Identity,
+		KontractId<T>,
+		Blake2_128Concat,
+		AccountId<T>,
+		BalanceOf<T>,
+		ValueQuery,
+	>;
+
+	/// Deleted kontracts whose storage deposits still need to be released,
+	/// the queue is consumed in `on_idle`
+	#[pallet::storage]
+	pub type PendingDeletion<T> = StorageValue<
+		_,
+		BoundedVec<KontractId<T>, <T as Config>::MaxDeletionQueue>,
+		ValueQuery,
+	>;
+
+	/// Prices and limits of the executions, kept on chain so they can be tuned by the root
+	/// without a runtime upgrade
+	#[derive(Encode, Decode, Clone, PartialEq, Eq, RuntimeDebugNoBound, MaxEncodedLen, TypeInfo)]
+	#[scale_info(skip_type_params(T))]
+	pub struct CostSchedule<T: Config> {
+		/// Weight of a unit of fuel
+		pub fuel_weight: u64,
+		/// Weight charged by every extrinsic that uploads or executes code
+		pub base_weight: u64,
+		/// Weight of every Byte of uploaded code
+		pub code_byte_weight: u64,
+		/// Weight of every Byte of the values read and written by the kontracts
+		pub read_byte_weight: u64,
+		pub write_byte_weight: u64,
+		/// Fuel charged by the host functions
+		pub host_fn_costs: HostFnCosts,
+		/// Fuel of the wasm instructions with `Metering::Instrumented`, injected in the code
+		/// when uploaded so the codes already uploaded keep their costs
+		pub instruction_costs: InstructionCosts,
+		/// Deposit reserved for every Byte of code
+		pub code_deposit_per_byte: BalanceOf<T>,
+		/// Deposit reserved for every Byte of storage used by a kontract
+		pub storage_deposit_per_byte: BalanceOf<T>,
+		/// Limits of the storage of the kontracts, at most the bounds of the `Config`
+		pub storage_limits: StorageLimits,
+	}
+
+	/// The values used before the first `set_schedule`, the weights are the ones
+	/// the pallet always used, still not benchmarked
+	#[pallet::type_value]
+	pub fn DefaultSchedule<T: Config>() -> CostSchedule<T> {
+		CostSchedule {
+			fuel_weight: 100,
+			base_weight: 10_000,
+			code_byte_weight: 155,
+			read_byte_weight: 5,
+			write_byte_weight: 10,
+			host_fn_costs: T::HostFnCosts::get(),
+			// One for every instruction, like the fuel of wasmtime
+			instruction_costs: InstructionCosts {
+				regular: 1,
+				load: 1,
+				store: 1,
+				branch: 1,
+				call: 1,
+				call_indirect: 1,
+				div: 1,
+				memory_grow: 0,
+			},
+			code_deposit_per_byte: T::CodeDepositPerByte::get(),
+			storage_deposit_per_byte: 1u32.into(),
+			storage_limits: StorageLimits {
+				max_key_size: T::MaxKontracStorageKeySize::get(),
+				max_value_size: T::MaxKontracStorageValueSize::get(),
+				max_entries: T::MaxStorageEntries::get(),
+				max_bytes: T::MaxStorageBytes::get(),
+			},
+		}
+	}
+
+	#[pallet::storage]
+	pub type Schedule<T> = StorageValue<_, CostSchedule<T>, ValueQuery, DefaultSchedule<T>>;
+
+	/// `KeyHashing` of the keys already stored, set by the first successful execution
+	#[pallet::storage]
+	pub type KeyHashingInUse<T> = StorageValue<_, KeyHashing, OptionQuery>;
 
 	#[pallet::event]
 	#[pallet::generate_deposit(pub(super) fn deposit_event)]
@@ -90,23 +353,47 @@ pub mod pallet {
 		/// New code has been uploaded
 		NewCodeUploaded { code_id: T::CodeId, who: AccountId<T> },
 
-		/// Code has been executed
-		CodeExecuted { code_id: T::CodeId, who: AccountId<T> },
+		/// New kontract created from an uploaded code
+		KontractInstantiated { kontract_id: KontractId<T>, code_id: T::CodeId, who: AccountId<T> },
+
+		/// The constructor of the kontract succeeded (or was not defined),
+		/// the kontract can now be executed
+		KontractInitialized { kontract_id: KontractId<T> },
+
+		/// The constructor of the kontract failed, it can be executed again with `initialize`
+		ConstructorFailed { kontract_id: KontractId<T>, error: DispatchError },
+
+		/// Kontract has been executed
+		KontractExecuted { kontract_id: KontractId<T>, who: AccountId<T> },
 
 		/// Code deleted by the root
 		CodeDeleted { code_id: T::CodeId },
 
-		/// Code upgrated by the root
-		CodeUpgraded { old_code_id: T::CodeId, new_code_id: T::CodeId },
+		/// Kontract deleted by the root
+		KontractDeleted { kontract_id: KontractId<T> },
+
+		/// Code of a kontract upgrated by the root
+		CodeUpgraded { kontract_id: KontractId<T>, old_code_id: T::CodeId, new_code_id: T::CodeId },
+
+		/// The storage of an upgraded kontract has been migrated by its `migrate` entry point
+		StorageMigrated { kontract_id: KontractId<T> },
 
 		/// Exceeded expected storage use
-		ExceededStorage { code_id: T::CodeId, who: AccountId<T> },
+		ExceededStorage { kontract_id: KontractId<T>, who: AccountId<T> },
+
+		/// Every storage deposit of a deleted kontract has been released
+		StorageDepositsReleased { kontract_id: KontractId<T> },
+
+		/// The root changed the `Schedule`
+		ScheduleUpdated,
 	}
 
 	#[pallet::error]
 	pub enum Error<T> {
 		/// Invalid CodeId
 		InvalidCodeId,
+		/// Invalid KontractId
+		InvalidKontractId,
 		/// ExectionCodeError
 		ExecutionCode(ExecutionErrors),
 		/// Exceeded expected Fuel
@@ -115,13 +402,101 @@ pub mod pallet {
 		WrongStorageEncoding,
 		/// Impossible accomplish a proper deposit for the storage usage
 		DepositError,
+		/// The same code is already on chain
+		CodeAlreadyUploaded,
+		/// The kontract has been deleted and its deposits are not yet released
+		KontractPendingDeletion,
+		/// Too many deleted kontracts are waiting for their deposits to be released
+		DeletionQueueFull,
+		/// A kontract with the same code, deployer and salt already exists
+		KontractAlreadyExists,
+		/// The code is still used by some kontracts
+		CodeInUse,
+		/// The constructor of the kontract has not been successfully executed yet
+		KontractNotInitialized,
+		/// The constructor of the kontract has been already executed
+		KontractAlreadyInitialized,
+		/// Only the deployer of the kontract can do this
+		NotKontractDeployer,
+		/// The kontract requested a transfer to something that is not an account
+		InvalidTransferDestination,
+		/// A transfer requested by the kontract can't be applied
+		TransferFailed,
+		/// The kontract requested to dispatch something that is not a call
+		InvalidRuntimeCall,
+		/// The call requested by the kontract is not accepted by the `CallFilter`
+		CallFiltered,
+		/// The calls requested by the kontract exceeded the `MaxDispatchWeight`
+		DispatchWeightExceeded,
+		/// Only `execute_code` can dispatch calls
+		DispatchNotAllowed,
+		/// The kontract called more extensions than `MaxExtensionCalls`
+		TooManyExtensionCalls,
+		/// The kontract wrote a key bigger than `MaxKontracStorageKeySize`
+		KeyTooLarge,
+		/// The kontract wrote a value bigger than `MaxKontracStorageValueSize`
+		ValueTooLarge,
+		/// The kontract wrote more than `MaxStorageEntries` or `MaxStorageBytes`
+		StorageFull,
+		/// The storage limits of the schedule are bigger than the bounds of the `Config`
+		InvalidSchedule,
+		/// The gas metering can't be injected in the code, it has to be a wasm binary
+		InvalidCode,
+		/// The code with the gas metering is bigger than `MaxCodeSize`
+		CodeTooLarge,
+		/// The code was uploaded before the code deposits, it has no owner and no refcount
+		MissingCodeInfo,
+		/// The `KeyHashing` of the runtime is not the one of the keys already stored
+		KeyHashingChanged,
+		/// A declared dependency was uploaded with a `Metering` different from the one
+		/// of the executed code
+		MeteringMismatch,
+		/// The code is metered by wasmtime (`Metering::Engine`, like the codes uploaded
+		/// before the metering), the `Wasmi` backend can't execute it
+		UnsupportedMetering,
+	}
+
+	#[pallet::hooks]
+	impl<T: Config> Hooks<BlockNumberFor<T>> for Pallet<T> {
+		fn on_idle(_n: BlockNumberFor<T>, remaining_weight: Weight) -> Weight {
+			Self::process_deletion_queue(remaining_weight)
+		}
+
+		fn on_runtime_upgrade() -> Weight {
+			crate::migration::migrate::<T>()
+		}
+
+		#[cfg(feature = "try-runtime")]
+		fn pre_upgrade() -> Result<Vec<u8>, &'static str> {
+			// The migrations are not split across blocks, they must fit in the block of the upgrade
+			ensure!(
+				crate::migration::pending_weight::<T>()
+					.all_lte(<T as frame_system::Config>::BlockWeights::get().max_block),
+				"The migrations of the kontracts don't fit in a block"
+			);
+			Ok(Vec::new())
+		}
+
+		fn integrity_test() {
+			// With `Metering::Engine` the fuel is counted by wasmtime, wasmi would
+			// consume a different fuel for the same execution
+			assert!(
+				!(T::Backend::get() == Backend::Wasmi && T::Metering::get() == Metering::Engine),
+				"The Wasmi backend requires Metering::Instrumented"
+			);
+		}
 	}
 
 	#[pallet::call]
 	impl<T: Config> Pallet<T> {
 		#[pallet::call_index(0)]
-		// 155 is a random value, this could be evaluated througth benchmarking
-		#[pallet::weight(10_000 + T::DbWeight::get().writes(1).ref_time() + 155 * code.len() as u64)]
+		#[pallet::weight({
+			let schedule = <Schedule<T>>::get();
+			Pallet::<T>::base_weight(&schedule)
+				.saturating_add(T::DbWeight::get().writes(1))
+				.saturating_add(Pallet::<T>::code_weight(&schedule, code.len()))
+				.ref_time()
+		})]
 		pub fn upload_code(
 			origin: OriginFor<T>,
 			code: BoundedVec<u8, T::MaxCodeSize>,
@@ -131,47 +506,134 @@ pub mod pallet {
 			// TODO: some pre evaluation of the code I'm trying to save on chain
 
 			let code_id = T::HashingAlgorith::hash(&code[..]);
+			ensure!(!<Codes<T>>::contains_key(&code_id), <Error<T>>::CodeAlreadyUploaded);
+			let (code, abi_version, metering) = Self::prepare_code(code)?;
+
+			// The uploader pays for the space the code will occupy on chain,
+			// the deposit is given back when the code is deleted
+			let deposit = Self::code_deposit(code.len());
+			<T as Config>::Currency::reserve(&who, deposit)
+				.map_err(|_| <Error<T>>::DepositError)?;
+
 			<Codes<T>>::insert(code_id.clone(), code);
+			<CodeInfoOf<T>>::insert(
+				code_id.clone(),
+				CodeInfo { owner: who.clone(), deposit, refcount: 0, abi_version, metering },
+			);
 
 			Self::deposit_event(Event::NewCodeUploaded { code_id, who });
 			Ok(())
 		}
 
 		#[pallet::call_index(1)]
-		#[pallet::weight(
-            10_000 + 
-            T::DbWeight::get().reads_writes(*expected_read as u64, *expected_write as u64).ref_time() + 
-            100 * *fuel as u64
-            )]
+		#[pallet::weight(Pallet::<T>::execute_code_weight(
+			&<Schedule<T>>::get(),
+			*fuel,
+			*expected_read,
+			*expected_write,
+			*max_read_bytes,
+			*max_write_bytes,
+			dependencies.len() as u32,
+		).ref_time())]
 		pub fn execute_code(
 			origin: OriginFor<T>,
-			code_id: T::CodeId,
+			kontract_id: KontractId<T>,
 			expected_modified_storage: i32,
 			fuel: u32,
 			expected_read: u32,
 			expected_write: u32,
-		) -> DispatchResult {
+			max_read_bytes: Option<u32>,
+			max_write_bytes: Option<u32>,
+			value: BalanceOf<T>,
+			dependencies: BoundedVec<DeclaredDependency<T>, T::MaxDependencies>,
+		) -> DispatchResultWithPostInfo {
 			let who = ensure_signed(origin)?;
 
-			let get_storage_size = |storage_encoded: &Vec<u8>| -> i32 {
-				let mut ref_slice_vec = &storage_encoded[..];
-				let _compact_len: codec::Compact<u32> =
-					Decode::decode(&mut ref_slice_vec).expect("LLLLOOLLL");
-				ref_slice_vec.len() as i32
-			};
+			let schedule = <Schedule<T>>::get();
+			let max_weight = Self::execute_code_weight(
+				&schedule,
+				fuel,
+				expected_read,
+				expected_write,
+				max_read_bytes,
+				max_write_bytes,
+				dependencies.len() as u32,
+			);
+			let (max_read_bytes, max_write_bytes) = Self::bytes_limits(
+				&schedule,
+				expected_read,
+				expected_write,
+				max_read_bytes,
+				max_write_bytes,
+			);
 
-			let code = <Codes<T>>::get(code_id.clone()).ok_or(<Error<T>>::InvalidCodeId)?;
+			let kontract =
+				<Kontracts<T>>::get(kontract_id.clone()).ok_or(<Error<T>>::InvalidKontractId)?;
+			ensure!(kontract.initialized, <Error<T>>::KontractNotInitialized);
+			let (code, abi_version, metering) = Self::load_code(&kontract.code_id)?;
 
-			let old_storage_raw = <Storages<T>>::get(code_id.clone()).encode();
-			let old_storage_size = get_storage_size(&old_storage_raw);
+			let old_storage_raw = <Storages<T>>::get(kontract_id.clone()).encode();
+			let old_storage_size = Self::storage_size(&old_storage_raw);
+
+			// The value is moved to the kontract only if the execution is successful,
+			// but the kontract can already use it
+			let kontract_account = Self::kontract_account(&kontract_id);
+			let balance = <T as Config>::Currency::free_balance(&kontract_account)
+				.saturating_add(value)
+				.saturated_into();
+
+			let (declared_kontracts, libraries) =
+				Self::load_dependencies(&dependencies[..], metering)?;
 
 			// I can easily encode the Storage because the encoding of a BoundedVec and
 			// a vec is the same, I can so encode here from BoundedVec and than in the client
 			// decode as Vec
-			let new_storage_raw =
-				execute_code(code.to_vec(), old_storage_raw, fuel, expected_read, expected_write)
-					.map_err(|e| <Error<T>>::ExecutionCode(e))?;
-			let new_storage_size = get_storage_size(&new_storage_raw);
+			let (
+				ExecutionOutcome {
+					storage: new_storage_raw,
+					storage_usage,
+					transfers,
+					calls,
+					dependencies: called,
+					..
+				},
+				executions,
+			) = Self::execute_with_extensions(ExecutionParams {
+				code,
+				abi_version,
+				storage: old_storage_raw,
+				storage_usage: <StorageUsageOf<T>>::get(&kontract_id),
+				entry_point: EntryPoint::Main,
+				input: Vec::new(),
+				fuel,
+				max_read: expected_read,
+				max_write: expected_write,
+				max_read_bytes,
+				max_write_bytes,
+				balance,
+				kontract_id: kontract_id.encode(),
+				dependencies: declared_kontracts,
+				libraries,
+				max_call_depth: T::MaxCallDepth::get(),
+				allow_reentrancy: T::AllowReentrancy::get(),
+				key_hashing: Self::key_hashing()?,
+				storage_limits: schedule.storage_limits.clone(),
+				host_fn_costs: schedule.host_fn_costs.clone(),
+				metering,
+				extension_outputs: Vec::new(),
+			})?;
+
+			// The weight reserved for the dispatched calls and for the executions repeated
+			// because of the extensions is refunded if not used
+			let unused_executions =
+				T::MaxExtensionCalls::get().saturating_add(1).saturating_sub(executions);
+			let execution_weight = max_weight
+				.saturating_sub(T::MaxDispatchWeight::get())
+				.saturating_sub(
+					Self::fuel_weight(&schedule, fuel).saturating_mul(unused_executions as u64),
+				);
+
+			let new_storage_size = Self::storage_size(&new_storage_raw);
 
 			// Here I was doing the difference between two scale encoded vector but
 			// is not so easy.. the user should manage also the change of the compact
@@ -189,22 +651,21 @@ pub mod pallet {
 					// I don't like this syntax.... the problem is that this arm return something
 					// and if I want to return () than I need to add ';' but than also the {} are
 					// required
-					<T as Config>::Currency::unreserve(&who, ((x * -1) as u32).into());
+					Self::unreserve_storage_deposit(&kontract_id, &who, (x * -1) as u32);
 				},
 				// The kontract used x space, I have to reserve the same amount
 				x if x <= expected_modified_storage =>
-					<T as Config>::Currency::reserve(&who, (x as u32).into())
-						.map_err(|_| <Error<T>>::DepositError)?,
+					Self::reserve_storage_deposit(&kontract_id, &who, x as u32)?,
 				// The used space is more the expected, return Error and slash the account with the
 				// same amount of new storage not correctly decleared
 				x => {
-					<T as Config>::Currency::slash(&who, (x as u32).into());
+					<T as Config>::Currency::slash(&who, Self::storage_deposit(x as u32));
 					// lol, that's true, I can't return error if I want to slash someone...
 					// I will deposit an event of ExceededStorage and return Ok()
 					// otherwise the overlay will be not applayed, I think there is 100% a better
 					// solution
-					Self::deposit_event(Event::ExceededStorage { code_id, who });
-					return Ok(())
+					Self::deposit_event(Event::ExceededStorage { kontract_id, who });
+					return Ok(Some(execution_weight).into())
 				},
 			};
 
@@ -212,48 +673,843 @@ pub mod pallet {
 			// a contract
 			let storage: KontractStorage<T> = Decode::decode(&mut &new_storage_raw[..])
 				.map_err(|_| <Error<T>>::ExecutionCode(ExecutionErrors::UnexpectedBehavoiur))?;
-			<Storages<T>>::insert(code_id.clone(), storage);
+			<Storages<T>>::insert(kontract_id.clone(), storage);
+			<StorageUsageOf<T>>::insert(kontract_id.clone(), storage_usage);
+			<KeyHashingInUse<T>>::put(T::KeyHashing::get());
+
+			<T as Config>::Currency::transfer(
+				&who,
+				&kontract_account,
+				value,
+				ExistenceRequirement::AllowDeath,
+			)?;
+			Self::apply_transfers(&kontract_account, transfers)?;
+			let mut dispatch_weight = Weight::zero();
+			Self::dispatch_calls(&kontract_account, calls, &mut dispatch_weight)?;
 
-			Self::deposit_event(Event::CodeExecuted { code_id, who });
+			// The caller pays also for the storage used by the called kontracts
+			Self::apply_dependencies(&who, called, &mut dispatch_weight)?;
 
-            // TODO: DispatchResultWithPostInfo
-            // If the user specify more read and write than the reallity than
+			Self::deposit_event(Event::KontractExecuted { kontract_id, who });
+
+            // TODO: If the user specify more read and write than the reallity than
             // a refund should be made
 
-			Ok(())
+			Ok(Some(execution_weight.saturating_add(dispatch_weight)).into())
 		}
 
 		#[pallet::call_index(2)]
-        #[pallet::weight(0)]
+		#[pallet::weight(T::DbWeight::get().reads_writes(2, 4).ref_time())]
 		pub fn delete_code(
 			origin: OriginFor<T>,
 			code_id: T::CodeId,
 		) -> DispatchResult {
 			ensure_root(origin)?;
 
+			ensure!(<Codes<T>>::contains_key(&code_id), <Error<T>>::InvalidCodeId);
+			let CodeInfo { owner, deposit, refcount, .. } =
+				<CodeInfoOf<T>>::get(&code_id).ok_or(<Error<T>>::MissingCodeInfo)?;
+			ensure!(refcount == 0, <Error<T>>::CodeInUse);
+			<T as Config>::Currency::unreserve(&owner, deposit);
+
 			<Codes<T>>::remove(code_id.clone());
+			<CodeInfoOf<T>>::remove(code_id.clone());
 
 			Self::deposit_event(Event::CodeDeleted { code_id });
 			Ok(())
 		}
 
 		#[pallet::call_index(3)]
-		#[pallet::weight(10_000 + T::DbWeight::get().writes(1).ref_time() + 155 * code.len() as u64)]
+		#[pallet::weight({
+			let schedule = <Schedule<T>>::get();
+			Pallet::<T>::base_weight(&schedule)
+				.saturating_add(T::DbWeight::get().reads_writes(3, 4))
+				.saturating_add(Pallet::<T>::code_weight(&schedule, code.len()))
+				.saturating_add(migrate.as_ref().map_or(Weight::zero(), |limits| {
+					Pallet::<T>::limits_weight(&schedule, limits)
+				}))
+				.ref_time()
+		})]
 		pub fn update_code(
 			origin: OriginFor<T>,
-			old_code_id: T::CodeId,
+			kontract_id: KontractId<T>,
 			code: BoundedVec<u8, T::MaxCodeSize>,
+			migrate: Option<ExecutionLimits>,
 		) -> DispatchResult {
-            // TODO: ensure root
 			ensure_root(origin)?;
 
-			<Codes<T>>::remove(old_code_id.clone());
-
+			let mut kontract =
+				<Kontracts<T>>::get(kontract_id.clone()).ok_or(<Error<T>>::InvalidKontractId)?;
+			let old_code_id = kontract.code_id.clone();
 			let new_code_id = T::HashingAlgorith::hash(&code[..]);
-			<Codes<T>>::insert(new_code_id.clone(), code);
+			let (code, abi_version, metering) = Self::prepare_code(code)?;
+
+			if new_code_id != old_code_id {
+				let old_code_info =
+					<CodeInfoOf<T>>::get(&old_code_id).ok_or(<Error<T>>::MissingCodeInfo)?;
+				let owner = old_code_info.owner.clone();
+
+				// If this kontract was the last one using the old code than the old code
+				// is replaced by the new one, otherwise the old code stays for the other kontracts
+				let released_deposit = if old_code_info.refcount <= 1 {
+					<Codes<T>>::remove(&old_code_id);
+					<CodeInfoOf<T>>::remove(&old_code_id);
+					old_code_info.deposit
+				} else {
+					<CodeInfoOf<T>>::insert(
+						&old_code_id,
+						CodeInfo { refcount: old_code_info.refcount - 1, ..old_code_info },
+					);
+					Zero::zero()
+				};
+
+				let required_deposit = match <CodeInfoOf<T>>::get(&new_code_id) {
+					Some(info) => {
+						<CodeInfoOf<T>>::insert(
+							&new_code_id,
+							CodeInfo { refcount: info.refcount.saturating_add(1), ..info },
+						);
+						Zero::zero()
+					},
+					None => {
+						let deposit = Self::code_deposit(code.len());
+						<Codes<T>>::insert(&new_code_id, code.clone());
+						<CodeInfoOf<T>>::insert(
+							&new_code_id,
+							CodeInfo {
+								owner: owner.clone(),
+								deposit,
+								refcount: 1,
+								abi_version,
+								metering,
+							},
+						);
+						deposit
+					},
+				};
+
+				// The owner of the old code keeps the ownership of the new one,
+				// only the difference between the two deposits is moved
+				if required_deposit > released_deposit {
+					<T as Config>::Currency::reserve(&owner, required_deposit - released_deposit)
+						.map_err(|_| <Error<T>>::DepositError)?;
+				} else {
+					<T as Config>::Currency::unreserve(&owner, released_deposit - required_deposit);
+				}
+
+				kontract.code_id = new_code_id.clone();
+				<Kontracts<T>>::insert(&kontract_id, kontract.clone());
+			}
 
-			Self::deposit_event(Event::CodeUpgraded { old_code_id, new_code_id });
+			// The storage lives under the address of the kontract, so it is preserved
+			// by the upgrade, the new code can optionally adapt it with the `migrate` entry point.
+			// The code is the stored one, if it was already uploaded it keeps its own bytes,
+			// version and metering
+			if let Some(limits) = migrate {
+				Self::execute_entry_point(
+					&kontract_id,
+					&kontract.deployer,
+					Self::load_code(&new_code_id)?,
+					EntryPoint::Migrate,
+					Vec::new(),
+					limits,
+				)?;
+			}
+
+			Self::deposit_event(Event::CodeUpgraded {
+				kontract_id: kontract_id.clone(),
+				old_code_id,
+				new_code_id,
+			});
+			if migrate.is_some() {
+				Self::deposit_event(Event::StorageMigrated { kontract_id });
+			}
 			Ok(())
 		}
+
+		#[pallet::call_index(4)]
+		#[pallet::weight({
+			let schedule = <Schedule<T>>::get();
+			Pallet::<T>::base_weight(&schedule)
+				.saturating_add(T::DbWeight::get().reads_writes(4, 3))
+				.saturating_add(Pallet::<T>::limits_weight(&schedule, limits))
+				.saturating_add(Pallet::<T>::bytes_weight(&schedule, (input.len() as u32, 0)))
+				.ref_time()
+		})]
+		pub fn instantiate(
+			origin: OriginFor<T>,
+			code_id: T::CodeId,
+			salt: BoundedVec<u8, T::MaxSaltLen>,
+			input: BoundedVec<u8, T::MaxInputLen>,
+			limits: ExecutionLimits,
+		) -> DispatchResult {
+			let who = ensure_signed(origin)?;
+
+			let kontract_id = Self::kontract_id(&code_id, &who, &salt);
+			ensure!(!<Kontracts<T>>::contains_key(&kontract_id), <Error<T>>::KontractAlreadyExists);
+			// The deposits of the old kontract under the same address are not yet released
+			ensure!(
+				!<PendingDeletion<T>>::get().contains(&kontract_id),
+				<Error<T>>::KontractPendingDeletion
+			);
+
+			<CodeInfoOf<T>>::try_mutate(&code_id, |info| match info {
+				Some(info) => {
+					info.refcount = info.refcount.saturating_add(1);
+					Ok(())
+				},
+				None => Err(<Error<T>>::InvalidCodeId),
+			})?;
+
+			let kontract =
+				KontractInfo { code_id: code_id.clone(), deployer: who.clone(), initialized: false };
+			<Kontracts<T>>::insert(&kontract_id, kontract.clone());
+
+			Self::deposit_event(Event::KontractInstantiated {
+				kontract_id: kontract_id.clone(),
+				code_id,
+				who,
+			});
+
+			// A failing constructor does not revert the instantiation, the deployer paid
+			// for the kontract that stays not executable until `initialize` succeeds.
+			// What the constructor did (storage, deposit and transfers) is reverted
+			let input = input.into_inner();
+			let result = with_transaction(|| {
+				match Self::initialize_kontract(&kontract_id, kontract, input, limits) {
+					Ok(()) => TransactionOutcome::Commit(Ok(())),
+					Err(error) => TransactionOutcome::Rollback(Err(error)),
+				}
+			});
+			if let Err(error) = result {
+				Self::deposit_event(Event::ConstructorFailed { kontract_id, error });
+			}
+			Ok(())
+		}
+
+		#[pallet::call_index(6)]
+		#[pallet::weight({
+			let schedule = <Schedule<T>>::get();
+			Pallet::<T>::base_weight(&schedule)
+				.saturating_add(T::DbWeight::get().reads_writes(4, 2))
+				.saturating_add(Pallet::<T>::limits_weight(&schedule, limits))
+				.saturating_add(Pallet::<T>::bytes_weight(&schedule, (input.len() as u32, 0)))
+				.ref_time()
+		})]
+		pub fn initialize(
+			origin: OriginFor<T>,
+			kontract_id: KontractId<T>,
+			input: BoundedVec<u8, T::MaxInputLen>,
+			limits: ExecutionLimits,
+		) -> DispatchResult {
+			let who = ensure_signed(origin)?;
+
+			let kontract =
+				<Kontracts<T>>::get(&kontract_id).ok_or(<Error<T>>::InvalidKontractId)?;
+			ensure!(kontract.deployer == who, <Error<T>>::NotKontractDeployer);
+			ensure!(!kontract.initialized, <Error<T>>::KontractAlreadyInitialized);
+
+			Self::initialize_kontract(&kontract_id, kontract, input.into_inner(), limits)
+		}
+
+		#[pallet::call_index(5)]
+		#[pallet::weight(T::DbWeight::get().reads_writes(3, 4).ref_time())]
+		pub fn delete_kontract(origin: OriginFor<T>, kontract_id: KontractId<T>) -> DispatchResult {
+			ensure_root(origin)?;
+
+			let kontract =
+				<Kontracts<T>>::take(&kontract_id).ok_or(<Error<T>>::InvalidKontractId)?;
+
+			// The storage deposits could be spread over a lot of accounts, those are released
+			// lazily in `on_idle`, meanwhile the same address can't be instantiated again
+			<PendingDeletion<T>>::try_mutate(|queue| queue.try_push(kontract_id.clone()))
+				.map_err(|_| <Error<T>>::DeletionQueueFull)?;
+
+			<Storages<T>>::remove(&kontract_id);
+			<StorageUsageOf<T>>::remove(&kontract_id);
+
+			<CodeInfoOf<T>>::mutate(&kontract.code_id, |info| {
+				if let Some(info) = info {
+					info.refcount = info.refcount.saturating_sub(1);
+				}
+			});
+
+			Self::deposit_event(Event::KontractDeleted { kontract_id });
+			Ok(())
+		}
+
+		#[pallet::call_index(7)]
+		#[pallet::weight(T::DbWeight::get().writes(1).ref_time())]
+		pub fn set_schedule(origin: OriginFor<T>, schedule: CostSchedule<T>) -> DispatchResult {
+			ensure_root(origin)?;
+
+			// The storage of the kontracts is bounded by the `Config`, bigger limits
+			// would make the executions fail while storing the result
+			let limits = schedule.storage_limits;
+			ensure!(
+				limits.max_key_size <= T::MaxKontracStorageKeySize::get() &&
+					limits.max_value_size <= T::MaxKontracStorageValueSize::get() &&
+					limits.max_entries <= T::MaxStorageEntries::get() &&
+					limits.max_bytes <= T::MaxStorageBytes::get(),
+				<Error<T>>::InvalidSchedule
+			);
+
+			<Schedule<T>>::put(schedule);
+
+			Self::deposit_event(Event::ScheduleUpdated);
+			Ok(())
+		}
+	}
+
+	impl<T: Config> Pallet<T> {
+		/// The code as it will be stored with the `Metering` of the `Config`, the id is always
+		/// the hash of the uploaded code
+		fn prepare_code(
+			code: BoundedVec<u8, T::MaxCodeSize>,
+		) -> Result<(BoundedVec<u8, T::MaxCodeSize>, AbiVersion, Metering), Error<T>> {
+			let metering = T::Metering::get();
+			let (code, abi_version) = T::Executor::validate(
+				code.into_inner(),
+				metering,
+				&<Schedule<T>>::get().instruction_costs,
+			)
+			.map_err(|_| <Error<T>>::InvalidCode)?;
+			let code = code.try_into().map_err(|_| <Error<T>>::CodeTooLarge)?;
+			Ok((code, abi_version, metering))
+		}
+
+		/// The code, the version of the host functions it imports and its metering, recorded
+		/// at the upload. The codes uploaded before the code deposits have no `CodeInfo`,
+		/// they are `V0` metered by wasmtime. Only the codes the backend can execute
+		fn load_code(code_id: &T::CodeId) -> Result<(Vec<u8>, AbiVersion, Metering), Error<T>> {
+			let code = <Codes<T>>::get(code_id).ok_or(<Error<T>>::InvalidCodeId)?;
+			let (abi_version, metering) = <CodeInfoOf<T>>::get(code_id)
+				.map_or((AbiVersion::V0, Metering::Engine), |info| {
+					(info.abi_version, info.metering)
+				});
+			// Uploaded before a switch to `Wasmi`, the fuel of wasmi would be different
+			ensure!(
+				!(T::Backend::get() == Backend::Wasmi && metering == Metering::Engine),
+				<Error<T>>::UnsupportedMetering
+			);
+			Ok((code.to_vec(), abi_version, metering))
+		}
+
+		/// Deposit required to keep on chain a code of `code_len` Bytes
+		pub fn code_deposit(code_len: usize) -> BalanceOf<T> {
+			<Schedule<T>>::get()
+				.code_deposit_per_byte
+				.saturating_mul((code_len as u32).into())
+		}
+
+		/// Address of the kontract instantiated by `deployer` from `code_id` with `salt`
+		pub fn kontract_id(
+			code_id: &T::CodeId,
+			deployer: &AccountId<T>,
+			salt: &[u8],
+		) -> KontractId<T> {
+			T::HashingAlgorith::hash(&(b"kontract", code_id, deployer, salt).encode())
+		}
+
+		/// Entries of the storage of a kontract with the keys used by the kontract,
+		/// `None` if the key can't be recovered with the current `KeyHashing`
+		pub fn storage_entries(kontract_id: &KontractId<T>) -> Vec<(Option<Vec<u8>>, Vec<u8>)> {
+			let key_hashing = <KeyHashingInUse<T>>::get().unwrap_or_else(T::KeyHashing::get);
+			<Storages<T>>::get(kontract_id)
+				.into_iter()
+				.map(|(key, value)| {
+					(key_hashing.raw_key(&key[..]).map(|key| key.to_vec()), value.to_vec())
+				})
+				.collect()
+		}
+
+		/// Execute the constructor of the kontract, if defined, and mark the kontract as
+		/// initialized
+		fn initialize_kontract(
+			kontract_id: &KontractId<T>,
+			mut kontract: KontractInfo<T>,
+			input: Vec<u8>,
+			limits: ExecutionLimits,
+		) -> DispatchResult {
+			let code = Self::load_code(&kontract.code_id)?;
+
+			let result = Self::execute_entry_point(
+				kontract_id,
+				&kontract.deployer,
+				code,
+				EntryPoint::Deploy,
+				input,
+				limits,
+			);
+
+			// The constructor is optional
+			let not_defined: DispatchError =
+				<Error<T>>::ExecutionCode(ExecutionErrors::EntryPointNotDefined).into();
+			match result {
+				Err(e) if e == not_defined => (),
+				res => res?,
+			};
+
+			kontract.initialized = true;
+			<Kontracts<T>>::insert(kontract_id, kontract);
+
+			Self::deposit_event(Event::KontractInitialized { kontract_id: kontract_id.clone() });
+			Ok(())
+		}
+
+		/// Execute an entry point of the code over the storage of the kontract,
+		/// there is no user that declared the expected storage variation so `payer`
+		/// pays (or get refunded) for all the storage variation
+		fn execute_entry_point(
+			kontract_id: &KontractId<T>,
+			payer: &AccountId<T>,
+			(code, abi_version, metering): (Vec<u8>, AbiVersion, Metering),
+			entry_point: EntryPoint,
+			input: Vec<u8>,
+			ExecutionLimits { fuel, max_read, max_write }: ExecutionLimits,
+		) -> DispatchResult {
+			let old_storage_raw = <Storages<T>>::get(kontract_id).encode();
+			let old_storage_size = Self::storage_size(&old_storage_raw);
+
+			let kontract_account = Self::kontract_account(kontract_id);
+			let balance = <T as Config>::Currency::free_balance(&kontract_account).saturated_into();
+			let schedule = <Schedule<T>>::get();
+			let (max_read_bytes, max_write_bytes) =
+				Self::bytes_limits(&schedule, max_read, max_write, None, None);
+
+			// No dependency can be declared, so no other kontract can be called
+			let (
+				ExecutionOutcome {
+					storage: new_storage_raw, storage_usage, transfers, calls, ..
+				},
+				_,
+			) = Self::execute_with_extensions(ExecutionParams {
+				code,
+				abi_version,
+				storage: old_storage_raw,
+				storage_usage: <StorageUsageOf<T>>::get(kontract_id),
+				entry_point,
+				input,
+				fuel,
+				max_read,
+				max_write,
+				max_read_bytes,
+				max_write_bytes,
+				balance,
+				kontract_id: kontract_id.encode(),
+				dependencies: Vec::new(),
+				libraries: Vec::new(),
+				max_call_depth: T::MaxCallDepth::get(),
+				allow_reentrancy: T::AllowReentrancy::get(),
+				key_hashing: Self::key_hashing()?,
+				storage_limits: schedule.storage_limits.clone(),
+				host_fn_costs: schedule.host_fn_costs.clone(),
+				metering,
+				extension_outputs: Vec::new(),
+			})?;
+			ensure!(calls.is_empty(), <Error<T>>::DispatchNotAllowed);
+			let new_storage_size = Self::storage_size(&new_storage_raw);
+
+			// Decoded before the deposit, nothing is reserved for a storage that is not stored
+			let storage: KontractStorage<T> = Decode::decode(&mut &new_storage_raw[..])
+				.map_err(|_| <Error<T>>::ExecutionCode(ExecutionErrors::UnexpectedBehavoiur))?;
+			Self::settle_storage_deposit(kontract_id, payer, new_storage_size - old_storage_size)?;
+
+			<Storages<T>>::insert(kontract_id, storage);
+			<StorageUsageOf<T>>::insert(kontract_id, storage_usage);
+			<KeyHashingInUse<T>>::put(T::KeyHashing::get());
+
+			Self::apply_transfers(&kontract_account, transfers)
+		}
+
+		/// Execute the code, every time the kontract calls an extension not yet resolved
+		/// the execution is stopped, the request is serviced by `T::Extension` and the code
+		/// is executed again from the beginning with all the known outputs.
+		/// Returns also the number of executions
+		fn execute_with_extensions(
+			mut params: ExecutionParams,
+		) -> Result<(ExecutionOutcome, u32), DispatchError> {
+			let mut executions = 0;
+			loop {
+				executions += 1;
+				let outcome =
+					T::Executor::execute(params.clone()).map_err(Self::execution_error)?;
+
+				let ExtensionRequest { kontract_id, kind, input } =
+					match outcome.pending_extension {
+						Some(request) => request,
+						None => return Ok((outcome, executions)),
+					};
+				ensure!(
+					(params.extension_outputs.len() as u32) < T::MaxExtensionCalls::get(),
+					<Error<T>>::TooManyExtensionCalls
+				);
+
+				let kontract_id: KontractId<T> = Decode::decode(&mut &kontract_id[..])
+					.map_err(|_| <Error<T>>::ExecutionCode(ExecutionErrors::UnexpectedBehavoiur))?;
+				let output = match kind {
+					RequestKind::Extension(id) =>
+						T::Extension::call(&Self::kontract_account(&kontract_id), id, input)?,
+					// Hashed again so the kontract always gets `RANDOM_SIZE` bytes,
+					// whatever is the hash of the runtime
+					RequestKind::Random =>
+						T::Randomness::random(&input[..]).0.using_encoded(blake2_256).to_vec(),
+				};
+				params.extension_outputs.push(output);
+			}
+		}
+
+		/// The `KeyHashing` of the `Config`, if it is the one of the keys already stored,
+		/// otherwise the values already stored would be unreachable
+		fn key_hashing() -> Result<KeyHashing, Error<T>> {
+			let key_hashing = T::KeyHashing::get();
+			match <KeyHashingInUse<T>>::get() {
+				Some(in_use) if in_use != key_hashing => Err(<Error<T>>::KeyHashingChanged),
+				_ => Ok(key_hashing),
+			}
+		}
+
+		/// The errors about the storage limits have their own error, so the user
+		/// knows what to change
+		fn execution_error(error: ExecutionErrors) -> Error<T> {
+			match error {
+				ExecutionErrors::KeyTooLarge => <Error<T>>::KeyTooLarge,
+				ExecutionErrors::ValueTooLarge => <Error<T>>::ValueTooLarge,
+				ExecutionErrors::StorageFull => <Error<T>>::StorageFull,
+				error => <Error<T>>::ExecutionCode(error),
+			}
+		}
+
+		/// Load everything needed by the executor to use the declared dependencies, all
+		/// executed with the `metering` of the caller
+		fn load_dependencies(
+			dependencies: &[DeclaredDependency<T>],
+			metering: Metering,
+		) -> Result<(Vec<Dependency>, Vec<Library>), DispatchError> {
+			let mut kontracts = Vec::new();
+			let mut libraries = Vec::new();
+
+			for dependency in dependencies {
+				match dependency {
+					DeclaredDependency::Kontract(kontract_id) => {
+						let kontract = <Kontracts<T>>::get(kontract_id)
+							.ok_or(<Error<T>>::InvalidKontractId)?;
+						ensure!(kontract.initialized, <Error<T>>::KontractNotInitialized);
+						let (code, abi_version, code_metering) =
+							Self::load_code(&kontract.code_id)?;
+						ensure!(code_metering == metering, <Error<T>>::MeteringMismatch);
+						let kontract_account = Self::kontract_account(kontract_id);
+
+						kontracts.push(Dependency {
+							kontract_id: kontract_id.encode(),
+							code,
+							abi_version,
+							storage: <Storages<T>>::get(kontract_id).encode(),
+							storage_usage: <StorageUsageOf<T>>::get(kontract_id),
+							balance: <T as Config>::Currency::free_balance(&kontract_account)
+								.saturated_into(),
+						});
+					},
+					DeclaredDependency::Library(code_id) => {
+						let (code, abi_version, code_metering) = Self::load_code(code_id)?;
+						ensure!(code_metering == metering, <Error<T>>::MeteringMismatch);
+						libraries.push(Library { code_id: code_id.encode(), code, abi_version });
+					},
+				}
+			}
+
+			Ok((kontracts, libraries))
+		}
+
+		/// Store the new storage of every called kontract and apply its transfers,
+		/// `payer` pays (or get refunded) for all the storage variation
+		fn apply_dependencies(
+			payer: &AccountId<T>,
+			dependencies: Vec<DependencyOutcome>,
+			dispatch_weight: &mut Weight,
+		) -> DispatchResult {
+			for DependencyOutcome { kontract_id, storage, storage_usage, transfers, calls } in
+				dependencies
+			{
+				let kontract_id: KontractId<T> = Decode::decode(&mut &kontract_id[..])
+					.map_err(|_| <Error<T>>::ExecutionCode(ExecutionErrors::UnexpectedBehavoiur))?;
+
+				let old_storage_size =
+					Self::storage_size(&<Storages<T>>::get(&kontract_id).encode());
+				let new_storage_size = Self::storage_size(&storage);
+				Self::settle_storage_deposit(
+					&kontract_id,
+					payer,
+					new_storage_size - old_storage_size,
+				)?;
+
+				let storage: KontractStorage<T> = Decode::decode(&mut &storage[..])
+					.map_err(|_| <Error<T>>::ExecutionCode(ExecutionErrors::UnexpectedBehavoiur))?;
+				<Storages<T>>::insert(&kontract_id, storage);
+				<StorageUsageOf<T>>::insert(&kontract_id, storage_usage);
+
+				let kontract_account = Self::kontract_account(&kontract_id);
+				Self::apply_transfers(&kontract_account, transfers)?;
+				Self::dispatch_calls(&kontract_account, calls, dispatch_weight)?;
+			}
+			Ok(())
+		}
+
+		/// Decode, filter and dispatch the calls requested by a kontract with its account
+		/// as origin, the weight of the calls is added to `dispatch_weight`
+		fn dispatch_calls(
+			kontract_account: &AccountId<T>,
+			calls: Vec<Vec<u8>>,
+			dispatch_weight: &mut Weight,
+		) -> DispatchResult {
+			for call in calls {
+				let call = <T as Config>::RuntimeCall::decode_with_depth_limit(
+					MAX_CALL_DECODE_DEPTH,
+					&mut &call[..],
+				)
+				.map_err(|_| <Error<T>>::InvalidRuntimeCall)?;
+				ensure!(T::CallFilter::contains(&call), <Error<T>>::CallFiltered);
+
+				let info = call.get_dispatch_info();
+				ensure!(
+					!dispatch_weight
+						.saturating_add(info.weight)
+						.any_gt(T::MaxDispatchWeight::get()),
+					<Error<T>>::DispatchWeightExceeded
+				);
+
+				let origin = frame_system::RawOrigin::Signed(kontract_account.clone()).into();
+				let result = call.dispatch(origin);
+				*dispatch_weight =
+					dispatch_weight.saturating_add(extract_actual_weight(&result, &info));
+				result.map_err(|e| e.error)?;
+			}
+			Ok(())
+		}
+
+		/// Weight of `execute_code`, including the max weight of the dispatched calls
+		/// and of the executions repeated because of the extensions
+		pub fn execute_code_weight(
+			schedule: &CostSchedule<T>,
+			fuel: u32,
+			expected_read: u32,
+			expected_write: u32,
+			max_read_bytes: Option<u32>,
+			max_write_bytes: Option<u32>,
+			n_dependencies: u32,
+		) -> Weight {
+			let db_weight = T::DbWeight::get();
+			let max_executions = T::MaxExtensionCalls::get().saturating_add(1);
+			// `KeyHashingInUse` is read and written, `CodeInfoOf` of the code is read
+			Self::base_weight(schedule)
+				.saturating_add(db_weight.reads_writes(2, 1))
+				.saturating_add(
+					Self::fuel_weight(schedule, fuel).saturating_mul(max_executions as u64),
+				)
+				.saturating_add(db_weight.reads_writes(expected_read as u64, expected_write as u64))
+				.saturating_add(Self::bytes_weight(
+					schedule,
+					Self::bytes_limits(
+						schedule,
+						expected_read,
+						expected_write,
+						max_read_bytes,
+						max_write_bytes,
+					),
+				))
+				.saturating_add(
+					db_weight.reads_writes(5 * n_dependencies as u64, 2 * n_dependencies as u64),
+				)
+				.saturating_add(T::MaxDispatchWeight::get())
+		}
+
+		/// Bytes of the values that can be read and written, without an explicit limit
+		/// every read and write can move a value of the max size
+		pub fn bytes_limits(
+			schedule: &CostSchedule<T>,
+			expected_read: u32,
+			expected_write: u32,
+			max_read_bytes: Option<u32>,
+			max_write_bytes: Option<u32>,
+		) -> (u32, u32) {
+			let max_value_size = schedule.storage_limits.max_value_size;
+			(
+				max_read_bytes.unwrap_or(expected_read.saturating_mul(max_value_size)),
+				max_write_bytes.unwrap_or(expected_write.saturating_mul(max_value_size)),
+			)
+		}
+
+		/// Weight of the bytes moved by the storage host functions, charged on top
+		/// of the weight of every read and write
+		pub fn bytes_weight(
+			schedule: &CostSchedule<T>,
+			(read_bytes, write_bytes): (u32, u32),
+		) -> Weight {
+			Weight::from_parts(
+				schedule
+					.read_byte_weight
+					.saturating_mul(read_bytes as u64)
+					.saturating_add(schedule.write_byte_weight.saturating_mul(write_bytes as u64)),
+				0,
+			)
+		}
+
+		/// Weight of a single execution with `fuel`
+		pub fn fuel_weight(schedule: &CostSchedule<T>, fuel: u32) -> Weight {
+			Weight::from_parts(schedule.fuel_weight.saturating_mul(fuel as u64), 0)
+		}
+
+		/// Weight charged by every extrinsic that uploads or executes code, the read
+		/// of the `Schedule` included
+		pub fn base_weight(schedule: &CostSchedule<T>) -> Weight {
+			Weight::from_parts(schedule.base_weight, 0).saturating_add(T::DbWeight::get().reads(1))
+		}
+
+		/// Weight of storing a code of `code_len` Bytes
+		pub fn code_weight(schedule: &CostSchedule<T>, code_len: usize) -> Weight {
+			Weight::from_parts(schedule.code_byte_weight.saturating_mul(code_len as u64), 0)
+		}
+
+		/// Weight of an entry point executed with `limits`, without the dispatched calls
+		pub fn limits_weight(schedule: &CostSchedule<T>, limits: &ExecutionLimits) -> Weight {
+			// `KeyHashingInUse` included
+			T::DbWeight::get()
+				.reads_writes(limits.max_read as u64 + 1, limits.max_write as u64 + 1)
+				.saturating_add(Self::bytes_weight(
+					schedule,
+					Self::bytes_limits(schedule, limits.max_read, limits.max_write, None, None),
+				))
+				.saturating_add(Self::fuel_weight(schedule, limits.fuel))
+		}
+
+		/// Deposit required to keep `bytes` of storage on chain
+		fn storage_deposit(bytes: u32) -> BalanceOf<T> {
+			<Schedule<T>>::get().storage_deposit_per_byte.saturating_mul(bytes.into())
+		}
+
+		/// Reserve or unreserve the deposit of `payer` for a storage variation of `diff` Bytes
+		fn settle_storage_deposit(
+			kontract_id: &KontractId<T>,
+			payer: &AccountId<T>,
+			diff: i32,
+		) -> DispatchResult {
+			match diff {
+				x if x < 0 => {
+					Self::unreserve_storage_deposit(kontract_id, payer, (x * -1) as u32);
+				},
+				x => Self::reserve_storage_deposit(kontract_id, payer, x as u32)?,
+			};
+			Ok(())
+		}
+
+		/// Account that holds the balance of the kontract
+		pub fn kontract_account(kontract_id: &KontractId<T>) -> AccountId<T> {
+			T::PalletId::get().into_sub_account_truncating(kontract_id)
+		}
+
+		/// Move the balance of the kontract as requested during its execution
+		fn apply_transfers(
+			kontract_account: &AccountId<T>,
+			transfers: Vec<Transfer>,
+		) -> DispatchResult {
+			for Transfer { dest, amount } in transfers {
+				let dest: AccountId<T> = Decode::decode(&mut &dest[..])
+					.map_err(|_| <Error<T>>::InvalidTransferDestination)?;
+				<T as Config>::Currency::transfer(
+					kontract_account,
+					&dest,
+					amount.saturated_into(),
+					ExistenceRequirement::AllowDeath,
+				)
+				.map_err(|_| <Error<T>>::TransferFailed)?;
+			}
+			Ok(())
+		}
+
+		/// Reserve the deposit of `bytes` from `who` and record it in the storage deposits
+		/// of the kontract
+		fn reserve_storage_deposit(
+			kontract_id: &KontractId<T>,
+			who: &AccountId<T>,
+			bytes: u32,
+		) -> DispatchResult {
+			let amount = Self::storage_deposit(bytes);
+			<T as Config>::Currency::reserve(who, amount).map_err(|_| <Error<T>>::DepositError)?;
+			<StorageDeposits<T>>::mutate(kontract_id, who, |deposit| {
+				*deposit = deposit.saturating_add(amount)
+			});
+			Ok(())
+		}
+
+		/// Unreserve the deposit of `bytes` to `who` and remove it from the storage deposits
+		/// of the kontract
+		fn unreserve_storage_deposit(kontract_id: &KontractId<T>, who: &AccountId<T>, bytes: u32) {
+			<StorageDeposits<T>>::mutate_exists(kontract_id, who, |deposit| {
+				// `who` could free storage paid by another account (or at an older price),
+				// never release more than what `who` deposited for this kontract
+				let amount = Self::storage_deposit(bytes).min(deposit.unwrap_or_default());
+				<T as Config>::Currency::unreserve(who, amount);
+				*deposit = deposit.map(|d| d.saturating_sub(amount)).filter(|d| !d.is_zero())
+			});
+		}
+
+		/// Release the storage deposits of the deleted kontracts until `remaining_weight`
+		/// is consumed, returns the used weight
+		fn process_deletion_queue(remaining_weight: Weight) -> Weight {
+			let db_weight = T::DbWeight::get();
+			// Read and write of the queue
+			let mut used_weight = db_weight.reads_writes(1, 1);
+			// Read and remove the ledger entry plus unreserve the balance of the depositor
+			let weight_per_deposit = db_weight.reads_writes(2, 2);
+
+			if remaining_weight.any_lt(used_weight) {
+				return Weight::zero()
+			}
+
+			let mut queue = <PendingDeletion<T>>::get();
+			if queue.is_empty() {
+				return db_weight.reads(1)
+			}
+
+			while let Some(kontract_id) = queue.first().cloned() {
+				let mut deposits = <StorageDeposits<T>>::drain_prefix(&kontract_id);
+				let mut completed = true;
+
+				loop {
+					// The weight is checked before `next` because the drain removes
+					// the entry as soon as it is returned
+					if remaining_weight.any_lt(used_weight.saturating_add(weight_per_deposit)) {
+						completed = false;
+						break
+					}
+					match deposits.next() {
+						Some((who, deposit)) => {
+							<T as Config>::Currency::unreserve(&who, deposit);
+							used_weight = used_weight.saturating_add(weight_per_deposit);
+						},
+						None => break,
+					}
+				}
+
+				if !completed {
+					break
+				}
+
+				queue.remove(0);
+				Self::deposit_event(Event::StorageDepositsReleased { kontract_id });
+			}
+
+			<PendingDeletion<T>>::put(queue);
+			used_weight
+		}
+
+		/// Size of the entries of an encoded kontract storage, without the compact length
+		/// in front of the map
+		fn storage_size(storage_encoded: &Vec<u8>) -> i32 {
+			let mut ref_slice_vec = &storage_encoded[..];
+			let _compact_len: codec::Compact<u32> =
+				Decode::decode(&mut ref_slice_vec).expect("LLLLOOLLL");
+			ref_slice_vec.len() as i32
+		}
 	}
 }
@@ -0,0 +1,205 @@
+use crate::{Config, Pallet};
+use frame_support::{
+	traits::{Get, GetStorageVersion, StorageVersion},
+	weights::Weight,
+};
+
+/// Apply, in order, the migrations not yet applied to the storage of the pallet
+pub fn migrate<T: Config>() -> Weight {
+	let mut weight = T::DbWeight::get().reads(1);
+	let on_chain = Pallet::<T>::on_chain_storage_version();
+
+	if on_chain < 1 {
+		weight = weight.saturating_add(v1::migrate::<T>());
+		StorageVersion::new(1).put::<Pallet<T>>();
+		weight = weight.saturating_add(T::DbWeight::get().writes(1));
+	}
+
+	if on_chain < 2 {
+		weight = weight.saturating_add(v2::migrate::<T>());
+		StorageVersion::new(2).put::<Pallet<T>>();
+		weight = weight.saturating_add(T::DbWeight::get().writes(1));
+	}
+
+	if on_chain < 3 {
+		weight = weight.saturating_add(v3::migrate::<T>());
+		StorageVersion::new(3).put::<Pallet<T>>();
+		weight = weight.saturating_add(T::DbWeight::get().writes(1));
+	}
+
+	if on_chain < 4 {
+		weight = weight.saturating_add(v4::migrate::<T>());
+		StorageVersion::new(4).put::<Pallet<T>>();
+		weight = weight.saturating_add(T::DbWeight::get().writes(1));
+	}
+
+	weight
+}
+
+/// Weight of the migrations not yet applied, counted from the entries they will touch
+/// without changing them. All of them run in the block of the upgrade, so it has to fit
+/// in a block, `pre_upgrade` checks it with try-runtime
+pub fn pending_weight<T: Config>() -> Weight {
+	let mut weight = T::DbWeight::get().reads(1);
+	let on_chain = Pallet::<T>::on_chain_storage_version();
+	let steps = [
+		(1, v1::pending_weight::<T> as fn() -> Weight),
+		(2, v2::pending_weight::<T>),
+		(3, v3::pending_weight::<T>),
+		(4, v4::pending_weight::<T>),
+	];
+	for (version, step_weight) in steps {
+		if on_chain < version {
+			weight = weight
+				.saturating_add(step_weight())
+				.saturating_add(T::DbWeight::get().writes(1));
+		}
+	}
+	weight
+}
+
+/// `StorageUsageOf` came with the quotas of entries and bytes, without it every kontract
+/// would start from an empty usage. Storages already over `MaxStorageBytes` are kept,
+/// the kontract can only shrink them
+mod v1 {
+	use crate::{Config, StorageUsage, StorageUsageOf, Storages};
+	use codec::Encode;
+	use frame_support::{traits::Get, weights::Weight};
+
+	pub fn migrate<T: Config>() -> Weight {
+		let mut count = 0u64;
+		let mut read = 0u64;
+		for (kontract_id, storage) in <Storages<T>>::iter() {
+			let bytes = storage.iter().map(|(key, value)| key.len() + value.len()).sum::<usize>();
+			<StorageUsageOf<T>>::insert(
+				kontract_id,
+				StorageUsage { bytes: bytes as u32, entries: storage.len() as u32 },
+			);
+			count += 1;
+			read += storage.encoded_size() as u64;
+		}
+		weight::<T>(count, read)
+	}
+
+	pub fn pending_weight<T: Config>() -> Weight {
+		let (count, read) = <Storages<T>>::iter_values()
+			.fold((0u64, 0u64), |(count, read), storage| {
+				(count + 1, read + storage.encoded_size() as u64)
+			});
+		weight::<T>(count, read)
+	}
+
+	// Every storage is read whole, its bytes are part of the proof
+	fn weight<T: Config>(count: u64, read: u64) -> Weight {
+		T::DbWeight::get()
+			.reads_writes(count, count)
+			.saturating_add(Weight::from_proof_size(read))
+	}
+}
+
+/// `CodeInfo` came with the version of the host functions imported by the code and with its
+/// metering, the codes uploaded before could only import the first version and were metered
+/// by wasmtime
+mod v2 {
+	use crate::{AbiVersion, AccountId, BalanceOf, CodeInfo, CodeInfoOf, Config, Metering};
+	use codec::{Decode, Encode};
+	use frame_support::{traits::Get, weights::Weight};
+
+	#[derive(Encode, Decode)]
+	struct OldCodeInfo<T: Config> {
+		owner: AccountId<T>,
+		deposit: BalanceOf<T>,
+		refcount: u32,
+	}
+
+	pub fn pending_weight<T: Config>() -> Weight {
+		let count = <CodeInfoOf<T>>::iter_keys().count() as u64;
+		T::DbWeight::get().reads_writes(count, count)
+	}
+
+	pub fn migrate<T: Config>() -> Weight {
+		let mut count = 0u64;
+		<CodeInfoOf<T>>::translate::<OldCodeInfo<T>, _>(|_, old| {
+			count += 1;
+			Some(CodeInfo {
+				owner: old.owner,
+				deposit: old.deposit,
+				refcount: old.refcount,
+				abi_version: AbiVersion::V0,
+				metering: Metering::Engine,
+			})
+		});
+		T::DbWeight::get().reads_writes(count, count)
+	}
+}
+
+/// `KeyHashingInUse` came with the choice of the `KeyHashing`, the storages written before
+/// have the keys hashed with blake2 and the first execution would record the scheme of the
+/// runtime, making them unreachable. So it is recorded here, the runtime has to keep it
+mod v3 {
+	use crate::{Config, KeyHashing, KeyHashingInUse, Storages};
+	use frame_support::{traits::Get, weights::Weight};
+
+	pub fn migrate<T: Config>() -> Weight {
+		if !needed::<T>() {
+			return T::DbWeight::get().reads(2)
+		}
+		<KeyHashingInUse<T>>::put(KeyHashing::Blake2);
+		T::DbWeight::get().reads_writes(2, 1)
+	}
+
+	pub fn pending_weight<T: Config>() -> Weight {
+		T::DbWeight::get().reads_writes(2, needed::<T>() as u64)
+	}
+
+	fn needed<T: Config>() -> bool {
+		<KeyHashingInUse<T>>::get().is_none() && <Storages<T>>::iter_keys().next().is_some()
+	}
+}
+
+/// Before `CodeInfo` and `KontractInfo` every uploaded code was a kontract, its storage
+/// was under the code id. Every code without a `CodeInfo` becomes a kontract with the
+/// code id as id, already initialized, so the storage is found where it is. Nobody uploaded
+/// or deployed them paying a deposit, so their account is the one of the kontract
+mod v4 {
+	use crate::{
+		AbiVersion, CodeInfo, CodeInfoOf, Codes, Config, KontractInfo, Kontracts, Metering, Pallet,
+	};
+	use frame_support::{sp_runtime::traits::Zero, traits::Get, weights::Weight};
+
+	pub fn migrate<T: Config>() -> Weight {
+		let mut count = 0u64;
+		let mut migrated = 0u64;
+		for code_id in <Codes<T>>::iter_keys() {
+			count += 1;
+			if <CodeInfoOf<T>>::contains_key(&code_id) {
+				continue
+			}
+			let account = Pallet::<T>::kontract_account(&code_id);
+			<CodeInfoOf<T>>::insert(
+				&code_id,
+				CodeInfo {
+					owner: account.clone(),
+					deposit: Zero::zero(),
+					refcount: 1,
+					abi_version: AbiVersion::V0,
+					metering: Metering::Engine,
+				},
+			);
+			<Kontracts<T>>::insert(
+				&code_id,
+				KontractInfo { code_id: code_id.clone(), deployer: account, initialized: true },
+			);
+			migrated += 1;
+		}
+		T::DbWeight::get().reads_writes(count * 2, migrated * 2)
+	}
+
+	pub fn pending_weight<T: Config>() -> Weight {
+		let (count, migrated) =
+			<Codes<T>>::iter_keys().fold((0u64, 0u64), |(count, migrated), code_id| {
+				(count + 1, migrated + !<CodeInfoOf<T>>::contains_key(&code_id) as u64)
+			});
+		T::DbWeight::get().reads_writes(count * 2, migrated * 2)
+	}
+}
@@ -1,11 +1,25 @@
 use crate as pallet_kontracts;
-use frame_support::traits::{ConstU16, ConstU32, ConstU64};
+use frame_support::{
+	parameter_types,
+	traits::{ConstU16, ConstU32, ConstU64, Contains, Randomness},
+	weights::Weight,
+	PalletId,
+};
 use frame_system as system;
+use kontracts_executor::{
+	instrument::InstrumentError, AbiVersion, ExecutionErrors, ExecutionOutcome, ExecutionParams,
+	InstructionCosts,
+};
+use pallet_kontracts::{
+	Backend, HostExecutor, HostFnCosts, KeyHashing, KontractExecutor, KontractExtension, Metering,
+};
 use sp_core::H256;
 use sp_runtime::{
 	testing::Header,
-	traits::{BlakeTwo256, IdentityLookup},
+	traits::{BlakeTwo256, Hash, IdentityLookup},
+	DispatchError,
 };
+use std::{cell::RefCell, collections::VecDeque};
 
 type UncheckedExtrinsic = frame_system::mocking::MockUncheckedExtrinsic<Test>;
 type Block = frame_system::mocking::MockBlock<Test>;
@@ -51,6 +65,88 @@ impl system::Config for Test {
 	type MaxConsumers = frame_support::traits::ConstU32<16>;
 }
 
+parameter_types! {
+	// Zero by default, so the tests about the storage deposit are not influenced by the code deposit
+	pub static CodeDepositPerByte: Balance = 0;
+	pub const KontractsPalletId: PalletId = PalletId(*b"kontract");
+	pub static MaxDispatchWeight: Weight = Weight::from_parts(1_000_000, u64::MAX);
+	pub static KontractsKeyHashing: KeyHashing = KeyHashing::Blake2;
+	pub static MaxStorageBytes: u32 = 64 * 1024;
+	// Free by default, so the tests with an exact fuel are not influenced by the host functions
+	pub static KontractsHostFnCosts: HostFnCosts = HostFnCosts { base: 0, per_byte: 0 };
+	pub static KontractsMetering: Metering = Metering::Engine;
+	pub static KontractsBackend: Backend = Backend::Native;
+	pub static KontractsAllowReentrancy: bool = false;
+	pub const MaxExtensionCalls: u32 = 4;
+}
+
+// The kontracts can dispatch every call, except the ones of this pallet
+pub struct TestCallFilter;
+impl Contains<RuntimeCall> for TestCallFilter {
+	fn contains(call: &RuntimeCall) -> bool {
+		!matches!(call, RuntimeCall::Kontracts(_))
+	}
+}
+
+// Only the extension 0, that gives back the input
+pub struct TestExtension;
+impl KontractExtension<u64> for TestExtension {
+	fn call(_: &u64, id: u32, input: Vec<u8>) -> Result<Vec<u8>, DispatchError> {
+		match id {
+			0 => Ok(input),
+			_ => Err(DispatchError::Other("UnknownExtension")),
+		}
+	}
+}
+
+// Predictable on purpose, the same subject always gives the same value
+pub struct TestRandomness;
+impl Randomness<H256, u64> for TestRandomness {
+	fn random(subject: &[u8]) -> (H256, u64) {
+		(BlakeTwo256::hash(subject), System::block_number())
+	}
+}
+
+thread_local! {
+	// Results returned by `MockExecutor` in order, when empty the kontracts are really executed
+	static SCRIPTED_EXECUTIONS: RefCell<VecDeque<Result<ExecutionOutcome, ExecutionErrors>>> =
+		RefCell::new(VecDeque::new());
+	static EXECUTIONS: RefCell<Vec<ExecutionParams>> = RefCell::new(Vec::new());
+}
+
+// The tests can script the next executions, so what the pallet does with an outcome
+// (or an error) is checked without writing a kontract for every case
+pub struct MockExecutor;
+
+impl MockExecutor {
+	pub fn script(result: Result<ExecutionOutcome, ExecutionErrors>) {
+		SCRIPTED_EXECUTIONS.with(|scripted| scripted.borrow_mut().push_back(result));
+	}
+
+	// Params of every execution requested by the pallet, scripted or not
+	pub fn executions() -> Vec<ExecutionParams> {
+		EXECUTIONS.with(|executions| executions.borrow().clone())
+	}
+}
+
+impl KontractExecutor for MockExecutor {
+	fn execute(params: ExecutionParams) -> Result<ExecutionOutcome, ExecutionErrors> {
+		EXECUTIONS.with(|executions| executions.borrow_mut().push(params.clone()));
+		match SCRIPTED_EXECUTIONS.with(|scripted| scripted.borrow_mut().pop_front()) {
+			Some(result) => result,
+			None => HostExecutor::<Test>::execute(params),
+		}
+	}
+
+	fn validate(
+		code: Vec<u8>,
+		metering: Metering,
+		costs: &InstructionCosts,
+	) -> Result<(Vec<u8>, AbiVersion), InstrumentError> {
+		HostExecutor::<Test>::validate(code, metering, costs)
+	}
+}
+
 impl pallet_kontracts::Config for Test {
 	type RuntimeEvent = RuntimeEvent;
 	type Currency = Balances;
@@ -58,7 +154,27 @@ impl pallet_kontracts::Config for Test {
 	type MaxCodeSize = ConstU32<1_000_000>;
 	type MaxKontracStorageKeySize = ConstU32<512>;
 	type MaxKontracStorageValueSize = ConstU32<512>;
-	type MaxKontracStorageSize = ConstU32<1024>; //1KiB
+	type MaxStorageEntries = ConstU32<1024>;
+	type MaxStorageBytes = MaxStorageBytes;
+	type MaxDeletionQueue = ConstU32<16>;
+	type MaxSaltLen = ConstU32<32>;
+	type MaxInputLen = ConstU32<1024>;
+	type CodeDepositPerByte = CodeDepositPerByte;
+	type PalletId = KontractsPalletId;
+	type MaxDependencies = ConstU32<4>;
+	type MaxCallDepth = ConstU32<4>;
+	type AllowReentrancy = KontractsAllowReentrancy;
+	type RuntimeCall = RuntimeCall;
+	type CallFilter = TestCallFilter;
+	type MaxDispatchWeight = MaxDispatchWeight;
+	type Extension = TestExtension;
+	type KeyHashing = KontractsKeyHashing;
+	type Randomness = TestRandomness;
+	type MaxExtensionCalls = MaxExtensionCalls;
+	type HostFnCosts = KontractsHostFnCosts;
+	type Metering = KontractsMetering;
+	type Backend = KontractsBackend;
+	type Executor = MockExecutor;
 	type CodeId = sp_core::H256;
 	type HashingAlgorith = BlakeTwo256;
 }
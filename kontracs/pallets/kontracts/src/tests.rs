@@ -1,7 +1,12 @@
-use crate::{mock::*, Error, Event};
+use crate::{mock::*, AbiVersion, DeclaredDependency, Error, Event, HostFnCosts, KeyHashing};
 use codec::{Decode, Encode};
 use frame_support::{
-	assert_noop, assert_ok, pallet_prelude::DispatchResult, BoundedBTreeMap, BoundedVec,
+	assert_err, assert_noop, assert_ok,
+	dispatch::GetDispatchInfo,
+	pallet_prelude::{DispatchResult, DispatchResultWithPostInfo},
+	traits::{Get, GetStorageVersion, Hooks, Randomness, StorageVersion},
+	weights::Weight,
+	BoundedBTreeMap, BoundedVec,
 };
 
 use sp_core::Hasher;
@@ -33,6 +38,18 @@ where
 	Ok((wasm_binary, code_id))
 }
 
+// Kontracts written by hand in the text format, converted to binaries because only those
+// can be uploaded
+fn load_wat<T>(name: &str) -> Result<(Vec<u8>, <T::HashingAlgorith as Hasher>::Out), &'static str>
+where
+	T: crate::Config,
+{
+	let path = ["wasm_test/", name, ".wat"].concat();
+	let wasm_binary = wat::parse_file(path).map_err(|_| "Invalid wat")?;
+	let code_id = T::HashingAlgorith::hash(&wasm_binary);
+	Ok((wasm_binary, code_id))
+}
+
 #[test]
 fn execute_empty_kontract() {
 	new_test_ext().execute_with(|| {
@@ -52,20 +69,52 @@ fn execute_empty_kontract() {
 
 		System::assert_last_event(Event::<Test>::NewCodeUploaded { code_id, who: account }.into());
 
+		let kontract_id = instantiate_kontract(account, code_id);
+
 		Kontracts::execute_code(
 			RuntimeOrigin::signed(account),
-			code_id,
+			kontract_id,
 			0,
 			u32::MAX,
 			u32::MAX,
 			u32::MAX,
+			None,
+			None,
+			0,
+			BoundedVec::default(),
 		)
 		.expect("Impossible execute code");
 
-		System::assert_last_event(Event::<Test>::CodeExecuted { code_id, who: account }.into());
+		System::assert_last_event(
+			Event::<Test>::KontractExecuted { kontract_id, who: account }.into(),
+		);
 	});
 }
 
+const UNLIMITED: crate::ExecutionLimits =
+	crate::ExecutionLimits { fuel: u32::MAX, max_read: u32::MAX, max_write: u32::MAX };
+
+fn instantiate_kontract(
+	account: u64,
+	code_id: <Test as crate::Config>::CodeId,
+) -> crate::KontractId<Test> {
+	Kontracts::instantiate(
+		RuntimeOrigin::signed(account),
+		code_id,
+		BoundedVec::default(),
+		BoundedVec::default(),
+		UNLIMITED,
+	)
+	.expect("Impossible instantiate kontract");
+
+	let kontract_id = Kontracts::kontract_id(&code_id, &account, &[]);
+	System::assert_has_event(
+		Event::<Test>::KontractInstantiated { kontract_id, code_id, who: account }.into(),
+	);
+	System::assert_last_event(Event::<Test>::KontractInitialized { kontract_id }.into());
+	kontract_id
+}
+
 fn key_hashed<T: crate::Config>(key: Vec<u8>) -> BoundedVec<u8, T::MaxKontracStorageKeySize> {
 	sp_core::Blake2Hasher::hash(&key[..])[..]
 		.to_vec()
@@ -93,13 +142,19 @@ macro_rules! write_B_kontract {
 
 		System::assert_last_event(Event::<Test>::NewCodeUploaded { code_id, who: $account }.into());
 
+		let kontract_id = instantiate_kontract($account, code_id);
+
 		Kontracts::execute_code(
 			RuntimeOrigin::signed($account),
-			code_id,
+			kontract_id,
 			$expected_storage,
 			u32::MAX,
 			u32::MAX,
 			u32::MAX,
+			None,
+			None,
+			0,
+			BoundedVec::default(),
 		)
 	}};
 }
@@ -110,7 +165,7 @@ fn write_8B_equal_kontract() {
 		let acc = 1;
 		let res_execution = write_B_kontract!("write_8B", acc, 47, 47);
 
-		assert_eq!(Ok(()), res_execution);
+		assert_ok!(res_execution);
 		assert_eq!(47, Balances::reserved_balance(acc));
 		assert_eq!(0, Balances::free_balance(acc));
 	});
@@ -126,7 +181,7 @@ fn write_8B_less_kontract() {
 		let res_execution = write_B_kontract!("write_8B", acc, 47, 30);
 
 		//assert_eq!(Err(Error::<Test>::ExceededStorage.into()), res_execution);
-		assert_eq!(Ok(()), res_execution);
+		assert_ok!(res_execution);
 		assert_eq!(0, Balances::reserved_balance(acc));
 		assert_eq!(0, Balances::free_balance(acc));
 	});
@@ -140,7 +195,7 @@ fn write_8B_more_kontract() {
 		// on the reality
 		let res_execution = write_B_kontract!("write_8B", acc, 50, 50);
 
-		assert_eq!(Ok(()), res_execution);
+		assert_ok!(res_execution);
 		assert_eq!(47, Balances::reserved_balance(acc));
 		assert_eq!(3, Balances::free_balance(acc));
 	});
@@ -172,6 +227,8 @@ fn write_8B_negative_kontract() {
 
 		System::assert_last_event(Event::<Test>::NewCodeUploaded { code_id, who: acc }.into());
 
+		let kontract_id = instantiate_kontract(acc, code_id);
+
 		// write in storage a bigger value under the same key
 		let mut kontract_storage: crate::pallet::KontractStorage<Test> = BoundedBTreeMap::new();
 		kontract_storage
@@ -183,12 +240,23 @@ fn write_8B_negative_kontract() {
 					.expect("Impossible create bounded vec"),
 			)
 			.expect("Impossible insert element in the map");
-		crate::Storages::<Test>::insert(code_id, kontract_storage);
+		crate::Storages::<Test>::insert(kontract_id, kontract_storage);
+		crate::StorageDeposits::<Test>::insert(kontract_id, acc, 55);
 
-		let res_execution =
-			Kontracts::execute_code(origin, code_id, -8, u32::MAX, u32::MAX, u32::MAX);
+		let res_execution = Kontracts::execute_code(
+			origin,
+			kontract_id,
+			-8,
+			u32::MAX,
+			u32::MAX,
+			u32::MAX,
+			None,
+			None,
+			0,
+			BoundedVec::default(),
+		);
 
-		assert_eq!(Ok(()), res_execution);
+		assert_ok!(res_execution);
 		assert_eq!(47, Balances::reserved_balance(acc));
 		assert_eq!(53, Balances::free_balance(acc));
 	});
@@ -215,6 +283,8 @@ fn delete_kontract() {
 
 		System::assert_last_event(Event::<Test>::NewCodeUploaded { code_id, who: acc }.into());
 
+		let kontract_id = instantiate_kontract(acc, code_id);
+
 		// write in storage a bigger value under the same key
 		let mut kontract_storage: crate::pallet::KontractStorage<Test> = BoundedBTreeMap::new();
 		kontract_storage
@@ -226,12 +296,23 @@ fn delete_kontract() {
 					.expect("Impossible create bounded vec"),
 			)
 			.expect("Impossible insert element in the map");
-		crate::Storages::<Test>::insert(code_id, kontract_storage);
+		crate::Storages::<Test>::insert(kontract_id, kontract_storage);
+		crate::StorageDeposits::<Test>::insert(kontract_id, acc, 47);
 
-		let res_execution =
-			Kontracts::execute_code(origin, code_id, -47, u32::MAX, u32::MAX, u32::MAX);
+		let res_execution = Kontracts::execute_code(
+			origin,
+			kontract_id,
+			-47,
+			u32::MAX,
+			u32::MAX,
+			u32::MAX,
+			None,
+			None,
+			0,
+			BoundedVec::default(),
+		);
 
-		assert_eq!(Ok(()), res_execution);
+		assert_ok!(res_execution);
 		assert_eq!(0, Balances::reserved_balance(acc));
 		assert_eq!(100, Balances::free_balance(acc));
 	});
@@ -239,6 +320,66 @@ fn delete_kontract() {
 
 //TODO: test with someone that decleared more free space than reality
 
+#[test]
+fn free_storage_paid_by_another_account() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		let (payer, other) = (1, 2);
+
+		Balances::set_balance(RuntimeOrigin::root(), payer, 53, 47)
+			.expect("Impossibel set balance");
+		// Reserved for something else, the kontract must not release it
+		Balances::set_balance(RuntimeOrigin::root(), other, 50, 30)
+			.expect("Impossibel set balance");
+
+		let (wasm_binary, code_id): (_, <Test as crate::Config>::CodeId) =
+			load_wasm::<Test>("delete").unwrap();
+
+		Kontracts::upload_code(
+			RuntimeOrigin::signed(payer),
+			BoundedVec::try_from(wasm_binary).expect("Code too big"),
+		)
+		.expect("Impossible upload code");
+
+		let kontract_id = instantiate_kontract(payer, code_id);
+
+		// The storage deleted by the kontract has been paid by `payer`
+		let mut kontract_storage: crate::pallet::KontractStorage<Test> = BoundedBTreeMap::new();
+		kontract_storage
+			.try_insert(
+				key_hashed::<Test>(vec![1u32, 2u32, 3u32].encode()),
+				vec![4u32, 5u32, 6u32]
+					.encode()
+					.try_into()
+					.expect("Impossible create bounded vec"),
+			)
+			.expect("Impossible insert element in the map");
+		crate::Storages::<Test>::insert(kontract_id, kontract_storage);
+		crate::StorageDeposits::<Test>::insert(kontract_id, payer, 47);
+
+		assert_ok!(Kontracts::execute_code(
+			RuntimeOrigin::signed(other),
+			kontract_id,
+			-47,
+			u32::MAX,
+			u32::MAX,
+			u32::MAX,
+			None,
+			None,
+			0,
+			BoundedVec::default(),
+		));
+
+		// `other` deposited nothing for this kontract, so nothing is released
+		assert_eq!(30, Balances::reserved_balance(other));
+		assert_eq!(50, Balances::free_balance(other));
+		assert_eq!(0, crate::StorageDeposits::<Test>::get(kontract_id, other));
+		// The deposit of `payer` stays in the ledger, released when the kontract is deleted
+		assert_eq!(47, Balances::reserved_balance(payer));
+		assert_eq!(47, crate::StorageDeposits::<Test>::get(kontract_id, payer));
+	});
+}
+
 #[test]
 fn read_kontract() {
 	new_test_ext().execute_with(|| {
@@ -256,6 +397,10 @@ fn read_kontract() {
 		)
 		.expect("Impossible upload code");
 
+		System::assert_last_event(Event::<Test>::NewCodeUploaded { code_id, who: account }.into());
+
+		let kontract_id = instantiate_kontract(account, code_id);
+
 		// write in memory the correct value
 		let mut kontract_storage: crate::pallet::KontractStorage<Test> = BoundedBTreeMap::new();
 		kontract_storage
@@ -267,21 +412,25 @@ fn read_kontract() {
 					.expect("Impossible create bounded vec"),
 			)
 			.expect("Impossible insert element in the map");
-		crate::Storages::<Test>::insert(code_id, kontract_storage);
-
-		System::assert_last_event(Event::<Test>::NewCodeUploaded { code_id, who: account }.into());
+		crate::Storages::<Test>::insert(kontract_id, kontract_storage);
 
 		Kontracts::execute_code(
 			RuntimeOrigin::signed(account),
-			code_id,
+			kontract_id,
 			0,
 			u32::MAX,
 			u32::MAX,
 			u32::MAX,
+			None,
+			None,
+			0,
+			BoundedVec::default(),
 		)
 		.expect("Impossible execute code");
 
-		System::assert_last_event(Event::<Test>::CodeExecuted { code_id, who: account }.into());
+		System::assert_last_event(
+			Event::<Test>::KontractExecuted { kontract_id, who: account }.into(),
+		);
 	});
 }
 
@@ -312,19 +461,27 @@ fn read_and_multiple_write_ok() {
 
 		System::assert_last_event(Event::<Test>::NewCodeUploaded { code_id, who: account }.into());
 
+		let kontract_id = instantiate_kontract(account, code_id);
+
 		Kontracts::execute_code(
 			origin,
-			code_id,
+			kontract_id,
 			deposit_for_storage as i32,
 			u32::MAX,
 			u32::MAX,
 			u32::MAX,
+			None,
+			None,
+			0,
+			BoundedVec::default(),
 		)
 		.expect("Impossible execute code");
 
-		System::assert_last_event(Event::<Test>::CodeExecuted { code_id, who: account }.into());
+		System::assert_last_event(
+			Event::<Test>::KontractExecuted { kontract_id, who: account }.into(),
+		);
 
-		let kontract_storage_result = crate::Storages::<Test>::get(code_id);
+		let kontract_storage_result = crate::Storages::<Test>::get(kontract_id);
 
 		// write in memory the correct value
 		let mut expected_kontract_storage: crate::pallet::KontractStorage<Test> =
@@ -380,6 +537,8 @@ fn collantz_conjecture_kontract() {
 
 		System::assert_last_event(Event::<Test>::NewCodeUploaded { code_id, who: account }.into());
 
+		let kontract_id = instantiate_kontract(account, code_id);
+
 		let next_collantz = |val: u32| match val % 2 {
 			0 => val / 2,
 			_ => (val * 3) + 1,
@@ -402,15 +561,19 @@ fn collantz_conjecture_kontract() {
 			// 4 bytes are used the first time and than zero
 			Kontracts::execute_code(
 				origin.clone(),
-				code_id,
+				kontract_id,
 				new_storage_size,
 				u32::MAX,
 				u32::MAX,
 				u32::MAX,
+				None,
+				None,
+				0,
+				BoundedVec::default(),
 			)
 			.expect("Impossible execute code");
 			let val: u32 = Decode::decode(
-				&mut &crate::Storages::<Test>::get(code_id)
+				&mut &crate::Storages::<Test>::get(kontract_id)
 					.get(&key)
 					.expect("Number not defined in the storage of the kontract")
 					.clone()
@@ -421,7 +584,9 @@ fn collantz_conjecture_kontract() {
 			assert_eq!(*collantz_number, val);
 		}
 
-		System::assert_last_event(Event::<Test>::CodeExecuted { code_id, who: account }.into());
+		System::assert_last_event(
+			Event::<Test>::KontractExecuted { kontract_id, who: account }.into(),
+		);
 	});
 }
 
@@ -444,15 +609,21 @@ fn panic_kontract() {
 
 		System::assert_last_event(Event::<Test>::NewCodeUploaded { code_id, who: account }.into());
 
+		let kontract_id = instantiate_kontract(account, code_id);
+
 		// TODO: fee managment if a panic occur
 		assert_noop!(
 			Kontracts::execute_code(
 				RuntimeOrigin::signed(account),
-				code_id,
+				kontract_id,
 				0,
 				u32::MAX,
 				u32::MAX,
-				u32::MAX
+				u32::MAX,
+				None,
+				None,
+				0,
+				BoundedVec::default()
 			),
 			Error::<Test>::ExecutionCode(kontracts_executor::ExecutionErrors::WasmPanic)
 		);
@@ -478,15 +649,21 @@ fn out_of_fuel_kontract() {
 
 		System::assert_last_event(Event::<Test>::NewCodeUploaded { code_id, who: account }.into());
 
+		let kontract_id = instantiate_kontract(account, code_id);
+
 		// TODO: fee managment if a panic occur
 		assert_noop!(
 			Kontracts::execute_code(
 				RuntimeOrigin::signed(account),
-				code_id,
+				kontract_id,
 				0,
 				10,
 				u32::MAX,
-				u32::MAX
+				u32::MAX,
+				None,
+				None,
+				0,
+				BoundedVec::default()
 			),
 			Error::<Test>::ExecutionCode(kontracts_executor::ExecutionErrors::OutOfFuel)
 		);
@@ -516,15 +693,21 @@ fn out_of_reads_or_writes(
 
 		System::assert_last_event(Event::<Test>::NewCodeUploaded { code_id, who: account }.into());
 
+		let kontract_id = instantiate_kontract(account, code_id);
+
 		// TODO: fee managment if a panic occur
 		assert_noop!(
 			Kontracts::execute_code(
 				RuntimeOrigin::signed(account),
-				code_id,
+				kontract_id,
 				0,
 				u32::MAX,
 				max_read,
-				max_write
+				max_write,
+				None,
+				None,
+				0,
+				BoundedVec::default()
 			),
 			Error::<Test>::ExecutionCode(expected_err)
 		);
@@ -540,3 +723,2359 @@ fn out_of_reads() {
 fn out_of_writes() {
 	out_of_reads_or_writes("write_8B", 0, 0, kontracts_executor::ExecutionErrors::OutOfWrites);
 }
+
+#[test]
+fn upload_code_reserve_deposit() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		CodeDepositPerByte::set(1);
+
+		let acc = 1;
+		let (wasm_binary, code_id): (_, <Test as crate::Config>::CodeId) =
+			load_wasm::<Test>("empty_execution").unwrap();
+		let code_len = wasm_binary.len() as u64;
+
+		Balances::set_balance(RuntimeOrigin::root(), acc, code_len + 10, 0)
+			.expect("Impossibel set balance");
+
+		Kontracts::upload_code(
+			RuntimeOrigin::signed(acc),
+			BoundedVec::try_from(wasm_binary.clone()).expect("Code too big"),
+		)
+		.expect("Impossible upload code");
+
+		assert_eq!(code_len, Balances::reserved_balance(acc));
+		assert_eq!(10, Balances::free_balance(acc));
+		assert_eq!(
+			Some(crate::CodeInfo {
+				owner: acc,
+				deposit: code_len,
+				refcount: 0,
+				abi_version: AbiVersion::V0,
+				metering: crate::Metering::Engine
+			}),
+			crate::CodeInfoOf::<Test>::get(code_id)
+		);
+
+		// The same code can't be uploaded twice
+		assert_noop!(
+			Kontracts::upload_code(
+				RuntimeOrigin::signed(acc),
+				BoundedVec::try_from(wasm_binary).expect("Code too big"),
+			),
+			Error::<Test>::CodeAlreadyUploaded
+		);
+	});
+}
+
+#[test]
+fn upload_code_without_deposit() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		CodeDepositPerByte::set(1);
+
+		let acc = 1;
+		let (wasm_binary, _) = load_wasm::<Test>("empty_execution").unwrap();
+
+		Balances::set_balance(RuntimeOrigin::root(), acc, wasm_binary.len() as u64 - 1, 0)
+			.expect("Impossibel set balance");
+
+		assert_noop!(
+			Kontracts::upload_code(
+				RuntimeOrigin::signed(acc),
+				BoundedVec::try_from(wasm_binary).expect("Code too big"),
+			),
+			Error::<Test>::DepositError
+		);
+	});
+}
+
+#[test]
+fn delete_code_unreserve_deposit() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		CodeDepositPerByte::set(1);
+
+		let acc = 1;
+		let (wasm_binary, code_id): (_, <Test as crate::Config>::CodeId) =
+			load_wasm::<Test>("empty_execution").unwrap();
+		let code_len = wasm_binary.len() as u64;
+
+		Balances::set_balance(RuntimeOrigin::root(), acc, code_len, 0)
+			.expect("Impossibel set balance");
+
+		Kontracts::upload_code(
+			RuntimeOrigin::signed(acc),
+			BoundedVec::try_from(wasm_binary).expect("Code too big"),
+		)
+		.expect("Impossible upload code");
+		assert_eq!(code_len, Balances::reserved_balance(acc));
+
+		assert_ok!(Kontracts::delete_code(RuntimeOrigin::root(), code_id));
+
+		System::assert_last_event(Event::<Test>::CodeDeleted { code_id }.into());
+		assert_eq!(0, Balances::reserved_balance(acc));
+		assert_eq!(code_len, Balances::free_balance(acc));
+		assert_eq!(None, crate::CodeInfoOf::<Test>::get(code_id));
+	});
+}
+
+#[test]
+fn delete_code_in_use() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+
+		let acc = 1;
+		let (wasm_binary, code_id): (_, <Test as crate::Config>::CodeId) =
+			load_wasm::<Test>("empty_execution").unwrap();
+
+		Kontracts::upload_code(
+			RuntimeOrigin::signed(acc),
+			BoundedVec::try_from(wasm_binary).expect("Code too big"),
+		)
+		.expect("Impossible upload code");
+
+		let kontract_id = instantiate_kontract(acc, code_id);
+
+		assert_noop!(
+			Kontracts::delete_code(RuntimeOrigin::root(), code_id),
+			Error::<Test>::CodeInUse
+		);
+
+		// Once the only kontract that was using the code is deleted the code can be deleted
+		assert_ok!(Kontracts::delete_kontract(RuntimeOrigin::root(), kontract_id));
+		assert_ok!(Kontracts::delete_code(RuntimeOrigin::root(), code_id));
+	});
+}
+
+#[test]
+fn delete_code_without_code_info() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+
+		// Uploaded before the code deposits, only the code is on chain
+		let (wasm_binary, code_id): (_, <Test as crate::Config>::CodeId) =
+			load_wasm::<Test>("empty_execution").unwrap();
+		crate::Codes::<Test>::insert(
+			code_id,
+			BoundedVec::<u8, <Test as crate::Config>::MaxCodeSize>::try_from(wasm_binary)
+				.expect("Code too big"),
+		);
+
+		assert_noop!(
+			Kontracts::delete_code(RuntimeOrigin::root(), code_id),
+			Error::<Test>::MissingCodeInfo
+		);
+		assert_noop!(
+			Kontracts::delete_code(RuntimeOrigin::root(), sp_core::H256::zero()),
+			Error::<Test>::InvalidCodeId
+		);
+	});
+}
+
+#[test]
+fn execute_code_without_code_info() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		let acc = 1;
+
+		// Uploaded and instantiated before the code deposits, it imports the first version
+		let (wasm_binary, code_id): (_, <Test as crate::Config>::CodeId) =
+			load_wasm::<Test>("empty_execution").unwrap();
+		crate::Codes::<Test>::insert(
+			code_id,
+			BoundedVec::<u8, <Test as crate::Config>::MaxCodeSize>::try_from(wasm_binary)
+				.expect("Code too big"),
+		);
+		let kontract_id = Kontracts::kontract_id(&code_id, &acc, &[]);
+		crate::Kontracts::<Test>::insert(
+			kontract_id,
+			crate::KontractInfo { code_id, deployer: acc, initialized: true },
+		);
+
+		assert_ok!(execute_with_dependencies(acc, kontract_id, 1_000_000, 10, vec![]));
+		let params = MockExecutor::executions().last().expect("No execution").clone();
+		assert_eq!(AbiVersion::V0, params.abi_version);
+	});
+}
+
+#[test]
+fn execute_code_version_1() {
+	new_test_ext().execute_with(|| {
+		// The blocks of the runtimes before the versioned interface call the first version
+		let (wasm_binary, _) = load_wasm::<Test>("write_8B").unwrap();
+		let params = kontracts_executor::ExecutionParams::original(
+			wasm_binary,
+			std::collections::BTreeMap::<Vec<u8>, Vec<u8>>::new().encode(),
+			u32::MAX,
+			10,
+			10,
+		);
+		assert_eq!(AbiVersion::V0, params.abi_version);
+
+		let outcome = kontracts_executor::execute(crate::Backend::Native, params)
+			.unwrap_or_else(|_| panic!("Execution failed"));
+		let storage: std::collections::BTreeMap<Vec<u8>, Vec<u8>> =
+			Decode::decode(&mut &outcome.storage[..]).unwrap();
+		assert_eq!(
+			Some(&vec![4u32, 5u32, 6u32].encode()),
+			storage.get(&key_hashed::<Test>(vec![1u32, 2u32, 3u32].encode())[..])
+		);
+	});
+}
+
+#[test]
+fn instantiate_passes_input_to_constructor() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		let acc = 1;
+		Balances::set_balance(RuntimeOrigin::root(), acc, 100, 0).expect("Impossibel set balance");
+
+		let (code, code_id) = load_wat::<Test>("constructor").unwrap();
+		assert_ok!(Kontracts::upload_code(
+			RuntimeOrigin::signed(acc),
+			BoundedVec::try_from(code).expect("Code too big"),
+		));
+
+		assert_ok!(Kontracts::instantiate(
+			RuntimeOrigin::signed(acc),
+			code_id,
+			BoundedVec::default(),
+			BoundedVec::try_from(b"hello".to_vec()).expect("Input too big"),
+			UNLIMITED,
+		));
+
+		let kontract_id = Kontracts::kontract_id(&code_id, &acc, &[]);
+		System::assert_last_event(Event::<Test>::KontractInitialized { kontract_id }.into());
+		let storage = crate::Storages::<Test>::get(kontract_id);
+		assert_eq!(
+			Some(b"hello".to_vec()),
+			storage.get(&key_hashed::<Test>(b"init".to_vec())).map(|value| value.to_vec())
+		);
+	});
+}
+
+#[test]
+fn failed_constructor_reverted() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		let acc = 1;
+		Balances::set_balance(RuntimeOrigin::root(), acc, 100, 0).expect("Impossibel set balance");
+
+		let (code, code_id) = load_wat::<Test>("constructor").unwrap();
+		assert_ok!(Kontracts::upload_code(
+			RuntimeOrigin::signed(acc),
+			BoundedVec::try_from(code).expect("Code too big"),
+		));
+
+		// Without input the constructor writes in the storage and then fails
+		assert_ok!(Kontracts::instantiate(
+			RuntimeOrigin::signed(acc),
+			code_id,
+			BoundedVec::default(),
+			BoundedVec::default(),
+			UNLIMITED,
+		));
+
+		let kontract_id = Kontracts::kontract_id(&code_id, &acc, &[]);
+		assert!(matches!(
+			System::events().last().map(|record| record.event.clone()),
+			Some(RuntimeEvent::Kontracts(Event::ConstructorFailed { .. }))
+		));
+		// The kontract exists, but nothing done by the constructor is kept
+		assert!(crate::Kontracts::<Test>::get(kontract_id).is_some());
+		assert!(crate::Storages::<Test>::get(kontract_id).is_empty());
+		assert_eq!(0, crate::StorageDeposits::<Test>::get(kontract_id, acc));
+		assert_eq!(0, Balances::reserved_balance(acc));
+
+		assert_ok!(Kontracts::initialize(
+			RuntimeOrigin::signed(acc),
+			kontract_id,
+			BoundedVec::try_from(b"hi".to_vec()).expect("Input too big"),
+			UNLIMITED
+		));
+		System::assert_last_event(Event::<Test>::KontractInitialized { kontract_id }.into());
+		assert_eq!(1, crate::Storages::<Test>::get(kontract_id).len());
+	});
+}
+
+#[test]
+fn instantiate_multiple_kontracts() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+
+		let acc = 1;
+		Balances::set_balance(RuntimeOrigin::root(), acc, 100, 0).expect("Impossibel set balance");
+
+		let (wasm_binary, code_id): (_, <Test as crate::Config>::CodeId) =
+			load_wasm::<Test>("write_8B").unwrap();
+
+		Kontracts::upload_code(
+			RuntimeOrigin::signed(acc),
+			BoundedVec::try_from(wasm_binary).expect("Code too big"),
+		)
+		.expect("Impossible upload code");
+
+		let first_kontract_id = instantiate_kontract(acc, code_id);
+
+		// The same deployer can't instantiate two kontracts with the same code and salt
+		assert_noop!(
+			Kontracts::instantiate(
+				RuntimeOrigin::signed(acc),
+				code_id,
+				BoundedVec::default(),
+				BoundedVec::default(),
+				UNLIMITED
+			),
+			Error::<Test>::KontractAlreadyExists
+		);
+
+		let salt: BoundedVec<u8, <Test as crate::Config>::MaxSaltLen> =
+			vec![1u8].try_into().expect("Salt too big");
+		assert_ok!(Kontracts::instantiate(
+			RuntimeOrigin::signed(acc),
+			code_id,
+			salt.clone(),
+			BoundedVec::default(),
+			UNLIMITED
+		));
+		let second_kontract_id = Kontracts::kontract_id(&code_id, &acc, &salt);
+
+		assert_ne!(first_kontract_id, second_kontract_id);
+		assert_eq!(Some(2), crate::CodeInfoOf::<Test>::get(code_id).map(|info| info.refcount));
+
+		assert_ok!(Kontracts::execute_code(
+			RuntimeOrigin::signed(acc),
+			first_kontract_id,
+			47,
+			u32::MAX,
+			u32::MAX,
+			u32::MAX,
+			None,
+			None,
+			0,
+			BoundedVec::default(),
+		));
+
+		// Every kontract has its own storage
+		assert_eq!(1, crate::Storages::<Test>::get(first_kontract_id).len());
+		assert!(crate::Storages::<Test>::get(second_kontract_id).is_empty());
+	});
+}
+
+#[test]
+fn execute_not_instantiated_code() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+
+		let acc = 1;
+		let (wasm_binary, code_id): (_, <Test as crate::Config>::CodeId) =
+			load_wasm::<Test>("empty_execution").unwrap();
+
+		Kontracts::upload_code(
+			RuntimeOrigin::signed(acc),
+			BoundedVec::try_from(wasm_binary).expect("Code too big"),
+		)
+		.expect("Impossible upload code");
+
+		assert_noop!(
+			Kontracts::execute_code(
+				RuntimeOrigin::signed(acc),
+				code_id,
+				0,
+				u32::MAX,
+				u32::MAX,
+				u32::MAX,
+				None,
+				None,
+				0,
+				BoundedVec::default()
+			),
+			Error::<Test>::InvalidKontractId
+		);
+	});
+}
+
+#[test]
+fn update_code_adjust_deposit() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		CodeDepositPerByte::set(1);
+
+		let acc = 1;
+		let (old_wasm_binary, old_code_id): (_, <Test as crate::Config>::CodeId) =
+			load_wasm::<Test>("empty_execution").unwrap();
+		let (new_wasm_binary, new_code_id): (_, <Test as crate::Config>::CodeId) =
+			load_wasm::<Test>("collantz_conjecture").unwrap();
+		let (old_len, new_len) = (old_wasm_binary.len() as u64, new_wasm_binary.len() as u64);
+		let start_balance = old_len + new_len;
+
+		Balances::set_balance(RuntimeOrigin::root(), acc, start_balance, 0)
+			.expect("Impossibel set balance");
+
+		Kontracts::upload_code(
+			RuntimeOrigin::signed(acc),
+			BoundedVec::try_from(old_wasm_binary).expect("Code too big"),
+		)
+		.expect("Impossible upload code");
+
+		let kontract_id = instantiate_kontract(acc, old_code_id);
+
+		assert_ok!(Kontracts::update_code(
+			RuntimeOrigin::root(),
+			kontract_id,
+			BoundedVec::try_from(new_wasm_binary).expect("Code too big"),
+			None,
+		));
+
+		System::assert_last_event(
+			Event::<Test>::CodeUpgraded { kontract_id, old_code_id, new_code_id }.into(),
+		);
+
+		// The kontract was the only one using the old code, so the old code is replaced
+		assert_eq!(None, crate::CodeInfoOf::<Test>::get(old_code_id));
+		assert_eq!(new_len, Balances::reserved_balance(acc));
+		assert_eq!(start_balance - new_len, Balances::free_balance(acc));
+		assert_eq!(
+			Some(crate::CodeInfo {
+				owner: acc,
+				deposit: new_len,
+				refcount: 1,
+				abi_version: AbiVersion::V0,
+				metering: crate::Metering::Engine
+			}),
+			crate::CodeInfoOf::<Test>::get(new_code_id)
+		);
+	});
+}
+
+#[test]
+fn update_code_preserve_storage() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+
+		let acc = 1;
+		let (old_wasm_binary, old_code_id): (_, <Test as crate::Config>::CodeId) =
+			load_wasm::<Test>("write_8B").unwrap();
+		let (new_wasm_binary, new_code_id): (_, <Test as crate::Config>::CodeId) =
+			load_wasm::<Test>("read").unwrap();
+
+		Kontracts::upload_code(
+			RuntimeOrigin::signed(acc),
+			BoundedVec::try_from(old_wasm_binary).expect("Code too big"),
+		)
+		.expect("Impossible upload code");
+
+		let kontract_id = instantiate_kontract(acc, old_code_id);
+
+		let mut kontract_storage: crate::pallet::KontractStorage<Test> = BoundedBTreeMap::new();
+		kontract_storage
+			.try_insert(
+				key_hashed::<Test>(vec![1u32, 2u32, 3u32].encode()),
+				vec![4u32, 5u32, 6u32]
+					.encode()
+					.try_into()
+					.expect("Impossible create bounded vec"),
+			)
+			.expect("Impossible insert element in the map");
+		crate::Storages::<Test>::insert(kontract_id, kontract_storage.clone());
+
+		assert_ok!(Kontracts::update_code(
+			RuntimeOrigin::root(),
+			kontract_id,
+			BoundedVec::try_from(new_wasm_binary).expect("Code too big"),
+			None,
+		));
+
+		assert_eq!(
+			Some(new_code_id),
+			crate::Kontracts::<Test>::get(kontract_id).map(|kontract| kontract.code_id)
+		);
+		assert_eq!(kontract_storage, crate::Storages::<Test>::get(kontract_id));
+
+		// The new code is able to read what the old one wrote
+		assert_ok!(Kontracts::execute_code(
+			RuntimeOrigin::signed(acc),
+			kontract_id,
+			0,
+			u32::MAX,
+			u32::MAX,
+			u32::MAX,
+			None,
+			None,
+			0,
+			BoundedVec::default(),
+		));
+	});
+}
+
+#[test]
+fn update_code_missing_migrate_entry_point() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+
+		let acc = 1;
+		let (old_wasm_binary, old_code_id): (_, <Test as crate::Config>::CodeId) =
+			load_wasm::<Test>("empty_execution").unwrap();
+		let (new_wasm_binary, _) = load_wasm::<Test>("write_8B").unwrap();
+
+		Kontracts::upload_code(
+			RuntimeOrigin::signed(acc),
+			BoundedVec::try_from(old_wasm_binary).expect("Code too big"),
+		)
+		.expect("Impossible upload code");
+
+		let kontract_id = instantiate_kontract(acc, old_code_id);
+
+		assert_noop!(
+			Kontracts::update_code(
+				RuntimeOrigin::root(),
+				kontract_id,
+				BoundedVec::try_from(new_wasm_binary).expect("Code too big"),
+				Some(crate::ExecutionLimits { fuel: u32::MAX, max_read: 10, max_write: 10 }),
+			),
+			Error::<Test>::ExecutionCode(kontracts_executor::ExecutionErrors::EntryPointNotDefined)
+		);
+	});
+}
+
+#[test]
+fn update_code_migrate_with_stored_code() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		let acc = 1;
+		Balances::set_balance(RuntimeOrigin::root(), acc, 1_000, 0)
+			.expect("Impossible set balance");
+		let (_, kontract_id) = instantiate_wat_kontract(acc, "caller");
+		let (new_code, new_code_id) = load_wat::<Test>("callee").unwrap();
+		assert_ok!(Kontracts::upload_code(
+			RuntimeOrigin::signed(acc),
+			BoundedVec::try_from(new_code.clone()).expect("Code too big"),
+		));
+
+		// Prepared again now the code would be instrumented
+		KontractsMetering::set(crate::Metering::Instrumented);
+		MockExecutor::script(Ok(scripted_outcome(Vec::new())));
+		assert_ok!(Kontracts::update_code(
+			RuntimeOrigin::root(),
+			kontract_id,
+			BoundedVec::try_from(new_code).expect("Code too big"),
+			Some(crate::ExecutionLimits { fuel: u32::MAX, max_read: 10, max_write: 10 }),
+		));
+
+		let params = MockExecutor::executions().last().expect("No execution").clone();
+		assert!(kontracts_executor::EntryPoint::Migrate == params.entry_point);
+		assert_eq!(
+			crate::Codes::<Test>::get(new_code_id).map(|code| code.to_vec()),
+			Some(params.code)
+		);
+		assert_eq!(crate::Metering::Engine, params.metering);
+	});
+}
+
+#[test]
+fn delete_kontract_release_storage_deposits() {
+	new_test_ext().execute_with(|| {
+		let acc = 1;
+		let res_execution = write_B_kontract!("write_8B", acc, 47, 47);
+		assert_ok!(res_execution);
+
+		let (_, code_id): (_, <Test as crate::Config>::CodeId) =
+			load_wasm::<Test>("write_8B").unwrap();
+		let kontract_id = Kontracts::kontract_id(&code_id, &acc, &[]);
+		assert_eq!(47, crate::StorageDeposits::<Test>::get(kontract_id, acc));
+
+		assert_ok!(Kontracts::delete_kontract(RuntimeOrigin::root(), kontract_id));
+
+		// The storage is immediately removed but the deposits are released later
+		System::assert_last_event(Event::<Test>::KontractDeleted { kontract_id }.into());
+		assert!(!crate::Storages::<Test>::contains_key(kontract_id));
+		assert_eq!(vec![kontract_id], crate::PendingDeletion::<Test>::get().into_inner());
+		assert_eq!(47, Balances::reserved_balance(acc));
+
+		// The same address can't be instantiated again until the deposits are released
+		assert_noop!(
+			Kontracts::instantiate(
+				RuntimeOrigin::signed(acc),
+				code_id,
+				BoundedVec::default(),
+				BoundedVec::default(),
+				UNLIMITED
+			),
+			Error::<Test>::KontractPendingDeletion
+		);
+
+		Kontracts::on_idle(1, Weight::MAX);
+
+		System::assert_last_event(Event::<Test>::StorageDepositsReleased { kontract_id }.into());
+		assert!(crate::PendingDeletion::<Test>::get().is_empty());
+		assert_eq!(0, crate::StorageDeposits::<Test>::get(kontract_id, acc));
+		assert_eq!(0, Balances::reserved_balance(acc));
+		assert_eq!(47, Balances::free_balance(acc));
+	});
+}
+
+#[test]
+fn execute_not_initialized_kontract() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+
+		let acc = 1;
+		let (wasm_binary, code_id): (_, <Test as crate::Config>::CodeId) =
+			load_wasm::<Test>("empty_execution").unwrap();
+
+		Kontracts::upload_code(
+			RuntimeOrigin::signed(acc),
+			BoundedVec::try_from(wasm_binary).expect("Code too big"),
+		)
+		.expect("Impossible upload code");
+
+		let kontract_id = instantiate_kontract(acc, code_id);
+
+		// The constructor is already been executed
+		assert_noop!(
+			Kontracts::initialize(
+				RuntimeOrigin::signed(acc),
+				kontract_id,
+				BoundedVec::default(),
+				UNLIMITED
+			),
+			Error::<Test>::KontractAlreadyInitialized
+		);
+
+		// Simulate a failed constructor
+		crate::Kontracts::<Test>::mutate(kontract_id, |kontract| {
+			kontract.as_mut().expect("Kontract not instantiated").initialized = false
+		});
+
+		assert_noop!(
+			Kontracts::execute_code(
+				RuntimeOrigin::signed(acc),
+				kontract_id,
+				0,
+				u32::MAX,
+				u32::MAX,
+				u32::MAX,
+				None,
+				None,
+				0,
+				BoundedVec::default()
+			),
+			Error::<Test>::KontractNotInitialized
+		);
+
+		// Only the deployer can retry the constructor
+		assert_noop!(
+			Kontracts::initialize(
+				RuntimeOrigin::signed(acc + 1),
+				kontract_id,
+				BoundedVec::default(),
+				UNLIMITED
+			),
+			Error::<Test>::NotKontractDeployer
+		);
+
+		assert_ok!(Kontracts::initialize(
+			RuntimeOrigin::signed(acc),
+			kontract_id,
+			BoundedVec::default(),
+			UNLIMITED
+		));
+		System::assert_last_event(Event::<Test>::KontractInitialized { kontract_id }.into());
+
+		assert_ok!(Kontracts::execute_code(
+			RuntimeOrigin::signed(acc),
+			kontract_id,
+			0,
+			u32::MAX,
+			u32::MAX,
+			u32::MAX,
+			None,
+			None,
+			0,
+			BoundedVec::default(),
+		));
+	});
+}
+
+#[test]
+fn execute_code_transfer_value_to_kontract() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		let acc = 1;
+		Balances::set_balance(RuntimeOrigin::root(), acc, 100, 0).expect("Impossibel set balance");
+
+		let (wasm_binary, code_id): (_, <Test as crate::Config>::CodeId) =
+			load_wasm::<Test>("empty_execution").unwrap();
+		assert_ok!(Kontracts::upload_code(
+			RuntimeOrigin::signed(acc),
+			BoundedVec::try_from(wasm_binary).expect("Code too big"),
+		));
+		let kontract_id = instantiate_kontract(acc, code_id);
+		let kontract_account = Kontracts::kontract_account(&kontract_id);
+
+		assert_ok!(Kontracts::execute_code(
+			RuntimeOrigin::signed(acc),
+			kontract_id,
+			0,
+			u32::MAX,
+			u32::MAX,
+			u32::MAX,
+			None,
+			None,
+			30,
+			BoundedVec::default(),
+		));
+		assert_eq!(70, Balances::free_balance(acc));
+		assert_eq!(30, Balances::free_balance(kontract_account));
+
+		// The value is not moved if the caller can't pay it
+		assert_noop!(
+			Kontracts::execute_code(
+				RuntimeOrigin::signed(acc),
+				kontract_id,
+				0,
+				u32::MAX,
+				u32::MAX,
+				u32::MAX,
+				None,
+				None,
+				71,
+				BoundedVec::default(),
+			),
+			pallet_balances::Error::<Test>::InsufficientBalance
+		);
+	});
+}
+
+#[test]
+fn execute_code_with_invalid_dependencies() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		let acc = 1;
+
+		let (wasm_binary, code_id): (_, <Test as crate::Config>::CodeId) =
+			load_wasm::<Test>("empty_execution").unwrap();
+		assert_ok!(Kontracts::upload_code(
+			RuntimeOrigin::signed(acc),
+			BoundedVec::try_from(wasm_binary).expect("Code too big"),
+		));
+		let kontract_id = instantiate_kontract(acc, code_id);
+
+		let execute = |dependency| {
+			Kontracts::execute_code(
+				RuntimeOrigin::signed(acc),
+				kontract_id,
+				0,
+				u32::MAX,
+				u32::MAX,
+				u32::MAX,
+				None,
+				None,
+				0,
+				BoundedVec::try_from(vec![dependency]).expect("Too many dependencies"),
+			)
+		};
+
+		assert_noop!(
+			execute(DeclaredDependency::Kontract(sp_core::H256::zero())),
+			Error::<Test>::InvalidKontractId
+		);
+		assert_noop!(
+			execute(DeclaredDependency::Library(sp_core::H256::zero())),
+			Error::<Test>::InvalidCodeId
+		);
+
+		let dependency = Kontracts::kontract_id(&code_id, &(acc + 1), &[]);
+		crate::Kontracts::<Test>::insert(
+			dependency,
+			crate::KontractInfo { code_id, deployer: acc + 1, initialized: false },
+		);
+		assert_noop!(
+			execute(DeclaredDependency::Kontract(dependency)),
+			Error::<Test>::KontractNotInitialized
+		);
+
+		// A declared dependency that is never called is not modified
+		crate::Kontracts::<Test>::mutate(dependency, |kontract| {
+			kontract.as_mut().expect("Kontract not instantiated").initialized = true
+		});
+		assert_ok!(execute(DeclaredDependency::Kontract(dependency)));
+		assert_ok!(execute(DeclaredDependency::Library(code_id)));
+		assert!(!crate::Storages::<Test>::contains_key(dependency));
+		assert_eq!(0, Balances::reserved_balance(acc));
+	});
+}
+
+#[test]
+fn execute_code_refund_unused_weight() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		let acc = 1;
+
+		let (wasm_binary, code_id): (_, <Test as crate::Config>::CodeId) =
+			load_wasm::<Test>("empty_execution").unwrap();
+		assert_ok!(Kontracts::upload_code(
+			RuntimeOrigin::signed(acc),
+			BoundedVec::try_from(wasm_binary).expect("Code too big"),
+		));
+		let kontract_id = instantiate_kontract(acc, code_id);
+
+		let post_info = Kontracts::execute_code(
+			RuntimeOrigin::signed(acc),
+			kontract_id,
+			0,
+			1_000,
+			10,
+			10,
+			None,
+			None,
+			0,
+			BoundedVec::default(),
+		)
+		.expect("Impossible execute code");
+
+		// The kontract dispatched nothing and called no extension, so all the weight
+		// reserved for the dispatched calls and the repeated executions is refunded
+		let max_extension_calls = <Test as crate::Config>::MaxExtensionCalls::get() as u64;
+		let schedule = crate::Schedule::<Test>::get();
+		assert_eq!(
+			Some(
+				Kontracts::execute_code_weight(&schedule, 1_000, 10, 10, None, None, 0) -
+					MaxDispatchWeight::get() -
+					Kontracts::fuel_weight(&schedule, 1_000) * max_extension_calls
+			),
+			post_info.actual_weight
+		);
+	});
+}
+
+#[test]
+fn storage_entries_recover_keys() {
+	new_test_ext().execute_with(|| {
+		let acc = 1;
+		KontractsKeyHashing::set(KeyHashing::Blake2Concat);
+
+		// The key is 16B hash + 13B key instead of the 32B hash
+		let res_execution = write_B_kontract!("write_8B", acc, 44, 44);
+		assert_ok!(res_execution);
+		assert_eq!(44, Balances::reserved_balance(acc));
+
+		let (_, code_id) = load_wasm::<Test>("write_8B").unwrap();
+		let kontract_id = Kontracts::kontract_id(&code_id, &acc, &[]);
+		assert_eq!(
+			vec![(Some(vec![1u32, 2u32, 3u32].encode()), vec![4u32, 5u32, 6u32].encode())],
+			Kontracts::storage_entries(&kontract_id)
+		);
+	});
+}
+
+#[test]
+fn storage_entries_with_hashed_keys() {
+	new_test_ext().execute_with(|| {
+		let acc = 1;
+		let res_execution = write_B_kontract!("write_8B", acc, 47, 47);
+		assert_ok!(res_execution);
+
+		let (_, code_id) = load_wasm::<Test>("write_8B").unwrap();
+		let kontract_id = Kontracts::kontract_id(&code_id, &acc, &[]);
+		assert_eq!(
+			vec![(None, vec![4u32, 5u32, 6u32].encode())],
+			Kontracts::storage_entries(&kontract_id)
+		);
+	});
+}
+
+fn execute_wat_kontract(name: &str) -> DispatchResult {
+	execute_wat_kontract_with_bytes(name, None, None)
+}
+
+fn execute_wat_kontract_with_bytes(
+	name: &str,
+	max_read_bytes: Option<u32>,
+	max_write_bytes: Option<u32>,
+) -> DispatchResult {
+	let account = 1;
+	let (code, code_id) = load_wat::<Test>(name).unwrap();
+
+	Kontracts::upload_code(
+		RuntimeOrigin::signed(account),
+		BoundedVec::try_from(code).expect("Code too big"),
+	)
+	.expect("Impossible upload code");
+	let kontract_id = instantiate_kontract(account, code_id);
+
+	Kontracts::execute_code(
+		RuntimeOrigin::signed(account),
+		kontract_id,
+		i32::MAX,
+		u32::MAX,
+		u32::MAX,
+		u32::MAX,
+		max_read_bytes,
+		max_write_bytes,
+		0,
+		BoundedVec::default(),
+	)
+	.map(|_| ())
+	.map_err(|e| e.error)
+}
+
+#[test]
+fn memory_access_out_of_bounds() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+
+		assert_eq!(
+			Err(Error::<Test>::ExecutionCode(
+				kontracts_executor::ExecutionErrors::MemoryOutOfBounds
+			)
+			.into()),
+			execute_wat_kontract("memory_out_of_bounds")
+		);
+	});
+}
+
+#[test]
+fn encoded_value_bigger_than_buffer() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+
+		assert_eq!(
+			Err(Error::<Test>::ExecutionCode(kontracts_executor::ExecutionErrors::BufferTooSmall)
+				.into()),
+			execute_wat_kontract("buffer_too_small")
+		);
+	});
+}
+
+#[test]
+fn write_value_too_large() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+
+		assert_eq!(
+			Err(Error::<Test>::ValueTooLarge.into()),
+			execute_wat_kontract("value_too_large")
+		);
+	});
+}
+
+#[test]
+fn write_key_too_large() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		// With the hash the stored key would always be 32 bytes
+		KontractsKeyHashing::set(KeyHashing::Identity);
+
+		assert_eq!(Err(Error::<Test>::KeyTooLarge.into()), execute_wat_kontract("key_too_large"));
+	});
+}
+
+#[test]
+fn write_too_many_entries() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+
+		assert_eq!(Err(Error::<Test>::StorageFull.into()), execute_wat_kontract("storage_full"));
+	});
+}
+
+#[test]
+fn storage_usage_tracked() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		Balances::set_balance(RuntimeOrigin::root(), 1, 1_000, 0).expect("Impossible set balance");
+
+		assert_ok!(execute_wat_kontract("two_values"));
+
+		let (_, code_id) = load_wat::<Test>("two_values").unwrap();
+		let kontract_id = Kontracts::kontract_id(&code_id, &1, &[]);
+		// Two hashed keys of 32 bytes and two values of 300 bytes
+		assert_eq!(
+			crate::StorageUsage { bytes: 664, entries: 2 },
+			crate::StorageUsageOf::<Test>::get(kontract_id)
+		);
+	});
+}
+
+#[test]
+fn write_too_many_bytes() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		MaxStorageBytes::set(600);
+
+		assert_eq!(Err(Error::<Test>::StorageFull.into()), execute_wat_kontract("two_values"));
+	});
+}
+
+#[test]
+fn write_too_many_bytes_in_execution() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+
+		// The kontract writes two values of 300 bytes
+		assert_eq!(
+			Err(Error::<Test>::ExecutionCode(kontracts_executor::ExecutionErrors::OutOfWriteBytes)
+				.into()),
+			execute_wat_kontract_with_bytes("two_values", None, Some(599))
+		);
+	});
+}
+
+#[test]
+fn write_bytes_within_limit() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		Balances::set_balance(RuntimeOrigin::root(), 1, 1_000, 0).expect("Impossible set balance");
+
+		assert_ok!(execute_wat_kontract_with_bytes("two_values", Some(0), Some(600)));
+	});
+}
+
+#[test]
+fn execute_code_weight_per_byte() {
+	new_test_ext().execute_with(|| {
+		let schedule = crate::Schedule::<Test>::get();
+		// Without explicit limits every write can move a value of the max size (512 bytes)
+		assert_eq!(
+			Kontracts::execute_code_weight(&schedule, 1_000, 0, 2, None, None, 0),
+			Kontracts::execute_code_weight(&schedule, 1_000, 0, 2, None, Some(1024), 0)
+		);
+		assert_eq!(
+			Kontracts::execute_code_weight(&schedule, 1_000, 0, 2, None, Some(1024), 0),
+			Kontracts::execute_code_weight(&schedule, 1_000, 0, 2, None, Some(24), 0) +
+				Kontracts::bytes_weight(&schedule, (0, 1000))
+		);
+	});
+}
+
+#[test]
+fn host_functions_consume_fuel() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		Balances::set_balance(RuntimeOrigin::root(), 1, 1_000, 0).expect("Impossible set balance");
+
+		// Two calls to `set` cost more than all the fuel given by `execute_wat_kontract`
+		KontractsHostFnCosts::set(HostFnCosts { base: u32::MAX as u64 / 2 + 1, per_byte: 0 });
+		assert_eq!(
+			Err(Error::<Test>::ExecutionCode(kontracts_executor::ExecutionErrors::OutOfFuel).into()),
+			execute_wat_kontract("two_values")
+		);
+	});
+}
+
+#[test]
+fn host_functions_consume_fuel_per_byte() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		Balances::set_balance(RuntimeOrigin::root(), 1, 1_000, 0).expect("Impossible set balance");
+
+		// Keys and values copied by the two calls to `set` are 608 bytes
+		KontractsHostFnCosts::set(HostFnCosts { base: 0, per_byte: u32::MAX as u64 / 600 });
+		assert_eq!(
+			Err(Error::<Test>::ExecutionCode(kontracts_executor::ExecutionErrors::OutOfFuel).into()),
+			execute_wat_kontract("two_values")
+		);
+	});
+}
+
+#[test]
+fn host_functions_fuel_per_byte_within_limit() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		Balances::set_balance(RuntimeOrigin::root(), 1, 1_000, 0).expect("Impossible set balance");
+
+		KontractsHostFnCosts::set(HostFnCosts { base: 0, per_byte: u32::MAX as u64 / 700 });
+		assert_ok!(execute_wat_kontract("two_values"));
+	});
+}
+
+#[test]
+fn schedule_defaults_to_config() {
+	new_test_ext().execute_with(|| {
+		CodeDepositPerByte::set(3);
+
+		let schedule = crate::Schedule::<Test>::get();
+		assert_eq!(3, schedule.code_deposit_per_byte);
+		assert_eq!(1, schedule.storage_deposit_per_byte);
+		assert_eq!(KontractsHostFnCosts::get(), schedule.host_fn_costs);
+		assert_eq!(MaxStorageBytes::get(), schedule.storage_limits.max_bytes);
+		assert_eq!(
+			Kontracts::fuel_weight(&schedule, 1),
+			Weight::from_parts(schedule.fuel_weight, 0)
+		);
+	});
+}
+
+#[test]
+fn set_schedule_only_root() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+
+		let schedule = crate::Schedule::<Test>::get();
+		assert_noop!(
+			Kontracts::set_schedule(RuntimeOrigin::signed(1), schedule.clone()),
+			sp_runtime::DispatchError::BadOrigin
+		);
+
+		assert_ok!(Kontracts::set_schedule(RuntimeOrigin::root(), schedule));
+		System::assert_last_event(Event::ScheduleUpdated.into());
+	});
+}
+
+#[test]
+fn set_schedule_limits_bounded_by_config() {
+	new_test_ext().execute_with(|| {
+		let mut schedule = crate::Schedule::<Test>::get();
+		schedule.storage_limits.max_entries += 1;
+
+		assert_noop!(
+			Kontracts::set_schedule(RuntimeOrigin::root(), schedule),
+			Error::<Test>::InvalidSchedule
+		);
+	});
+}
+
+#[test]
+fn schedule_changes_deposits_and_weights() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+
+		let mut schedule = crate::Schedule::<Test>::get();
+		schedule.code_deposit_per_byte = 2;
+		schedule.fuel_weight = 7;
+		assert_ok!(Kontracts::set_schedule(RuntimeOrigin::root(), schedule));
+
+		// The config is not used anymore
+		CodeDepositPerByte::set(1);
+		assert_eq!(20, Kontracts::code_deposit(10));
+		assert_eq!(
+			Weight::from_parts(7_000, 0),
+			Kontracts::fuel_weight(&crate::Schedule::<Test>::get(), 1_000)
+		);
+	});
+}
+
+#[test]
+fn schedule_storage_limits_used_by_executor() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		Balances::set_balance(RuntimeOrigin::root(), 1, 1_000, 0).expect("Impossible set balance");
+
+		let mut schedule = crate::Schedule::<Test>::get();
+		schedule.storage_limits.max_bytes = 600;
+		assert_ok!(Kontracts::set_schedule(RuntimeOrigin::root(), schedule));
+
+		assert_eq!(Err(Error::<Test>::StorageFull.into()), execute_wat_kontract("two_values"));
+	});
+}
+
+#[test]
+fn instrumented_code_executed() {
+	new_test_ext().execute_with(|| {
+		KontractsMetering::set(crate::Metering::Instrumented);
+		let acc = 1;
+
+		let (wasm_binary, code_id): (_, <Test as crate::Config>::CodeId) =
+			load_wasm::<Test>("write_8B").unwrap();
+		let res_execution = write_B_kontract!("write_8B", acc, 47, 47);
+
+		// The code id is the hash of the uploaded code, but the stored one calls `env.gas`
+		let stored_code = crate::Codes::<Test>::get(code_id).expect("Code not stored");
+		assert!(stored_code.len() > wasm_binary.len());
+		assert!(stored_code.windows(3).any(|name| name == b"gas"));
+
+		assert_ok!(res_execution);
+		assert_eq!(47, Balances::reserved_balance(acc));
+	});
+}
+
+#[test]
+fn code_executed_with_metering_of_upload() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		let acc = 1;
+		Balances::set_balance(RuntimeOrigin::root(), acc, 1_000, 0)
+			.expect("Impossible set balance");
+		KontractsMetering::set(crate::Metering::Instrumented);
+		let (code_id, kontract_id) = instantiate_wat_kontract(acc, "get_versions");
+		assert_eq!(
+			Some(crate::Metering::Instrumented),
+			crate::CodeInfoOf::<Test>::get(code_id).map(|info| info.metering)
+		);
+
+		// The new metering is only for the new codes
+		KontractsMetering::set(crate::Metering::Engine);
+		assert_ok!(execute_with_dependencies(acc, kontract_id, 1_000_000, 10, vec![]));
+		let params = MockExecutor::executions().last().expect("No execution").clone();
+		assert_eq!(crate::Metering::Instrumented, params.metering);
+	});
+}
+
+#[test]
+fn dependency_with_different_metering() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		let acc = 1;
+		Balances::set_balance(RuntimeOrigin::root(), acc, 1_000, 0)
+			.expect("Impossible set balance");
+		let (_, caller) = instantiate_wat_kontract(acc, "caller");
+		KontractsMetering::set(crate::Metering::Instrumented);
+		let (library, callee) = instantiate_wat_kontract(acc, "callee");
+
+		for dependency in
+			[DeclaredDependency::Kontract(callee), DeclaredDependency::Library(library)]
+		{
+			assert_noop!(
+				execute_with_dependencies(acc, caller, 1_000_000, 10, vec![dependency]),
+				Error::<Test>::MeteringMismatch
+			);
+		}
+	});
+}
+
+#[test]
+fn instrumented_out_of_fuel() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		KontractsMetering::set(crate::Metering::Instrumented);
+
+		let account = 1;
+		let (wasm_binary, code_id): (_, <Test as crate::Config>::CodeId) =
+			load_wasm::<Test>("loop").unwrap();
+		Kontracts::upload_code(
+			RuntimeOrigin::signed(account),
+			BoundedVec::try_from(wasm_binary).expect("Code too big"),
+		)
+		.expect("Impossible upload code");
+		let kontract_id = instantiate_kontract(account, code_id);
+
+		// The gas injected in the loop is consumed at every iteration
+		assert_noop!(
+			Kontracts::execute_code(
+				RuntimeOrigin::signed(account),
+				kontract_id,
+				0,
+				10,
+				u32::MAX,
+				u32::MAX,
+				None,
+				None,
+				0,
+				BoundedVec::default()
+			),
+			Error::<Test>::ExecutionCode(kontracts_executor::ExecutionErrors::OutOfFuel)
+		);
+	});
+}
+
+#[test]
+fn text_code_rejected() {
+	new_test_ext().execute_with(|| {
+		let wat = std::fs::read("wasm_test/two_values.wat").expect("Wrong path");
+
+		for metering in [crate::Metering::Engine, crate::Metering::Instrumented] {
+			KontractsMetering::set(metering);
+			assert_noop!(
+				Kontracts::upload_code(
+					RuntimeOrigin::signed(1),
+					BoundedVec::try_from(wat.clone()).expect("Code too big"),
+				),
+				Error::<Test>::InvalidCode
+			);
+		}
+	});
+}
+
+// Every kontract in `wasm_test` by name, the text ones are converted because wasmi
+// executes only the binaries
+fn wasm_test_codes() -> Vec<(String, Vec<u8>)> {
+	let mut codes: Vec<_> = std::fs::read_dir("wasm_test")
+		.expect("Missing wasm_test")
+		.filter_map(|entry| {
+			let path = entry.ok()?.path();
+			let code = match path.extension()?.to_str()? {
+				"wasm" => std::fs::read(&path).expect("Impossible read code"),
+				"wat" => wat::parse_file(&path).expect("Invalid wat"),
+				_ => return None,
+			};
+			Some((path.file_stem()?.to_str()?.to_owned(), code))
+		})
+		.collect();
+	codes.sort();
+	codes
+}
+
+// Execute the code with both the backends, the storage has the value expected by `read`
+fn execute_on_backends(
+	code: Vec<u8>,
+	metering: crate::Metering,
+) -> [Result<kontracts_executor::ExecutionOutcome, kontracts_executor::ExecutionErrors>; 2] {
+	let key = KeyHashing::Blake2.hash(&vec![1u32, 2u32, 3u32].encode());
+	let storage: std::collections::BTreeMap<Vec<u8>, Vec<u8>> =
+		[(key, vec![4u32, 5u32, 6u32].encode())].into();
+
+	let params = kontracts_executor::ExecutionParams {
+		abi_version: kontracts_executor::instrument::abi_version(&code[..])
+			.expect("Invalid imports"),
+		code,
+		storage: storage.encode(),
+		storage_usage: crate::StorageUsage::default(),
+		entry_point: kontracts_executor::EntryPoint::Main,
+		input: Vec::new(),
+		fuel: 10_000_000,
+		max_read: 100,
+		max_write: 100,
+		max_read_bytes: 10_000,
+		max_write_bytes: 10_000,
+		balance: 1_000,
+		kontract_id: 0u64.encode(),
+		dependencies: Vec::new(),
+		libraries: Vec::new(),
+		max_call_depth: 4,
+		allow_reentrancy: false,
+		key_hashing: KeyHashing::Blake2,
+		storage_limits: kontracts_executor::StorageLimits {
+			max_key_size: 512,
+			max_value_size: 512,
+			max_entries: 1024,
+			max_bytes: 64 * 1024,
+		},
+		host_fn_costs: HostFnCosts { base: 100, per_byte: 1 },
+		metering,
+		extension_outputs: Vec::new(),
+	};
+
+	[crate::Backend::Native, crate::Backend::Wasmi]
+		.map(|backend| kontracts_executor::execute(backend, params.clone()))
+}
+
+#[test]
+fn backends_same_results() {
+	new_test_ext().execute_with(|| {
+		for (name, code) in wasm_test_codes() {
+			// The fuel of the engines is not the same, only the results are compared
+			let [native, wasmi] =
+				execute_on_backends(code, crate::Metering::Engine).map(|result| {
+					result.map(|outcome| kontracts_executor::ExecutionOutcome {
+						fuel_consumed: 0,
+						..outcome
+					})
+				});
+			assert_eq!(native.encode(), wasmi.encode(), "Different results for {}", name);
+		}
+	});
+}
+
+#[test]
+fn backends_same_fuel() {
+	new_test_ext().execute_with(|| {
+		let costs = <crate::Schedule<Test>>::get().instruction_costs;
+
+		for (name, code) in wasm_test_codes() {
+			let code = kontracts_executor::instrument::inject_gas(&code, &costs)
+				.expect("Impossible instrument code");
+
+			let [native, wasmi] = execute_on_backends(code, crate::Metering::Instrumented);
+			assert_eq!(native.encode(), wasmi.encode(), "Different execution of {}", name);
+		}
+	});
+}
+
+#[test]
+#[should_panic(expected = "The Wasmi backend requires Metering::Instrumented")]
+fn wasmi_backend_requires_instrumented_metering() {
+	new_test_ext().execute_with(|| {
+		KontractsBackend::set(crate::Backend::Wasmi);
+		KontractsMetering::set(crate::Metering::Engine);
+		<Kontracts as Hooks<u64>>::integrity_test();
+	});
+}
+
+#[test]
+fn wasmi_backend_rejects_engine_metered_codes() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		let acc = 1;
+		Balances::set_balance(RuntimeOrigin::root(), acc, 1_000, 0)
+			.expect("Impossible set balance");
+		let (_, kontract_id) = instantiate_wat_kontract(acc, "get_versions");
+
+		// Uploaded with the metering of wasmtime before the switch of the backend
+		KontractsBackend::set(crate::Backend::Wasmi);
+		KontractsMetering::set(crate::Metering::Instrumented);
+		assert_noop!(
+			execute_with_dependencies(acc, kontract_id, 1_000_000, 10, vec![]),
+			Error::<Test>::UnsupportedMetering
+		);
+	});
+}
+
+#[test]
+fn wasmi_backend_executes_kontracts() {
+	new_test_ext().execute_with(|| {
+		KontractsBackend::set(crate::Backend::Wasmi);
+		KontractsMetering::set(crate::Metering::Instrumented);
+		let acc = 1;
+
+		assert_ok!(write_B_kontract!("write_8B", acc, 47, 47));
+
+		let (_, code_id) = load_wasm::<Test>("write_8B").unwrap();
+		let kontract_id = Kontracts::kontract_id(&code_id, &acc, &[]);
+		assert_eq!(
+			vec![(None, vec![4u32, 5u32, 6u32].encode())],
+			Kontracts::storage_entries(&kontract_id)
+		);
+	});
+}
+
+// Kontract executed by the tests with a scripted executor, the code is never executed after
+// the instantiation
+fn instantiate_empty_kontract(account: u64) -> crate::KontractId<Test> {
+	let (wasm_binary, code_id): (_, <Test as crate::Config>::CodeId) =
+		load_wasm::<Test>("empty_execution").unwrap();
+	assert_ok!(Kontracts::upload_code(
+		RuntimeOrigin::signed(account),
+		BoundedVec::try_from(wasm_binary).expect("Code too big"),
+	));
+	instantiate_kontract(account, code_id)
+}
+
+// Outcome of an execution that leaves the kontract with `storage`
+fn scripted_outcome(storage: Vec<(Vec<u8>, Vec<u8>)>) -> kontracts_executor::ExecutionOutcome {
+	let storage_usage = crate::StorageUsage {
+		bytes: storage.iter().map(|(key, value)| (key.len() + value.len()) as u32).sum(),
+		entries: storage.len() as u32,
+	};
+	kontracts_executor::ExecutionOutcome {
+		storage: storage.into_iter().collect::<std::collections::BTreeMap<_, _>>().encode(),
+		storage_usage,
+		transfers: Vec::new(),
+		calls: Vec::new(),
+		dependencies: Vec::new(),
+		pending_extension: None,
+		fuel_consumed: 0,
+	}
+}
+
+fn execute_scripted(account: u64, kontract_id: crate::KontractId<Test>) -> DispatchResult {
+	Kontracts::execute_code(
+		RuntimeOrigin::signed(account),
+		kontract_id,
+		100,
+		1_000,
+		10,
+		10,
+		None,
+		None,
+		0,
+		BoundedVec::default(),
+	)
+	.map(|_| ())
+	.map_err(|e| e.error)
+}
+
+#[test]
+fn scripted_storage_reserves_deposit() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		let acc = 1;
+		Balances::set_balance(RuntimeOrigin::root(), acc, 1_000, 0)
+			.expect("Impossible set balance");
+		let kontract_id = instantiate_empty_kontract(acc);
+
+		MockExecutor::script(Ok(scripted_outcome(vec![([7u8; 32].to_vec(), vec![1, 2, 3, 4])])));
+		assert_ok!(execute_scripted(acc, kontract_id));
+
+		// Key and value with their compact length
+		assert_eq!(38, Balances::reserved_balance(acc));
+		assert_eq!(vec![(None, vec![1, 2, 3, 4])], Kontracts::storage_entries(&kontract_id));
+		assert_eq!(
+			crate::StorageUsage { bytes: 36, entries: 1 },
+			crate::StorageUsageOf::<Test>::get(kontract_id)
+		);
+		System::assert_last_event(Event::<Test>::KontractExecuted { kontract_id, who: acc }.into());
+	});
+}
+
+#[test]
+fn scripted_error_returned() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		let acc = 1;
+		let kontract_id = instantiate_empty_kontract(acc);
+
+		MockExecutor::script(Err(kontracts_executor::ExecutionErrors::StorageFull));
+		assert_noop!(execute_scripted(acc, kontract_id), Error::<Test>::StorageFull);
+
+		MockExecutor::script(Err(kontracts_executor::ExecutionErrors::OutOfFuel));
+		assert_noop!(
+			execute_scripted(acc, kontract_id),
+			Error::<Test>::ExecutionCode(kontracts_executor::ExecutionErrors::OutOfFuel)
+		);
+	});
+}
+
+#[test]
+fn scripted_extension_executed_again() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		let acc = 1;
+		let kontract_id = instantiate_empty_kontract(acc);
+
+		MockExecutor::script(Ok(kontracts_executor::ExecutionOutcome {
+			pending_extension: Some(kontracts_executor::ExtensionRequest {
+				kontract_id: kontract_id.encode(),
+				kind: kontracts_executor::RequestKind::Random,
+				input: b"subject".to_vec(),
+			}),
+			..scripted_outcome(Vec::new())
+		}));
+		MockExecutor::script(Ok(scripted_outcome(Vec::new())));
+		assert_ok!(execute_scripted(acc, kontract_id));
+
+		// The second execution knows the random value requested by the first one
+		let executions = MockExecutor::executions();
+		let random =
+			TestRandomness::random(b"subject").0.using_encoded(sp_core::hashing::blake2_256);
+		assert_eq!(vec![random.to_vec()], executions[executions.len() - 1].extension_outputs);
+		assert!(executions[executions.len() - 2].extension_outputs.is_empty());
+	});
+}
+
+fn upload_wat_binary(account: u64, wat: &str) -> DispatchResult {
+	let code = wat::parse_str(wat).expect("Invalid wat");
+	Kontracts::upload_code(
+		RuntimeOrigin::signed(account),
+		BoundedVec::try_from(code).expect("Code too big"),
+	)
+}
+
+#[test]
+fn upload_code_records_abi_version() {
+	new_test_ext().execute_with(|| {
+		let acc = 1;
+
+		let code = wat::parse_file("wasm_test/get_versions.wat").expect("Invalid wat");
+		let code_id = <Test as crate::Config>::HashingAlgorith::hash(&code);
+		assert_ok!(Kontracts::upload_code(
+			RuntimeOrigin::signed(acc),
+			BoundedVec::try_from(code).expect("Code too big"),
+		));
+		assert_eq!(
+			Some(AbiVersion::V1),
+			crate::CodeInfoOf::<Test>::get(code_id).map(|info| info.abi_version)
+		);
+
+		let (code, code_id) = load_wat::<Test>("two_values").unwrap();
+		assert_ok!(Kontracts::upload_code(
+			RuntimeOrigin::signed(acc),
+			BoundedVec::try_from(code).expect("Code too big"),
+		));
+		assert_eq!(
+			Some(AbiVersion::V0),
+			crate::CodeInfoOf::<Test>::get(code_id).map(|info| info.abi_version)
+		);
+
+		assert_eq!(
+			Err(Error::<Test>::InvalidCode.into()),
+			upload_wat_binary(
+				acc,
+				r#"(module
+					(import "env" "set" (func (param i32 i32 i32 i32)))
+					(import "kontracts_v1" "get" (func (param i32 i32 i32 i32) (result i32)))
+				)"#
+			)
+		);
+		assert_eq!(
+			Err(Error::<Test>::InvalidCode.into()),
+			upload_wat_binary(acc, r#"(module (import "kontracts_v9" "get" (func)))"#)
+		);
+	});
+}
+
+#[test]
+fn get_v1_returns_value_len() {
+	new_test_ext().execute_with(|| {
+		let code = wat::parse_file("wasm_test/get_versions.wat").expect("Invalid wat");
+
+		for result in execute_on_backends(code, crate::Metering::Engine) {
+			let outcome = result.ok().expect("Execution failed");
+			let storage =
+				std::collections::BTreeMap::<Vec<u8>, Vec<u8>>::decode(&mut &outcome.storage[..])
+					.expect("Invalid storage");
+			assert_eq!(
+				Some(
+					&[&3u32.to_le_bytes()[..], &b"abc\0"[..], &u32::MAX.to_le_bytes()[..]].concat()
+				),
+				storage.get(&KeyHashing::Blake2.hash(b"key2"))
+			);
+		}
+	});
+}
+
+#[test]
+fn execution_links_only_recorded_abi_version() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		let acc = 1;
+		Balances::set_balance(RuntimeOrigin::root(), acc, 1_000, 0)
+			.expect("Impossible set balance");
+		let (code_id, kontract_id) = instantiate_wat_kontract(acc, "get_versions");
+
+		assert_ok!(execute_with_dependencies(acc, kontract_id, 1_000_000, 10, vec![]));
+		let params = MockExecutor::executions().last().expect("No execution").clone();
+		assert_eq!(AbiVersion::V1, params.abi_version);
+
+		// The functions of `kontracts_v1` are not linked to a code recorded as `V0`
+		crate::CodeInfoOf::<Test>::mutate(code_id, |info| {
+			info.as_mut().expect("Missing code info").abi_version = AbiVersion::V0
+		});
+		assert_noop!(
+			execute_with_dependencies(acc, kontract_id, 1_000_000, 10, vec![]),
+			Error::<Test>::ExecutionCode(
+				kontracts_executor::ExecutionErrors::ImpossibleCreateInstance
+			)
+		);
+	});
+}
+
+#[test]
+fn legacy_modules_same_functions() {
+	new_test_ext().execute_with(|| {
+		for entry in std::fs::read_dir("wasm_test").expect("Missing wasm_test") {
+			let path = entry.expect("Invalid entry").path();
+			if path.extension().and_then(|ext| ext.to_str()) != Some("wat") {
+				continue
+			}
+			let wat = std::fs::read_to_string(&path).expect("Impossible read code");
+
+			// `env` and `kontracts_v0` are the same version of the host functions
+			let env = wat::parse_str(&wat).expect("Invalid wat");
+			let v0 =
+				wat::parse_str(wat.replace("\"env\"", "\"kontracts_v0\"")).expect("Invalid wat");
+			assert_eq!(
+				execute_on_backends(env, crate::Metering::Engine).encode(),
+				execute_on_backends(v0, crate::Metering::Engine).encode(),
+				"Different execution of {:?}",
+				path
+			);
+		}
+	});
+}
+
+// Upload the kontract written in the text format (if not yet uploaded) and instantiate it
+fn instantiate_wat_kontract(
+	account: u64,
+	name: &str,
+) -> (<Test as crate::Config>::CodeId, crate::KontractId<Test>) {
+	let (code, code_id) = load_wat::<Test>(name).unwrap();
+	if !crate::Codes::<Test>::contains_key(code_id) {
+		assert_ok!(Kontracts::upload_code(
+			RuntimeOrigin::signed(account),
+			BoundedVec::try_from(code).expect("Code too big"),
+		));
+	}
+	(code_id, instantiate_kontract(account, code_id))
+}
+
+// Write directly in the storage of the kontract, without any deposit
+fn insert_storage(kontract_id: crate::KontractId<Test>, key: &[u8], value: Vec<u8>) {
+	crate::Storages::<Test>::mutate(kontract_id, |storage| {
+		storage
+			.try_insert(
+				key_hashed::<Test>(key.to_vec()),
+				value.try_into().expect("Impossible create bounded vec"),
+			)
+			.expect("Impossible insert element in the map");
+	});
+}
+
+fn storage_value(kontract_id: crate::KontractId<Test>, key: &[u8]) -> Option<Vec<u8>> {
+	crate::Storages::<Test>::get(kontract_id)
+		.get(&key_hashed::<Test>(key.to_vec()))
+		.map(|value| value.to_vec())
+}
+
+fn execute_with_dependencies(
+	account: u64,
+	kontract_id: crate::KontractId<Test>,
+	fuel: u32,
+	max_write: u32,
+	dependencies: Vec<DeclaredDependency<Test>>,
+) -> DispatchResult {
+	Kontracts::execute_code(
+		RuntimeOrigin::signed(account),
+		kontract_id,
+		i32::MAX,
+		fuel,
+		10,
+		max_write,
+		None,
+		None,
+		0,
+		BoundedVec::try_from(dependencies).expect("Too many dependencies"),
+	)
+	.map(|_| ())
+	.map_err(|e| e.error)
+}
+
+#[test]
+fn call_declared_kontract() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		let acc = 1;
+		Balances::set_balance(RuntimeOrigin::root(), acc, 1_000, 0)
+			.expect("Impossible set balance");
+
+		let (_, caller) = instantiate_wat_kontract(acc, "caller");
+		let (_, callee) = instantiate_wat_kontract(acc, "callee");
+		insert_storage(caller, b"callee", callee.encode());
+
+		assert_ok!(execute_with_dependencies(
+			acc,
+			caller,
+			10_000_000,
+			10,
+			vec![DeclaredDependency::Kontract(callee)]
+		));
+
+		// The output of the callee is stored by the caller
+		assert_eq!(Some(b"pong".to_vec()), storage_value(caller, b"output"));
+		assert_eq!(Some(b"yes".to_vec()), storage_value(callee, b"called"));
+		// The user pays the storage of both
+		let callee_deposit = crate::StorageDeposits::<Test>::get(callee, acc);
+		assert!(callee_deposit > 0);
+		assert_eq!(
+			crate::StorageDeposits::<Test>::get(caller, acc) + callee_deposit,
+			Balances::reserved_balance(acc)
+		);
+
+		// 1_000_000 is forwarded to the callee, what is not consumed is given back
+		let params = MockExecutor::executions().last().expect("No execution").clone();
+		let outcome = kontracts_executor::execute(crate::Backend::Native, params)
+			.ok()
+			.expect("Execution failed");
+		assert!(outcome.fuel_consumed < 1_000_000);
+	});
+}
+
+#[test]
+fn call_forwarding_more_fuel_than_available() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		let acc = 1;
+		let (_, caller) = instantiate_wat_kontract(acc, "caller");
+		let (_, callee) = instantiate_wat_kontract(acc, "callee");
+		insert_storage(caller, b"callee", callee.encode());
+
+		assert_noop!(
+			execute_with_dependencies(
+				acc,
+				caller,
+				500_000,
+				10,
+				vec![DeclaredDependency::Kontract(callee)]
+			),
+			Error::<Test>::ExecutionCode(kontracts_executor::ExecutionErrors::OutOfFuel)
+		);
+	});
+}
+
+#[test]
+fn call_committed_only_with_the_caller() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		let acc = 1;
+		Balances::set_balance(RuntimeOrigin::root(), acc, 1_000, 0)
+			.expect("Impossible set balance");
+
+		let (_, caller) = instantiate_wat_kontract(acc, "caller");
+		let (_, callee) = instantiate_wat_kontract(acc, "callee");
+		insert_storage(caller, b"callee", callee.encode());
+
+		// The callee writes successfully, then the caller is out of writes
+		assert_noop!(
+			execute_with_dependencies(
+				acc,
+				caller,
+				10_000_000,
+				1,
+				vec![DeclaredDependency::Kontract(callee)]
+			),
+			Error::<Test>::ExecutionCode(kontracts_executor::ExecutionErrors::OutOfWrites)
+		);
+		assert_eq!(None, storage_value(callee, b"called"));
+		assert_eq!(0, crate::StorageDeposits::<Test>::get(callee, acc));
+		assert_eq!(0, Balances::reserved_balance(acc));
+	});
+}
+
+#[test]
+fn call_itself() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		let acc = 1;
+		let (_, caller) = instantiate_wat_kontract(acc, "caller");
+		insert_storage(caller, b"callee", caller.encode());
+
+		assert_noop!(
+			execute_with_dependencies(acc, caller, 10_000_000, 10, vec![]),
+			Error::<Test>::ExecutionCode(kontracts_executor::ExecutionErrors::ReentrancyDenied)
+		);
+
+		// With the reentrancy the kontract calls itself until the max depth
+		KontractsAllowReentrancy::set(true);
+		assert_noop!(
+			execute_with_dependencies(acc, caller, 10_000_000, 10, vec![]),
+			Error::<Test>::ExecutionCode(kontracts_executor::ExecutionErrors::MaxCallDepthReached)
+		);
+	});
+}
+
+#[test]
+fn read_declared_kontract() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		let acc = 1;
+		Balances::set_balance(RuntimeOrigin::root(), acc, 1_000, 0)
+			.expect("Impossible set balance");
+
+		let (_, reader) = instantiate_wat_kontract(acc, "foreign_reader");
+		let (_, target) = instantiate_wat_kontract(acc, "callee");
+		insert_storage(reader, b"target", target.encode());
+		insert_storage(target, b"called", b"yes".to_vec());
+
+		// Not declared, the storage of the target is not loaded
+		assert_noop!(
+			execute_with_dependencies(acc, reader, 1_000_000, 10, vec![]),
+			Error::<Test>::ExecutionCode(kontracts_executor::ExecutionErrors::KontractNotDeclared)
+		);
+
+		// The read of the other kontract is counted with the reads of the kontract
+		assert_noop!(
+			Kontracts::execute_code(
+				RuntimeOrigin::signed(acc),
+				reader,
+				i32::MAX,
+				1_000_000,
+				1,
+				10,
+				None,
+				None,
+				0,
+				BoundedVec::try_from(vec![DeclaredDependency::Kontract(target)])
+					.expect("Too many dependencies"),
+			)
+			.map_err(|e| e.error),
+			Error::<Test>::ExecutionCode(kontracts_executor::ExecutionErrors::OutOfReads)
+		);
+
+		assert_ok!(execute_with_dependencies(
+			acc,
+			reader,
+			1_000_000,
+			10,
+			vec![DeclaredDependency::Kontract(target)]
+		));
+		assert_eq!(Some(b"yes".to_vec()), storage_value(reader, b"foreign"));
+		// Only read, the target is unchanged
+		assert_eq!(0, crate::StorageDeposits::<Test>::get(target, acc));
+	});
+}
+
+#[test]
+fn read_foreign_values_of_any_size() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		let acc = 1;
+		Balances::set_balance(RuntimeOrigin::root(), acc, 10_000, 0)
+			.expect("Impossible set balance");
+
+		let (wasm_binary, code_id): (_, <Test as crate::Config>::CodeId) =
+			load_wasm::<Test>("read_foreign").unwrap();
+		assert_ok!(Kontracts::upload_code(
+			RuntimeOrigin::signed(acc),
+			BoundedVec::try_from(wasm_binary).expect("Code too big"),
+		));
+		let reader = instantiate_kontract(acc, code_id);
+		let (_, target) = instantiate_wat_kontract(acc, "callee");
+		insert_storage(reader, b"target", target.encode());
+		let dependencies = vec![DeclaredDependency::Kontract(target)];
+
+		// Not in the storage of the target
+		assert_ok!(execute_with_dependencies(acc, reader, 1_000_000, 10, dependencies.clone()));
+		assert_eq!(Some(vec![]), storage_value(reader, b"missing"));
+		assert_eq!(None, storage_value(reader, b"foreign"));
+
+		// Bigger than the first buffer of the kontract, read again with the returned size
+		insert_storage(target, b"called", vec![7; 300]);
+		assert_ok!(execute_with_dependencies(acc, reader, 1_000_000, 10, dependencies));
+		assert_eq!(Some(vec![7; 300]), storage_value(reader, b"foreign"));
+	});
+}
+
+#[test]
+fn delegate_call_declared_library() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		let acc = 1;
+		Balances::set_balance(RuntimeOrigin::root(), acc, 1_000, 0)
+			.expect("Impossible set balance");
+
+		// The library is only uploaded, it has no storage of its own
+		let (code, library) = load_wat::<Test>("library").unwrap();
+		assert_ok!(Kontracts::upload_code(
+			RuntimeOrigin::signed(acc),
+			BoundedVec::try_from(code).expect("Code too big"),
+		));
+		let (_, delegator) = instantiate_wat_kontract(acc, "delegator");
+		insert_storage(delegator, b"library", library.encode());
+
+		assert_noop!(
+			execute_with_dependencies(acc, delegator, 1_000_000, 10, vec![]),
+			Error::<Test>::ExecutionCode(kontracts_executor::ExecutionErrors::CodeNotDeclared)
+		);
+
+		assert_ok!(execute_with_dependencies(
+			acc,
+			delegator,
+			1_000_000,
+			10,
+			vec![DeclaredDependency::Library(library)]
+		));
+		// Written by the library in the storage of the delegator, that is paid by the user
+		assert_eq!(Some(b"delegated".to_vec()), storage_value(delegator, b"lib"));
+		assert_eq!(
+			crate::StorageDeposits::<Test>::get(delegator, acc),
+			Balances::reserved_balance(acc)
+		);
+		assert!(Balances::reserved_balance(acc) > 0);
+	});
+}
+
+// Kontract that dispatches `call`, its account has some balance to transfer
+fn instantiate_dispatch_kontract(account: u64, call: RuntimeCall) -> crate::KontractId<Test> {
+	let (_, kontract_id) = instantiate_wat_kontract(account, "dispatch");
+	insert_storage(kontract_id, b"call", call.encode());
+	Balances::set_balance(RuntimeOrigin::root(), Kontracts::kontract_account(&kontract_id), 100, 0)
+		.expect("Impossible set balance");
+	kontract_id
+}
+
+#[test]
+fn dispatch_call_from_kontract() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		let acc = 1;
+		let call = RuntimeCall::Balances(pallet_balances::Call::transfer { dest: 2, value: 10 });
+		let call_weight = call.get_dispatch_info().weight;
+		// More than needed, the unused part is refunded
+		MaxDispatchWeight::set(Weight::from_parts(call_weight.ref_time() + 1_000, u64::MAX));
+		let kontract_id = instantiate_dispatch_kontract(acc, call);
+
+		let post_info = Kontracts::execute_code(
+			RuntimeOrigin::signed(acc),
+			kontract_id,
+			0,
+			1_000_000,
+			10,
+			10,
+			None,
+			None,
+			0,
+			BoundedVec::default(),
+		)
+		.expect("Impossible execute code");
+
+		// Dispatched by the account of the kontract
+		assert_eq!(10, Balances::free_balance(2));
+		assert_eq!(90, Balances::free_balance(Kontracts::kontract_account(&kontract_id)));
+
+		let max_extension_calls = <Test as crate::Config>::MaxExtensionCalls::get() as u64;
+		let schedule = crate::Schedule::<Test>::get();
+		assert_eq!(
+			Some(
+				Kontracts::execute_code_weight(&schedule, 1_000_000, 10, 10, None, None, 0) -
+					MaxDispatchWeight::get() -
+					Kontracts::fuel_weight(&schedule, 1_000_000) * max_extension_calls +
+					call_weight
+			),
+			post_info.actual_weight
+		);
+	});
+}
+
+#[test]
+fn dispatch_call_rejected() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		let acc = 1;
+		let execute = |kontract_id| {
+			Kontracts::execute_code(
+				RuntimeOrigin::signed(acc),
+				kontract_id,
+				0,
+				1_000_000,
+				10,
+				10,
+				None,
+				None,
+				0,
+				BoundedVec::default(),
+			)
+			.map(|_| ())
+			.map_err(|e| e.error)
+		};
+
+		// The calls of the pallet are not accepted by the filter of the mock
+		let filtered = instantiate_dispatch_kontract(
+			acc,
+			RuntimeCall::Kontracts(crate::Call::delete_code { code_id: sp_core::H256::zero() }),
+		);
+		assert_err!(execute(filtered), Error::<Test>::CallFiltered);
+
+		let call = RuntimeCall::Balances(pallet_balances::Call::transfer { dest: 2, value: 10 });
+		let call_weight = call.get_dispatch_info().weight;
+		MaxDispatchWeight::set(Weight::from_parts(call_weight.ref_time() - 1, u64::MAX));
+		let too_heavy = instantiate_dispatch_kontract(acc + 1, call);
+		assert_err!(execute(too_heavy), Error::<Test>::DispatchWeightExceeded);
+		assert_eq!(0, Balances::free_balance(2));
+
+		// Not a call of the runtime
+		let (_, invalid) = instantiate_wat_kontract(acc + 2, "dispatch");
+		insert_storage(invalid, b"call", vec![255, 255, 255]);
+		assert_err!(execute(invalid), Error::<Test>::InvalidRuntimeCall);
+	});
+}
+
+fn execute_extension_kontract(kontract_id: crate::KontractId<Test>) -> DispatchResultWithPostInfo {
+	Kontracts::execute_code(
+		RuntimeOrigin::signed(1),
+		kontract_id,
+		i32::MAX,
+		1_000_000,
+		10,
+		10,
+		None,
+		None,
+		0,
+		BoundedVec::default(),
+	)
+}
+
+#[test]
+fn extension_refund_unused_executions() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		let acc = 1;
+		Balances::set_balance(RuntimeOrigin::root(), acc, 1_000, 0)
+			.expect("Impossible set balance");
+		let (_, kontract_id) = instantiate_wat_kontract(acc, "extension");
+		insert_storage(kontract_id, b"calls", vec![1]);
+
+		// Executed again with the output of the extension
+		let post_info = execute_extension_kontract(kontract_id).expect("Impossible execute code");
+		assert_eq!(Some(b"ping".to_vec()), storage_value(kontract_id, b"output"));
+
+		// The fuel is charged `MaxExtensionCalls + 1` times, two executions are used
+		let max_extension_calls = <Test as crate::Config>::MaxExtensionCalls::get() as u64;
+		let schedule = crate::Schedule::<Test>::get();
+		assert_eq!(
+			Some(
+				Kontracts::execute_code_weight(&schedule, 1_000_000, 10, 10, None, None, 0) -
+					MaxDispatchWeight::get() -
+					Kontracts::fuel_weight(&schedule, 1_000_000) * (max_extension_calls - 1)
+			),
+			post_info.actual_weight
+		);
+	});
+}
+
+#[test]
+fn too_many_extension_calls() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		let acc = 1;
+		Balances::set_balance(RuntimeOrigin::root(), acc, 1_000, 0)
+			.expect("Impossible set balance");
+		let (_, kontract_id) = instantiate_wat_kontract(acc, "extension");
+		let max_extension_calls = <Test as crate::Config>::MaxExtensionCalls::get() as u8;
+
+		insert_storage(kontract_id, b"calls", vec![max_extension_calls]);
+		assert_ok!(execute_extension_kontract(kontract_id));
+
+		insert_storage(kontract_id, b"calls", vec![max_extension_calls + 1]);
+		assert_noop!(
+			execute_extension_kontract(kontract_id).map_err(|e| e.error),
+			Error::<Test>::TooManyExtensionCalls
+		);
+	});
+}
+
+#[test]
+fn random_value_of_the_runtime() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		let acc = 1;
+		Balances::set_balance(RuntimeOrigin::root(), acc, 1_000, 0)
+			.expect("Impossible set balance");
+		let (_, kontract_id) = instantiate_wat_kontract(acc, "random");
+
+		assert_ok!(execute_with_dependencies(acc, kontract_id, 1_000_000, 10, vec![]));
+
+		let random =
+			TestRandomness::random(b"subject").0.using_encoded(sp_core::hashing::blake2_256);
+		assert_eq!(Some(random.to_vec()), storage_value(kontract_id, b"random"));
+	});
+}
+
+// Value of `key` with a `KeyHashing` that keeps the keys
+fn raw_storage_value(kontract_id: crate::KontractId<Test>, key: &[u8]) -> Option<Vec<u8>> {
+	Kontracts::storage_entries(&kontract_id)
+		.into_iter()
+		.find(|(raw_key, _)| raw_key.as_deref() == Some(key))
+		.map(|(_, value)| value)
+}
+
+fn check_next_key(key_hashing: KeyHashing) {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		let acc = 1;
+		KontractsKeyHashing::set(key_hashing);
+		Balances::set_balance(RuntimeOrigin::root(), acc, 1_000, 0)
+			.expect("Impossible set balance");
+		let (_, kontract_id) = instantiate_wat_kontract(acc, "next_key");
+
+		assert_ok!(execute_with_dependencies(acc, kontract_id, 1_000_000, 10, vec![]));
+
+		assert_eq!(Some(b"kakb".to_vec()), raw_storage_value(kontract_id, b"listed"));
+		assert_eq!(Some(vec![0]), raw_storage_value(kontract_id, b"first"));
+		assert_eq!(Some(b"a".to_vec()), raw_storage_value(kontract_id, b"second"));
+		assert_eq!(Some(vec![2]), raw_storage_value(kontract_id, b"needed"));
+	});
+}
+
+#[test]
+fn next_key_with_identity() {
+	check_next_key(KeyHashing::Identity);
+}
+
+#[test]
+fn next_key_with_blake2_concat() {
+	check_next_key(KeyHashing::Blake2Concat);
+}
+
+#[test]
+fn next_key_with_blake2() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		let acc = 1;
+		Balances::set_balance(RuntimeOrigin::root(), acc, 1_000, 0)
+			.expect("Impossible set balance");
+		let (_, kontract_id) = instantiate_wat_kontract(acc, "next_key");
+
+		assert_noop!(
+			execute_with_dependencies(acc, kontract_id, 1_000_000, 10, vec![]),
+			Error::<Test>::ExecutionCode(kontracts_executor::ExecutionErrors::KeysNotRecoverable)
+		);
+	});
+}
+
+#[test]
+fn key_hashing_fixed_by_first_execution() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		let acc = 1;
+		KontractsKeyHashing::set(KeyHashing::Identity);
+		Balances::set_balance(RuntimeOrigin::root(), acc, 1_000, 0)
+			.expect("Impossible set balance");
+		let (_, kontract_id) = instantiate_wat_kontract(acc, "random");
+		assert_eq!(None, crate::KeyHashingInUse::<Test>::get());
+
+		assert_ok!(execute_with_dependencies(acc, kontract_id, 1_000_000, 10, vec![]));
+		assert_eq!(Some(KeyHashing::Identity), crate::KeyHashingInUse::<Test>::get());
+
+		KontractsKeyHashing::set(KeyHashing::Blake2Concat);
+		assert_noop!(
+			execute_with_dependencies(acc, kontract_id, 1_000_000, 10, vec![]),
+			Error::<Test>::KeyHashingChanged
+		);
+		// The stored keys are still listed with the scheme used to write them
+		assert!(raw_storage_value(kontract_id, b"random").is_some());
+	});
+}
+
+#[test]
+fn raw_values_host_functions() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		let acc = 1;
+		Balances::set_balance(RuntimeOrigin::root(), acc, 1_000, 0)
+			.expect("Impossible set balance");
+		let (_, kontract_id) = instantiate_wat_kontract(acc, "raw_values");
+
+		assert_ok!(execute_with_dependencies(acc, kontract_id, 1_000_000, 10, vec![]));
+
+		assert_eq!(Some(b"hello world".to_vec()), storage_value(kontract_id, b"a"));
+		assert_eq!(
+			// contains_key before and after the append, value_len of "a" and of a missing key,
+			// get_range at the end, after the end and of a missing key, take with a small buffer
+			Some(vec![0, 1, 11, 0xff, 0, 0xfe, 0xff, 3]),
+			storage_value(kontract_id, b"results")
+		);
+		assert_eq!(Some(b"world".to_vec()), storage_value(kontract_id, b"range"));
+		assert_eq!(Some(b"abc".to_vec()), storage_value(kontract_id, b"taken"));
+		assert_eq!(None, storage_value(kontract_id, b"t"));
+	});
+}
+
+#[test]
+fn append_value_too_large() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		let acc = 1;
+		Balances::set_balance(RuntimeOrigin::root(), acc, 1_000, 0)
+			.expect("Impossible set balance");
+		let (_, kontract_id) = instantiate_wat_kontract(acc, "append_too_large");
+
+		assert_noop!(
+			execute_with_dependencies(acc, kontract_id, 1_000_000, 10, vec![]),
+			Error::<Test>::ValueTooLarge
+		);
+	});
+}
+
+#[test]
+fn migrate_storage_usage() {
+	new_test_ext().execute_with(|| {
+		StorageVersion::new(0).put::<Kontracts>();
+		let kontract_id = Kontracts::kontract_id(&Default::default(), &1, &[]);
+		insert_storage(kontract_id, b"key", vec![1, 2, 3]);
+		insert_storage(kontract_id, b"other", vec![]);
+		assert_eq!(crate::StorageUsage::default(), crate::StorageUsageOf::<Test>::get(kontract_id));
+
+		Kontracts::on_runtime_upgrade();
+
+		// Keys of 32 bytes, the hashes
+		assert_eq!(
+			crate::StorageUsage { bytes: 32 + 3 + 32, entries: 2 },
+			crate::StorageUsageOf::<Test>::get(kontract_id)
+		);
+		assert_eq!(StorageVersion::new(4), Kontracts::on_chain_storage_version());
+	});
+}
+
+#[test]
+fn migrate_code_info_abi_version() {
+	new_test_ext().execute_with(|| {
+		StorageVersion::new(1).put::<Kontracts>();
+		let code_id = <Test as crate::Config>::HashingAlgorith::hash(b"code");
+		// `CodeInfo` without the version: owner, deposit and refcount
+		frame_support::storage::unhashed::put(
+			&crate::CodeInfoOf::<Test>::hashed_key_for(code_id),
+			&(1u64, 10u64, 2u32),
+		);
+
+		Kontracts::on_runtime_upgrade();
+
+		assert_eq!(
+			Some(crate::CodeInfo {
+				owner: 1,
+				deposit: 10,
+				refcount: 2,
+				abi_version: AbiVersion::V0,
+				metering: crate::Metering::Engine
+			}),
+			crate::CodeInfoOf::<Test>::get(code_id)
+		);
+		assert_eq!(StorageVersion::new(4), Kontracts::on_chain_storage_version());
+	});
+}
+
+#[test]
+fn migrate_key_hashing_of_existing_storages() {
+	new_test_ext().execute_with(|| {
+		KontractsKeyHashing::set(KeyHashing::Blake2Concat);
+		StorageVersion::new(2).put::<Kontracts>();
+		Kontracts::on_runtime_upgrade();
+		// Without storages the scheme is chosen by the first execution
+		assert_eq!(None, crate::KeyHashingInUse::<Test>::get());
+
+		StorageVersion::new(2).put::<Kontracts>();
+		let kontract_id = Kontracts::kontract_id(&Default::default(), &1, &[]);
+		insert_storage(kontract_id, b"key", vec![1, 2, 3]);
+
+		Kontracts::on_runtime_upgrade();
+
+		// The keys already written were hashed with blake2
+		assert_eq!(Some(KeyHashing::Blake2), crate::KeyHashingInUse::<Test>::get());
+		assert_eq!(StorageVersion::new(4), Kontracts::on_chain_storage_version());
+	});
+}
+
+#[test]
+fn migrate_kontracts_before_kontract_info() {
+	new_test_ext().execute_with(|| {
+		System::set_block_number(1);
+		let acc = 1;
+		StorageVersion::new(0).put::<Kontracts>();
+		// Uploaded before the code infos, the storage is under the code id
+		let (wasm_binary, code_id): (_, <Test as crate::Config>::CodeId) =
+			load_wasm::<Test>("read").unwrap();
+		crate::Codes::<Test>::insert(
+			code_id,
+			BoundedVec::<u8, <Test as crate::Config>::MaxCodeSize>::try_from(wasm_binary)
+				.expect("Code too big"),
+		);
+		insert_storage(code_id, &vec![1u32, 2u32, 3u32].encode(), vec![4u32, 5u32, 6u32].encode());
+		assert_noop!(
+			execute_with_dependencies(acc, code_id, 1_000_000, 10, vec![]),
+			Error::<Test>::InvalidKontractId
+		);
+
+		Kontracts::on_runtime_upgrade();
+
+		let account = Kontracts::kontract_account(&code_id);
+		assert_eq!(
+			Some(crate::CodeInfo {
+				owner: account,
+				deposit: 0,
+				refcount: 1,
+				abi_version: AbiVersion::V0,
+				metering: crate::Metering::Engine
+			}),
+			crate::CodeInfoOf::<Test>::get(code_id)
+		);
+		assert_eq!(
+			Some(crate::KontractInfo { code_id, deployer: account, initialized: true }),
+			crate::Kontracts::<Test>::get(code_id)
+		);
+		// The kontract reads what was stored before
+		assert_ok!(execute_with_dependencies(acc, code_id, 1_000_000, 10, vec![]));
+		assert_eq!(StorageVersion::new(4), Kontracts::on_chain_storage_version());
+	});
+}
+
+#[test]
+fn migrations_weight_fits_in_a_block() {
+	new_test_ext().execute_with(|| {
+		StorageVersion::new(0).put::<Kontracts>();
+		for n in 0..10u8 {
+			let code_id = <Test as crate::Config>::HashingAlgorith::hash(&[n]);
+			crate::Codes::<Test>::insert(code_id, BoundedVec::try_from(vec![n]).unwrap());
+			insert_storage(code_id, &[n], vec![n; 100]);
+		}
+
+		// Computed before, it is the weight of the migrations, with the storages read
+		let pending = crate::migration::pending_weight::<Test>();
+		assert!(pending.proof_size() > 10 * 100);
+		assert_eq!(pending, Kontracts::on_runtime_upgrade());
+		let max_block = <<Test as frame_system::Config>::BlockWeights as Get<
+			frame_system::limits::BlockWeights,
+		>>::get()
+		.max_block;
+		assert!(pending.all_lte(max_block));
+	});
+}
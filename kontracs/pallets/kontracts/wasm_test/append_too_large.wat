@@ -0,0 +1,10 @@
+(module
+	(import "kontracts_v1" "append" (func $append (param i32 i32 i32 i32)))
+	(memory (export "memory") 1)
+	(data (i32.const 0) "log")
+	;; Every append is smaller than the max value size, together they are bigger
+	(func (export "main")
+		(call $append (i32.const 0) (i32.const 3) (i32.const 64) (i32.const 300))
+		(call $append (i32.const 0) (i32.const 3) (i32.const 64) (i32.const 300))
+	)
+)
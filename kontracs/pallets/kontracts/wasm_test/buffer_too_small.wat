@@ -0,0 +1,11 @@
+(module
+	(import "env" "set" (func $set (param i32 i32 i32 i32)))
+	(import "env" "get" (func $get (param i32 i32 i32 i32)))
+	(memory (export "memory") 1)
+	(func (export "main")
+		;; A value of 64 bytes, encoded with 2 bytes of compact length
+		(call $set (i32.const 0) (i32.const 4) (i32.const 100) (i32.const 64))
+		;; The buffer can contain the value but not its encoding
+		(call $get (i32.const 0) (i32.const 4) (i32.const 200) (i32.const 64))
+	)
+)
@@ -0,0 +1,12 @@
+(module
+	(import "kontracts_v1" "set" (func $set (param i32 i32 i32 i32)))
+	(import "kontracts_v1" "set_output" (func $set_output (param i32 i32)))
+	(memory (export "memory") 1)
+	(data (i32.const 0) "called")
+	(data (i32.const 8) "yes")
+	(data (i32.const 16) "pong")
+	(func (export "main")
+		(call $set (i32.const 0) (i32.const 6) (i32.const 8) (i32.const 3))
+		(call $set_output (i32.const 16) (i32.const 4))
+	)
+)
@@ -0,0 +1,32 @@
+(module
+	(import "kontracts_v1" "set" (func $set (param i32 i32 i32 i32)))
+	(import "kontracts_v1" "get" (func $get (param i32 i32 i32 i32) (result i32)))
+	(import "kontracts_v1" "input_len" (func $input_len (result i32)))
+	(import "kontracts_v1" "input" (func $input (param i32 i32)))
+	(import "kontracts_v1" "call" (func $call (param i32 i32 i32 i32 i64)))
+	(import "kontracts_v1" "call_output_len" (func $call_output_len (result i32)))
+	(import "kontracts_v1" "call_output" (func $call_output (param i32 i32)))
+	(memory (export "memory") 1)
+	(data (i32.const 0) "callee")
+	(data (i32.const 8) "output")
+	;; Calls the kontract stored under `callee` and stores its output under `output`
+	(func (export "main")
+		(local $fuel i64)
+		(local $len i32)
+		;; Half of the fuel received as input is forwarded, the first caller forwards 1_000_000
+		(if (i32.eq (call $input_len) (i32.const 8))
+			(then
+				(call $input (i32.const 32) (i32.const 8))
+				(local.set $fuel (i64.load (i32.const 32)))
+			)
+			(else (local.set $fuel (i64.const 2000000)))
+		)
+		(local.set $fuel (i64.div_u (local.get $fuel) (i64.const 2)))
+		(i64.store (i32.const 32) (local.get $fuel))
+		(drop (call $get (i32.const 0) (i32.const 6) (i32.const 64) (i32.const 32)))
+		(call $call (i32.const 64) (i32.const 32) (i32.const 32) (i32.const 8) (local.get $fuel))
+		(local.set $len (call $call_output_len))
+		(call $call_output (i32.const 128) (local.get $len))
+		(call $set (i32.const 8) (i32.const 6) (i32.const 128) (local.get $len))
+	)
+)
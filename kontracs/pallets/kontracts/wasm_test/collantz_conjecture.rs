@@ -10,7 +10,7 @@ fn main() {
     // Collatz Conjecture
     let key = 1u32;
 
-    let val: u32 = Decode::decode(&mut &read(key.encode())[..]).unwrap_or(5);
+    let val: u32 = read(key.encode()).and_then(|val| Decode::decode(&mut &val[..]).ok()).unwrap_or(5);
 
     let res = match val % 2 {
         0 => val / 2,
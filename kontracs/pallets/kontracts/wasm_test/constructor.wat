@@ -0,0 +1,17 @@
+(module
+	(import "kontracts_v1" "set" (func $set (param i32 i32 i32 i32)))
+	(import "kontracts_v1" "input_len" (func $input_len (result i32)))
+	(import "kontracts_v1" "input" (func $input (param i32 i32)))
+	(memory (export "memory") 1)
+	(data (i32.const 0) "init")
+	(func (export "main"))
+	;; The input is stored under the key `init`
+	(func (export "deploy")
+		(local $len i32)
+		(local.set $len (call $input_len))
+		(call $input (i32.const 16) (local.get $len))
+		(call $set (i32.const 0) (i32.const 4) (i32.const 16) (local.get $len))
+		;; Without input the storage is written and then the constructor fails
+		(if (i32.eqz (local.get $len)) (then unreachable))
+	)
+)
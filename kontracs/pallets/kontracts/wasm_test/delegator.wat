@@ -0,0 +1,11 @@
+(module
+	(import "kontracts_v1" "get" (func $get (param i32 i32 i32 i32) (result i32)))
+	(import "kontracts_v1" "delegate_call" (func $delegate_call (param i32 i32 i32 i32)))
+	(memory (export "memory") 1)
+	(data (i32.const 0) "library")
+	;; Executes the code stored under `library` over its own storage
+	(func (export "main")
+		(drop (call $get (i32.const 0) (i32.const 7) (i32.const 64) (i32.const 32)))
+		(call $delegate_call (i32.const 64) (i32.const 32) (i32.const 0) (i32.const 0))
+	)
+)
@@ -0,0 +1,13 @@
+(module
+	(import "kontracts_v1" "get" (func $get (param i32 i32 i32 i32) (result i32)))
+	(import "kontracts_v1" "dispatch_call" (func $dispatch_call (param i32 i32)))
+	(memory (export "memory") 1)
+	(data (i32.const 0) "call")
+	;; Dispatches the encoded call stored under `call`
+	(func (export "main")
+		(call $dispatch_call
+			(i32.const 64)
+			(call $get (i32.const 0) (i32.const 4) (i32.const 64) (i32.const 1024))
+		)
+	)
+)
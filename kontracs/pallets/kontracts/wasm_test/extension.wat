@@ -0,0 +1,32 @@
+(module
+	(import "kontracts_v1" "set" (func $set (param i32 i32 i32 i32)))
+	(import "kontracts_v1" "get" (func $get (param i32 i32 i32 i32) (result i32)))
+	(import "kontracts_v1" "call_extension"
+		(func $call_extension (param i32 i32 i32 i32 i32) (result i32))
+	)
+	(memory (export "memory") 1)
+	(data (i32.const 0) "calls")
+	(data (i32.const 8) "output")
+	(data (i32.const 16) "ping")
+	;; Calls the extension 0 as many times as the byte stored under `calls`,
+	;; the last output is stored under `output`
+	(func (export "main")
+		(local $calls i32)
+		(local $len i32)
+		(drop (call $get (i32.const 0) (i32.const 5) (i32.const 32) (i32.const 1)))
+		(local.set $calls (i32.load8_u (i32.const 32)))
+		(block $done
+			(loop $next
+				(br_if $done (i32.eqz (local.get $calls)))
+				(local.set $len
+					(call $call_extension
+						(i32.const 0) (i32.const 16) (i32.const 4) (i32.const 64) (i32.const 64)
+					)
+				)
+				(local.set $calls (i32.sub (local.get $calls) (i32.const 1)))
+				(br $next)
+			)
+		)
+		(call $set (i32.const 8) (i32.const 6) (i32.const 64) (local.get $len))
+	)
+)
@@ -0,0 +1,22 @@
+(module
+	(import "kontracts_v1" "set" (func $set (param i32 i32 i32 i32)))
+	(import "kontracts_v1" "get" (func $get (param i32 i32 i32 i32) (result i32)))
+	(import "kontracts_v1" "get_foreign" (func $get_foreign (param i32 i32 i32 i32 i32 i32) (result i32)))
+	(memory (export "memory") 1)
+	(data (i32.const 0) "target")
+	(data (i32.const 8) "called")
+	(data (i32.const 16) "foreign")
+	;; Reads `called` of the kontract stored under `target` and stores it under `foreign`
+	(func (export "main")
+		(local $len i32)
+		(drop (call $get (i32.const 0) (i32.const 6) (i32.const 64) (i32.const 32)))
+		(local.set $len
+			(call $get_foreign
+				(i32.const 64) (i32.const 32) (i32.const 8) (i32.const 6) (i32.const 128) (i32.const 64)
+			)
+		)
+		;; The value is written as it is, missing or too big it is not copied
+		(if (i32.gt_u (local.get $len) (i32.const 64)) (then unreachable))
+		(call $set (i32.const 16) (i32.const 7) (i32.const 128) (local.get $len))
+	)
+)
@@ -0,0 +1,22 @@
+(module
+	(import "kontracts_v1" "set" (func $set (param i32 i32 i32 i32)))
+	(import "kontracts_v1" "get" (func $get (param i32 i32 i32 i32) (result i32)))
+	(memory (export "memory") 1)
+	;; Two keys of 4 bytes and a value of 3 bytes
+	(data (i32.const 0) "key1key2abc")
+	(func (export "main")
+		(call $set (i32.const 0) (i32.const 4) (i32.const 8) (i32.const 3))
+		;; The value is written as it is and its length is returned
+		(i32.store
+			(i32.const 100)
+			(call $get (i32.const 0) (i32.const 4) (i32.const 104) (i32.const 4))
+		)
+		;; KEY_NOT_FOUND for a missing key
+		(i32.store
+			(i32.const 108)
+			(call $get (i32.const 4) (i32.const 4) (i32.const 112) (i32.const 4))
+		)
+		;; Length, value and KEY_NOT_FOUND stored in key2
+		(call $set (i32.const 4) (i32.const 4) (i32.const 100) (i32.const 12))
+	)
+)
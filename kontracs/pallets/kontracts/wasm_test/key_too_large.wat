@@ -0,0 +1,8 @@
+(module
+	(import "env" "set" (func $set (param i32 i32 i32 i32)))
+	(memory (export "memory") 1)
+	(func (export "main")
+		;; 513 bytes, one more than the limit of the mock if the key is not hashed
+		(call $set (i32.const 0) (i32.const 513) (i32.const 1000) (i32.const 4))
+	)
+)
@@ -0,0 +1,10 @@
+(module
+	(import "kontracts_v1" "set" (func $set (param i32 i32 i32 i32)))
+	(memory (export "memory") 1)
+	(data (i32.const 0) "lib")
+	(data (i32.const 8) "delegated")
+	;; Executed with delegate_call, so it writes in the storage of the caller
+	(func (export "main")
+		(call $set (i32.const 0) (i32.const 3) (i32.const 8) (i32.const 9))
+	)
+)
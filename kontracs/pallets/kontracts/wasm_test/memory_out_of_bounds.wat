@@ -0,0 +1,8 @@
+(module
+	(import "env" "get" (func $get (param i32 i32 i32 i32)))
+	(memory (export "memory") 1)
+	(func (export "main")
+		;; The key goes past the end of the only page of memory
+		(call $get (i32.const 65530) (i32.const 16) (i32.const 0) (i32.const 8))
+	)
+)
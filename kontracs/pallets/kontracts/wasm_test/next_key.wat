@@ -0,0 +1,75 @@
+(module
+	(import "kontracts_v1" "set" (func $set (param i32 i32 i32 i32)))
+	(import "kontracts_v1" "next_key" (func $next_key (param i32 i32 i32 i32 i32 i32) (result i32)))
+	(memory (export "memory") 1)
+	(data (i32.const 0) "k")
+	(data (i32.const 16) "kakbaz")
+	(data (i32.const 22) "v")
+	(data (i32.const 32) "listed")
+	(data (i32.const 40) "first")
+	(data (i32.const 48) "second")
+	(data (i32.const 56) "needed")
+	;; Writes the keys "", "a", "ka", "kb" and "z", then stores:
+	;; the keys with the prefix "k" under `listed`, concatenated
+	;; the length of the first key under `first`
+	;; the key after the empty key under `second`
+	;; the length of "ka" under `needed`, returned for a buffer too small
+	(func (export "main")
+		(local $len i32)
+		(local $out i32)
+		(local $after_size i32)
+		(call $set (i32.const 22) (i32.const 0) (i32.const 22) (i32.const 1))
+		(call $set (i32.const 20) (i32.const 1) (i32.const 22) (i32.const 1))
+		(call $set (i32.const 16) (i32.const 2) (i32.const 22) (i32.const 1))
+		(call $set (i32.const 18) (i32.const 2) (i32.const 22) (i32.const 1))
+		(call $set (i32.const 21) (i32.const 1) (i32.const 22) (i32.const 1))
+
+		;; Every key is written after the previous one, that is the next `after`
+		(local.set $out (i32.const 512))
+		(local.set $after_size (i32.const -1))
+		(block $done
+			(loop $walk
+				(local.set $len
+					(call $next_key
+						(i32.const 0) (i32.const 1)
+						(i32.sub (local.get $out) (local.get $after_size)) (local.get $after_size)
+						(local.get $out) (i32.const 64)
+					)
+				)
+				(br_if $done (i32.eq (local.get $len) (i32.const -1)))
+				(local.set $out (i32.add (local.get $out) (local.get $len)))
+				(local.set $after_size (local.get $len))
+				(br $walk)
+			)
+		)
+		(call $set
+			(i32.const 32) (i32.const 6)
+			(i32.const 512) (i32.sub (local.get $out) (i32.const 512))
+		)
+
+		;; Without `after` the first key is the empty one
+		(i32.store8 (i32.const 256)
+			(call $next_key
+				(i32.const 0) (i32.const 0) (i32.const 0) (i32.const -1)
+				(i32.const 300) (i32.const 64)
+			)
+		)
+		(call $set (i32.const 40) (i32.const 5) (i32.const 256) (i32.const 1))
+
+		(local.set $len
+			(call $next_key
+				(i32.const 0) (i32.const 0) (i32.const 0) (i32.const 0)
+				(i32.const 300) (i32.const 64)
+			)
+		)
+		(call $set (i32.const 48) (i32.const 6) (i32.const 300) (local.get $len))
+
+		(i32.store8 (i32.const 260)
+			(call $next_key
+				(i32.const 0) (i32.const 1) (i32.const 0) (i32.const -1)
+				(i32.const 400) (i32.const 1)
+			)
+		)
+		(call $set (i32.const 56) (i32.const 6) (i32.const 260) (i32.const 1))
+	)
+)
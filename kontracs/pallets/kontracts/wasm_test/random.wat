@@ -0,0 +1,12 @@
+(module
+	(import "kontracts_v1" "set" (func $set (param i32 i32 i32 i32)))
+	(import "kontracts_v1" "random" (func $random (param i32 i32 i32)))
+	(memory (export "memory") 1)
+	(data (i32.const 0) "subject")
+	(data (i32.const 8) "random")
+	;; The random value of `subject` is stored under `random`
+	(func (export "main")
+		(call $random (i32.const 0) (i32.const 7) (i32.const 64))
+		(call $set (i32.const 8) (i32.const 6) (i32.const 64) (i32.const 32))
+	)
+)
@@ -0,0 +1,61 @@
+(module
+	(import "kontracts_v1" "set" (func $set (param i32 i32 i32 i32)))
+	(import "kontracts_v1" "contains_key" (func $contains_key (param i32 i32) (result i32)))
+	(import "kontracts_v1" "value_len" (func $value_len (param i32 i32) (result i32)))
+	(import "kontracts_v1" "take" (func $take (param i32 i32 i32 i32) (result i32)))
+	(import "kontracts_v1" "append" (func $append (param i32 i32 i32 i32)))
+	(import "kontracts_v1" "get_range" (func $get_range (param i32 i32 i32 i32 i32) (result i32)))
+	(memory (export "memory") 1)
+	(data (i32.const 0) "a")
+	(data (i32.const 1) "none")
+	(data (i32.const 8) "hello world")
+	(data (i32.const 24) "t")
+	(data (i32.const 25) "abc")
+	(data (i32.const 32) "results")
+	(data (i32.const 40) "range")
+	(data (i32.const 48) "taken")
+	;; "hello world" is appended in two parts under "a" and "abc" is taken from "t",
+	;; the returned values (the last byte of them) are stored under `results`,
+	;; the range 6..11 of "a" under `range` and the value taken under `taken`
+	(func (export "main")
+		(i32.store8 (i32.const 512) (call $contains_key (i32.const 0) (i32.const 1)))
+		(call $append (i32.const 0) (i32.const 1) (i32.const 8) (i32.const 5))
+		(call $append (i32.const 0) (i32.const 1) (i32.const 13) (i32.const 6))
+		(i32.store8 (i32.const 513) (call $contains_key (i32.const 0) (i32.const 1)))
+		(i32.store8 (i32.const 514) (call $value_len (i32.const 0) (i32.const 1)))
+		(i32.store8 (i32.const 515) (call $value_len (i32.const 1) (i32.const 4)))
+
+		(drop
+			(call $get_range
+				(i32.const 0) (i32.const 1) (i32.const 6) (i32.const 5) (i32.const 256)
+			)
+		)
+		;; An offset at the end of the value is an empty range, after the end it is out of range
+		(i32.store8 (i32.const 516)
+			(call $get_range
+				(i32.const 0) (i32.const 1) (i32.const 11) (i32.const 5) (i32.const 400)
+			)
+		)
+		(i32.store8 (i32.const 517)
+			(call $get_range
+				(i32.const 0) (i32.const 1) (i32.const 12) (i32.const 5) (i32.const 400)
+			)
+		)
+		(i32.store8 (i32.const 518)
+			(call $get_range
+				(i32.const 1) (i32.const 4) (i32.const 0) (i32.const 5) (i32.const 400)
+			)
+		)
+
+		;; With a buffer too small the value is kept and its length returned
+		(call $set (i32.const 24) (i32.const 1) (i32.const 25) (i32.const 3))
+		(i32.store8 (i32.const 519)
+			(call $take (i32.const 24) (i32.const 1) (i32.const 300) (i32.const 2))
+		)
+		(drop (call $take (i32.const 24) (i32.const 1) (i32.const 300) (i32.const 64)))
+
+		(call $set (i32.const 32) (i32.const 7) (i32.const 512) (i32.const 8))
+		(call $set (i32.const 40) (i32.const 5) (i32.const 256) (i32.const 5))
+		(call $set (i32.const 48) (i32.const 5) (i32.const 300) (i32.const 3))
+	)
+)
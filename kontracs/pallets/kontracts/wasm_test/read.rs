@@ -11,7 +11,7 @@ fn main() {
     let key = vec![1u32, 2u32, 3u32];
 
     let value: Vec<u32> =
-        Decode::decode(&mut &read(key.encode())[..]).expect("Impossible decode whats' I insered");
+        Decode::decode(&mut &read(key.encode()).expect("Value not found")[..]).expect("Impossible decode whats' I insered");
 
     let expected_value = vec![4u32, 5u32, 6u32];
 
@@ -0,0 +1,15 @@
+#![no_std]
+#![no_main]
+#![feature(alloc_error_handler)]
+#![feature(core_intrinsics)]
+
+#[kontracts_proc_macro::kontracts]
+fn main() {
+    let target = read(b"target".to_vec()).expect("Target not found");
+
+    // Copies `called` of the target, or marks that it is not in its storage
+    match read_foreign(target, b"called".to_vec()) {
+        Some(value) => write(b"foreign".to_vec(), value),
+        None => write(b"missing".to_vec(), vec![]),
+    }
+}
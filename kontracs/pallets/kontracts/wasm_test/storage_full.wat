@@ -0,0 +1,14 @@
+(module
+	(import "env" "set" (func $set (param i32 i32 i32 i32)))
+	(memory (export "memory") 1)
+	(func (export "main")
+		(local $i i32)
+		;; 1025 different keys, one more than the limit of the mock
+		(loop $write
+			(i32.store (i32.const 0) (local.get $i))
+			(call $set (i32.const 0) (i32.const 4) (i32.const 100) (i32.const 0))
+			(local.set $i (i32.add (local.get $i) (i32.const 1)))
+			(br_if $write (i32.le_u (local.get $i) (i32.const 1024)))
+		)
+	)
+)
@@ -0,0 +1,10 @@
+(module
+	(import "env" "set" (func $set (param i32 i32 i32 i32)))
+	(memory (export "memory") 1)
+	(data (i32.const 0) "key1key2")
+	(func (export "main")
+		;; Two different keys with 300 bytes each
+		(call $set (i32.const 0) (i32.const 4) (i32.const 100) (i32.const 300))
+		(call $set (i32.const 4) (i32.const 4) (i32.const 100) (i32.const 300))
+	)
+)
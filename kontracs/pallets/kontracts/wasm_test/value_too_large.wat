@@ -0,0 +1,8 @@
+(module
+	(import "env" "set" (func $set (param i32 i32 i32 i32)))
+	(memory (export "memory") 1)
+	(func (export "main")
+		;; 513 bytes, one more than the limit of the mock
+		(call $set (i32.const 0) (i32.const 4) (i32.const 100) (i32.const 513))
+	)
+)
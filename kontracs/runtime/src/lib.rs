@@ -29,7 +29,8 @@ use sp_version::RuntimeVersion;
 pub use frame_support::{
 	construct_runtime, parameter_types,
 	traits::{
-		ConstU128, ConstU32, ConstU64, ConstU8, KeyOwnerProofSystem, Randomness, StorageInfo,
+		ConstBool, ConstU128, ConstU32, ConstU64, ConstU8, Contains, KeyOwnerProofSystem,
+		Randomness, StorageInfo,
 	},
 	weights::{
 		constants::{
@@ -37,7 +38,7 @@ pub use frame_support::{
 		},
 		IdentityFee, Weight,
 	},
-	StorageValue,
+	PalletId, StorageValue,
 };
 pub use frame_system::Call as SystemCall;
 pub use pallet_balances::Call as BalancesCall;
@@ -105,7 +106,8 @@ pub const VERSION: RuntimeVersion = RuntimeVersion {
 	//   `spec_version`, and `authoring_version` are the same between Wasm and native.
 	// This value is set to 100 to notify Polkadot-JS App (https://polkadot.js.org/apps) to use
 	//   the compatible custom types.
-	spec_version: 100,
+	// 101: the kontracts pallet is at the storage version 4, its migrations run at the upgrade.
+	spec_version: 101,
 	impl_version: 1,
 	apis: RUNTIME_API_VERSIONS,
 	transaction_version: 1,
@@ -275,6 +277,53 @@ impl pallet_sudo::Config for Runtime {
 	type RuntimeCall = RuntimeCall;
 }
 
+parameter_types! {
+	pub const KontractsPalletId: PalletId = PalletId(*b"kontract");
+	// The keys already stored are hashed with blake2 and the migration records it in
+	// `KeyHashingInUse`, another scheme (to list the keys) needs a new chain
+	pub const KontractsKeyHashing: pallet_kontracts::KeyHashing =
+		pallet_kontracts::KeyHashing::Blake2;
+	// A host function costs like a few hundreds of wasm instructions
+	pub const KontractsHostFnCosts: pallet_kontracts::HostFnCosts =
+		pallet_kontracts::HostFnCosts { base: 500, per_byte: 1 };
+	// The codes are executed with the fuel of wasmtime, without instrumentation
+	pub const KontractsMetering: pallet_kontracts::Metering = pallet_kontracts::Metering::Engine;
+	// The node registers `execute_code`, so wasmtime is used
+	pub const KontractsBackend: pallet_kontracts::Backend = pallet_kontracts::Backend::Native;
+	pub const KontractsMaxDispatchWeight: Weight =
+		Weight::from_parts(WEIGHT_REF_TIME_PER_SECOND / 4, u64::MAX);
+}
+
+/// The kontracts can only transfer balance and make remarks
+pub struct KontractsCallFilter;
+impl Contains<RuntimeCall> for KontractsCallFilter {
+	fn contains(call: &RuntimeCall) -> bool {
+		matches!(
+			call,
+			RuntimeCall::Balances(pallet_balances::Call::transfer { .. }) |
+				RuntimeCall::System(frame_system::Call::remark { .. })
+		)
+	}
+}
+
+/// Functions of the runtime available to the kontracts through `call_extension`
+pub struct KontractsExtension;
+impl pallet_kontracts::KontractExtension<AccountId> for KontractsExtension {
+	fn call(
+		_kontract_account: &AccountId,
+		id: u32,
+		_input: Vec<u8>,
+	) -> Result<Vec<u8>, sp_runtime::DispatchError> {
+		use codec::Encode;
+
+		match id {
+			// Current block number
+			0 => Ok(System::block_number().encode()),
+			_ => Err(sp_runtime::DispatchError::Other("UnknownExtension")),
+		}
+	}
+}
+
 /// Configure the pallet-kontracts in pallets/kontracts.
 impl pallet_kontracts::Config for Runtime {
 	type RuntimeEvent = RuntimeEvent;
@@ -283,7 +332,29 @@ impl pallet_kontracts::Config for Runtime {
 	type MaxCodeSize = ConstU32<1_000_000>;
 	type MaxKontracStorageKeySize = ConstU32<512>;
 	type MaxKontracStorageValueSize = ConstU32<1024>;
-	type MaxKontracStorageSize = ConstU32<1_048_576>; //1MiB
+	// The old bound of the storage map, lowering it would make the bigger storages undecodable,
+	// the `Schedule` can lower the limit of the executions
+	type MaxStorageEntries = ConstU32<1_048_576>;
+	type MaxStorageBytes = ConstU32<1_048_576>; //1MiB
+	type MaxDeletionQueue = ConstU32<128>;
+	type MaxSaltLen = ConstU32<32>;
+	type MaxInputLen = ConstU32<16_384>;
+	type CodeDepositPerByte = ConstU128<1>;
+	type PalletId = KontractsPalletId;
+	type MaxDependencies = ConstU32<16>;
+	type MaxCallDepth = ConstU32<8>;
+	type AllowReentrancy = ConstBool<false>;
+	type RuntimeCall = RuntimeCall;
+	type CallFilter = KontractsCallFilter;
+	type MaxDispatchWeight = KontractsMaxDispatchWeight;
+	type Extension = KontractsExtension;
+	type KeyHashing = KontractsKeyHashing;
+	type Randomness = RandomnessCollectiveFlip;
+	type MaxExtensionCalls = ConstU32<8>;
+	type HostFnCosts = KontractsHostFnCosts;
+	type Metering = KontractsMetering;
+	type Backend = KontractsBackend;
+	type Executor = pallet_kontracts::HostExecutor<Runtime>;
 	type CodeId = sp_core::H256;
 	type HashingAlgorith = BlakeTwo256;
 }
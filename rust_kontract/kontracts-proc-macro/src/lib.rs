@@ -3,6 +3,12 @@ extern crate proc_macro;
 use proc_macro::TokenStream;
 use quote::{quote, ToTokens};
 
+/// Turns the function in the entry point of a kontract and defines the functions
+/// to use the host (storage, calls, extensions...)
+///
+/// WARNING: `random_value` is not safe randomness, the value can be predicted and
+/// manipulated by the block producers, never use it where someone can gain something
+/// guessing or choosing it
 #[proc_macro_attribute]
 pub fn kontracts(_attr: TokenStream, input: TokenStream) -> TokenStream {
     let main_function: syn::ItemFn = syn::parse(input).unwrap();
@@ -26,11 +32,32 @@ pub fn kontracts(_attr: TokenStream, input: TokenStream) -> TokenStream {
         type Key = alloc::vec::Vec<u8>;
         type Value = alloc::vec::Vec<u8>;
         const MaxValueBytes: u32 = 100;
+        const MaxKeyBytes: u32 = 100;
 
         extern "C" {
             pub fn set(key_ptr: u32, key_size: u32, value_ptr: u32, value_size: u32);
             pub fn get(key_ptr: u32, key_size: u32, value_ptr: u32, value_max_size: u32);
+            pub fn get_value(key_ptr: u32, key_size: u32, value_ptr: u32, value_max_size: u32) -> u32;
+            pub fn get_foreign_value(kontract_id_ptr: u32, kontract_id_size: u32, key_ptr: u32, key_size: u32, value_ptr: u32, value_max_size: u32) -> u32;
             pub fn remove(key_ptr: u32, key_size: u32);
+            pub fn contains_key(key_ptr: u32, key_size: u32) -> u32;
+            pub fn value_len(key_ptr: u32, key_size: u32) -> u32;
+            pub fn take(key_ptr: u32, key_size: u32, value_ptr: u32, value_max_size: u32) -> u32;
+            pub fn append(key_ptr: u32, key_size: u32, value_ptr: u32, value_size: u32);
+            pub fn get_range(key_ptr: u32, key_size: u32, offset: u32, len: u32, value_ptr: u32) -> u32;
+            pub fn next_key(prefix_ptr: u32, prefix_size: u32, after_ptr: u32, after_size: u32, key_ptr: u32, key_max_size: u32) -> u32;
+            pub fn input_len() -> u32;
+            pub fn input(input_ptr: u32, input_max_size: u32);
+            pub fn balance(balance_ptr: u32);
+            pub fn transfer(dest_ptr: u32, dest_size: u32, amount_ptr: u32);
+            pub fn call(kontract_id_ptr: u32, kontract_id_size: u32, input_ptr: u32, input_size: u32, fuel: u64);
+            pub fn delegate_call(code_id_ptr: u32, code_id_size: u32, input_ptr: u32, input_size: u32);
+            pub fn dispatch_call(call_ptr: u32, call_len: u32);
+            pub fn call_extension(id: u32, input_ptr: u32, input_len: u32, output_ptr: u32, output_max_size: u32) -> u32;
+            pub fn random(subject_ptr: u32, subject_len: u32, out_ptr: u32);
+            pub fn call_output_len() -> u32;
+            pub fn call_output(output_ptr: u32, output_max_size: u32);
+            pub fn set_output(output_ptr: u32, output_size: u32);
         }
 
         fn write(key: Key, value: Value) {
@@ -44,17 +71,299 @@ pub fn kontracts(_attr: TokenStream, input: TokenStream) -> TokenStream {
             }
         }
 
-        fn read(key: Key) -> alloc::vec::Vec<u8> {
-            let result = vec![0; MaxValueBytes as usize];
+        // None if the key is not in the storage. Values bigger than `MaxValueBytes`
+        // are read twice, so counted as two reads
+        fn read(key: Key) -> Option<Value> {
+            let mut result = vec![0; MaxValueBytes as usize];
+            loop {
+                let len = unsafe {
+                    get_value(
+                        key[..].as_ptr() as u32,
+                        key.len() as u32,
+                        result[..].as_ptr() as u32,
+                        result.len() as u32,
+                    )
+                };
+                match len {
+                    KEY_NOT_FOUND => return None,
+                    // The buffer was too small, the host returned the needed size
+                    len if len as usize > result.len() => result.resize(len as usize, 0),
+                    len => {
+                        result.truncate(len as usize);
+                        return Some(result);
+                    }
+                }
+            }
+        }
+
+        // Statuses of the storage functions, never a valid length
+        const KEY_NOT_FOUND: u32 = u32::MAX;
+        const OUT_OF_RANGE: u32 = u32::MAX - 1;
+
+        // Counted as a read
+        fn contains(key: Key) -> bool {
+            unsafe { contains_key(key[..].as_ptr() as u32, key.len() as u32) == 1 }
+        }
+
+        // Length of the raw stored value, counted as a read
+        fn len_of(key: Key) -> Option<u32> {
+            match unsafe { value_len(key[..].as_ptr() as u32, key.len() as u32) } {
+                KEY_NOT_FOUND => None,
+                len => Some(len),
+            }
+        }
+
+        // Read and remove the raw value, counted as a read and a write. Values bigger than
+        // `MaxValueBytes` are kept by the first call and cost another read
+        fn take_value(key: Key) -> Option<Value> {
+            let mut result = vec![0; MaxValueBytes as usize];
+            loop {
+                let len = unsafe {
+                    take(
+                        key[..].as_ptr() as u32,
+                        key.len() as u32,
+                        result[..].as_ptr() as u32,
+                        result.len() as u32,
+                    )
+                };
+                match len {
+                    KEY_NOT_FOUND => return None,
+                    // The buffer was too small, the host returned the needed size
+                    len if len as usize > result.len() => result.resize(len as usize, 0),
+                    len => {
+                        result.truncate(len as usize);
+                        return Some(result);
+                    }
+                }
+            }
+        }
+
+        // Add the bytes at the end of the raw value, useful for logs, counted as a write
+        fn append_value(key: Key, value: Value) {
             unsafe {
-                get(
+                append(
                     key[..].as_ptr() as u32,
                     key.len() as u32,
+                    value[..].as_ptr() as u32,
+                    value.len() as u32,
+                );
+            }
+        }
+
+        // At most `len` bytes of the raw value from `offset`, empty if the offset
+        // is after the end of the value. Counted as a read
+        fn read_range(key: Key, offset: u32, len: u32) -> Option<Value> {
+            let mut result = vec![0; len as usize];
+            let read = unsafe {
+                get_range(
+                    key[..].as_ptr() as u32,
+                    key.len() as u32,
+                    offset,
+                    len,
+                    result[..].as_ptr() as u32,
+                )
+            };
+            match read {
+                KEY_NOT_FOUND => None,
+                OUT_OF_RANGE => Some(vec![]),
+                read => {
+                    result.truncate(read as usize);
+                    Some(result)
+                }
+            }
+        }
+
+        // Read from the storage of another kontract declared in the dependencies of the execution,
+        // counted as a normal read. Like `read` None if the key is not in its storage
+        fn read_foreign(kontract_id: alloc::vec::Vec<u8>, key: Key) -> Option<Value> {
+            let mut result = vec![0; MaxValueBytes as usize];
+            loop {
+                let len = unsafe {
+                    get_foreign_value(
+                        kontract_id[..].as_ptr() as u32,
+                        kontract_id.len() as u32,
+                        key[..].as_ptr() as u32,
+                        key.len() as u32,
+                        result[..].as_ptr() as u32,
+                        result.len() as u32,
+                    )
+                };
+                match len {
+                    KEY_NOT_FOUND => return None,
+                    // The buffer was too small, the host returned the needed size
+                    len if len as usize > result.len() => result.resize(len as usize, 0),
+                    len => {
+                        result.truncate(len as usize);
+                        return Some(result);
+                    }
+                }
+            }
+        }
+
+        // First key with the prefix after `after` (from the first one if `after` is None),
+        // counted as a read, keys bigger than `MaxKeyBytes` are read twice. Only if the runtime
+        // uses a `KeyHashing` that keeps the keys, otherwise the execution fails
+        fn next_storage_key(prefix: Key, after: Option<Key>) -> Option<Key> {
+            let mut result = vec![0; MaxKeyBytes as usize];
+            // u32::MAX as size means no `after`, an empty one is the empty key
+            let (after_ptr, after_size) = match &after {
+                Some(after) => (after[..].as_ptr() as u32, after.len() as u32),
+                None => (0, u32::MAX),
+            };
+            loop {
+                let len = unsafe {
+                    next_key(
+                        prefix[..].as_ptr() as u32,
+                        prefix.len() as u32,
+                        after_ptr,
+                        after_size,
+                        result[..].as_ptr() as u32,
+                        result.len() as u32,
+                    )
+                };
+                match len {
+                    u32::MAX => return None,
+                    // The buffer was too small, the host returned the needed size
+                    len if len as usize > result.len() => result.resize(len as usize, 0),
+                    len => {
+                        result.truncate(len as usize);
+                        return Some(result);
+                    }
+                }
+            }
+        }
+
+        // Input data given to the constructor, empty in all the other entry points
+        fn read_input() -> alloc::vec::Vec<u8> {
+            let result = vec![0; unsafe { input_len() } as usize];
+            unsafe {
+                input(
                     result[..].as_ptr() as u32,
                     result.len() as u32,
                 );
             }
-            parity_scale_codec::Decode::decode(&mut &result[..]).expect("Result is not decodable as Vec<u8>")
+            result
+        }
+
+        // Free balance of the kontract, including the value sent with the call
+        fn get_balance() -> u128 {
+            let result = [0u8; 16];
+            unsafe {
+                balance(result[..].as_ptr() as u32);
+            }
+            u128::from_le_bytes(result)
+        }
+
+        // The destination is the scale encoded AccountId,
+        // the transfer is applied only if the execution is successful
+        fn send(dest: alloc::vec::Vec<u8>, amount: u128) {
+            let amount = amount.to_le_bytes();
+            unsafe {
+                transfer(
+                    dest[..].as_ptr() as u32,
+                    dest.len() as u32,
+                    amount[..].as_ptr() as u32,
+                );
+            }
+        }
+
+        // Execute the main of another kontract, declared in the dependencies of the execution,
+        // giving it part of the fuel, returns the data set by the called kontract
+        fn call_kontract(kontract_id: alloc::vec::Vec<u8>, input: alloc::vec::Vec<u8>, fuel: u64) -> alloc::vec::Vec<u8> {
+            unsafe {
+                call(
+                    kontract_id[..].as_ptr() as u32,
+                    kontract_id.len() as u32,
+                    input[..].as_ptr() as u32,
+                    input.len() as u32,
+                    fuel,
+                );
+            }
+            let result = vec![0; unsafe { call_output_len() } as usize];
+            unsafe {
+                call_output(
+                    result[..].as_ptr() as u32,
+                    result.len() as u32,
+                );
+            }
+            result
+        }
+
+        // Execute the main of a code declared as library over the storage of this kontract,
+        // the library uses all the remaining fuel, returns the data set by the library
+        fn delegate_call_code(code_id: alloc::vec::Vec<u8>, input: alloc::vec::Vec<u8>) -> alloc::vec::Vec<u8> {
+            unsafe {
+                delegate_call(
+                    code_id[..].as_ptr() as u32,
+                    code_id.len() as u32,
+                    input[..].as_ptr() as u32,
+                    input.len() as u32,
+                );
+            }
+            let result = vec![0; unsafe { call_output_len() } as usize];
+            unsafe {
+                call_output(
+                    result[..].as_ptr() as u32,
+                    result.len() as u32,
+                );
+            }
+            result
+        }
+
+        // The call is the scale encoded RuntimeCall, dispatched with the kontract account as
+        // origin only if the execution succeed and if accepted by the filter of the runtime
+        fn dispatch(call: alloc::vec::Vec<u8>) {
+            unsafe {
+                dispatch_call(
+                    call[..].as_ptr() as u32,
+                    call.len() as u32,
+                );
+            }
+        }
+
+        // Call the function `id` registered by the runtime, the output can't be longer
+        // than `output_max_size`
+        fn extension(id: u32, input: alloc::vec::Vec<u8>, output_max_size: u32) -> alloc::vec::Vec<u8> {
+            let mut result = vec![0; output_max_size as usize];
+            let len = unsafe {
+                call_extension(
+                    id,
+                    input[..].as_ptr() as u32,
+                    input.len() as u32,
+                    result[..].as_ptr() as u32,
+                    result.len() as u32,
+                )
+            };
+            result.truncate(len as usize);
+            result
+        }
+
+        /// Random value of the runtime for the subject, always 32 bytes
+        ///
+        /// WARNING: the value is predictable and can be manipulated by the block producers,
+        /// it is known by the block author and by everyone after the block (with the
+        /// collective flip also influenced by the previous authors), never use it where
+        /// someone can gain something guessing or choosing it
+        fn random_value(subject: alloc::vec::Vec<u8>) -> [u8; 32] {
+            let mut result = [0; 32];
+            unsafe {
+                random(
+                    subject[..].as_ptr() as u32,
+                    subject.len() as u32,
+                    result[..].as_mut_ptr() as u32,
+                );
+            }
+            result
+        }
+
+        // Data given back to the kontract that called this one
+        fn return_data(output: alloc::vec::Vec<u8>) {
+            unsafe {
+                set_output(
+                    output[..].as_ptr() as u32,
+                    output.len() as u32,
+                );
+            }
         }
 
         fn delete(key: Key) {
@@ -70,3 +379,33 @@ pub fn kontracts(_attr: TokenStream, input: TokenStream) -> TokenStream {
         #main_function
     ))
 }
+
+/// Mark the function executed only once when the code of the kontract is upgraded,
+/// it has access to the storage of the old code and can adapt it to the new one
+#[proc_macro_attribute]
+pub fn migrate(_attr: TokenStream, input: TokenStream) -> TokenStream {
+    let migrate_function: syn::ItemFn = syn::parse(input).unwrap();
+    let body = migrate_function.block;
+    TokenStream::from(quote!(
+        #[no_mangle]
+        pub extern "C" fn migrate() #body
+    ))
+}
+
+/// Mark the constructor of the kontract, the function must accept the input data
+/// given to the instantiation: `fn(input: Vec<u8>)`.
+/// The pallet executes it only once, when the kontract is instantiated, the function
+/// can't be called `deploy` because that is the name of the generated entry point
+#[proc_macro_attribute]
+pub fn constructor(_attr: TokenStream, input: TokenStream) -> TokenStream {
+    let constructor_function: syn::ItemFn = syn::parse(input).unwrap();
+    let name = &constructor_function.sig.ident;
+    TokenStream::from(quote!(
+        #constructor_function
+
+        #[no_mangle]
+        pub extern "C" fn deploy() {
+            #name(read_input())
+        }
+    ))
+}
@@ -14,7 +14,7 @@ fn main() {
     write(key.encode(), value.encode());
 
     let read_value: Vec<u32> =
-        Decode::decode(&mut &read(key.encode())[..]).expect("Impossible decode whats' I insered");
+        Decode::decode(&mut &read(key.encode()).expect("Value not found")[..]).expect("Impossible decode whats' I insered");
 
     let key_2 = vec![4u32, 5u32, 6u32];
 